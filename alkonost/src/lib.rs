@@ -6,24 +6,31 @@ use chat_manager::ChatManager;
 use detector::DetectorManager;
 use error::{AlkonostError, AlkonostInitError};
 use shared::{
+    diagnostics::{self, DiagnosticsStore, SavedBundle},
     http_client::HttpClient,
     messages::{self, alkonost::IncMessage},
     ActorWrapper, AlkSender,
 };
 use stream_finder::StreamFinder;
 use tokio::{
-    sync::mpsc::{self, Receiver},
+    sync::mpsc::{self, Receiver, UnboundedSender},
     task::JoinHandle,
 };
 
 pub mod error;
 
 pub type DetectorParams = shared::detector_params::DetectorParams;
+pub type CrossStreamParams = shared::detector_params::CrossStreamParams;
+pub type ReputationParams = shared::detector_params::ReputationParams;
+pub type MemoryParams = shared::detector_params::MemoryParams;
+pub type ScriptParams = shared::detector_params::ScriptParams;
 pub type RequestSettings = shared::http_client::RequestSettings;
+pub type RetryPolicy = shared::http_client::RetryPolicy;
 pub type AlkonostInMessage = shared::messages::alkonost::IncMessage;
 pub type AlkonostOutMessage = shared::messages::detector::OutMessage;
 pub type DetectorDecision = shared::messages::detector::DetectorDecision;
 pub type DecisionAction = shared::messages::detector::Decision;
+pub type ShadowStats = shared::messages::detector::ShadowStats;
 
 pub struct Alkonost {
     rx: Receiver<IncMessage>,
@@ -32,6 +39,8 @@ pub struct Alkonost {
     detector: JoinHandle<()>,
     finder_to_chat_handle: JoinHandle<()>,
     chat_to_detector_handle: JoinHandle<()>,
+    diagnostics_handle: JoinHandle<()>,
+    diagnostics_events_tx: UnboundedSender<SavedBundle>,
     stream_finder_tx: AlkSender<messages::stream_finder::IncMessage>,
     chat_manager_tx: AlkSender<messages::chat_manager::IncMessage>,
     detector_tx: AlkSender<messages::detector::IncMessage>,
@@ -49,6 +58,7 @@ impl Alkonost {
         AlkonostInitError,
     > {
         let (detector_result_tx, detector_result_rx) = mpsc::channel(32);
+        let diagnostics_result_tx = detector_result_tx.clone();
         let ActorWrapper {
             join_handle: detector,
             tx: detector_tx,
@@ -58,7 +68,21 @@ impl Alkonost {
         let http_client = HttpClient::init()?;
         let http_client = Arc::new(http_client);
 
+        // Saved failure bundles are reported next to the results of the detector
+        let (diagnostics_events_tx, mut diagnostics_events_rx) = mpsc::unbounded_channel();
+        let diagnostics = match DiagnosticsStore::new(
+            diagnostics::DEFAULT_DIR.into(),
+            diagnostics::DEFAULT_MAX_SIZE,
+        ) {
+            Ok(store) => Some(Arc::new(store.with_events(diagnostics_events_tx.clone()))),
+            Err(e) => {
+                shared::tracing_warn!("Couldn't create the diagnostics directory: {}", &e);
+                None
+            }
+        };
+
         let (chat_manager_result_tx, mut chat_manager_result_rx) = mpsc::channel(32);
+        let (poller_failures_tx, poller_failures_rx) = mpsc::unbounded_channel();
         let ActorWrapper {
             join_handle: chat_manager,
             tx: chat_manager_tx,
@@ -66,6 +90,8 @@ impl Alkonost {
             http_client.clone(),
             request_settings.clone(),
             chat_manager_result_tx,
+            poller_failures_rx,
+            diagnostics.clone(),
         );
         let mut chat_manager_tx_clone = chat_manager_tx.clone();
 
@@ -78,10 +104,36 @@ impl Alkonost {
             request_settings,
             stream_finder_result_tx,
             chat_poll_interval,
+            diagnostics,
         );
 
         let chat_to_detector_handle = tokio::spawn(async move {
             while let Some(out_message) = chat_manager_result_rx.recv().await {
+                // Chat manager restarts failed pollers, while the detector keeps the state of the stream.
+                // The manager may be waiting for this task to take its messages,
+                // so the failures go through their own channel, that never blocks.
+                if let messages::chat_poller::OutMessage::PollerFailed {
+                    channel,
+                    video_id,
+                    poller,
+                    kind,
+                    ..
+                } = &out_message
+                {
+                    let failure = messages::chat_manager::PollerFailure {
+                        channel: channel.clone(),
+                        video_id: video_id.clone(),
+                        poller: *poller,
+                        kind: kind.clone(),
+                    };
+                    if let Err(e) = poller_failures_tx.send(failure) {
+                        shared::tracing_error!(
+                            "Chat Manager's end of the failures channel has closed: {}",
+                            &e
+                        );
+                    }
+                }
+
                 let inc_message = messages::detector::IncMessage::ChatPoller(out_message);
                 match detector_tx_clone.send(inc_message).await {
                     Ok(_r) => {}
@@ -112,6 +164,16 @@ impl Alkonost {
             }
         });
 
+        let diagnostics_handle = tokio::spawn(async move {
+            while let Some(bundle) = diagnostics_events_rx.recv().await {
+                let out_message = messages::detector::OutMessage::DiagnosticsSaved(bundle);
+                if let Err(e) = diagnostics_result_tx.send(out_message).await {
+                    shared::tracing_error!("Couldn't report a saved failure bundle: {}", &e);
+                    return;
+                }
+            }
+        });
+
         let (tx, rx) = mpsc::channel(32);
 
         let alkonost = Self {
@@ -121,6 +183,8 @@ impl Alkonost {
             detector,
             finder_to_chat_handle,
             chat_to_detector_handle,
+            diagnostics_handle,
+            diagnostics_events_tx,
             stream_finder_tx,
             chat_manager_tx,
             detector_tx,
@@ -166,6 +230,10 @@ impl Alkonost {
 
         Alkonost::await_task(self.chat_to_detector_handle, "chat_to_detector").await;
 
+        // Every store with the events sender has been dropped along with the modules
+        drop(self.diagnostics_events_tx);
+        Alkonost::await_task(self.diagnostics_handle, "diagnostics").await;
+
         Alkonost::close_task(
             self.detector,
             &mut self.detector_tx,
@@ -248,6 +316,74 @@ impl Alkonost {
 
                     self.detector_tx.send(module_message).await?;
                 }
+                messages::alkonost::IncMessage::UpdateCrossStreamParams(params) => {
+                    let module_message =
+                        messages::detector::IncMessage::UpdateCrossStreamParams(params);
+                    self.detector_tx.send(module_message).await?;
+                }
+                messages::alkonost::IncMessage::LoadReputation(path) => {
+                    let module_message = messages::detector::IncMessage::LoadReputation(path);
+                    self.detector_tx.send(module_message).await?;
+                }
+                messages::alkonost::IncMessage::AddToWatchlist { channel_id, reason } => {
+                    let module_message =
+                        messages::detector::IncMessage::AddToWatchlist { channel_id, reason };
+                    self.detector_tx.send(module_message).await?;
+                }
+                messages::alkonost::IncMessage::GetDeletedMessages { video_id } => {
+                    let module_message =
+                        messages::detector::IncMessage::GetDeletedMessages { video_id };
+                    self.detector_tx.send(module_message).await?;
+                }
+                messages::alkonost::IncMessage::GetMemoryUsage => {
+                    let module_message = messages::detector::IncMessage::GetMemoryUsage;
+                    self.detector_tx.send(module_message).await?;
+                }
+                messages::alkonost::IncMessage::RemoveFromWatchlist(channel_id) => {
+                    let module_message =
+                        messages::detector::IncMessage::RemoveFromWatchlist(channel_id);
+                    self.detector_tx.send(module_message).await?;
+                }
+                messages::alkonost::IncMessage::UpdateScript { channel, script } => {
+                    let module_message =
+                        messages::detector::IncMessage::UpdateScript { channel, script };
+                    self.detector_tx.send(module_message).await?;
+                }
+                messages::alkonost::IncMessage::SetChatCheckpointDir(dir) => {
+                    let module_message = messages::chat_manager::IncMessage::SetCheckpointDir(dir);
+                    self.chat_manager_tx.send(module_message).await?;
+                }
+                messages::alkonost::IncMessage::SetDiagnosticsDir(dir) => {
+                    let store = dir.map(|dir| {
+                        DiagnosticsStore::new(dir, diagnostics::DEFAULT_MAX_SIZE).map(|store| {
+                            Arc::new(store.with_events(self.diagnostics_events_tx.clone()))
+                        })
+                    });
+                    let store = match store.transpose() {
+                        Ok(store) => store,
+                        Err(e) => {
+                            shared::tracing_warn!("Couldn't set the diagnostics directory: {}", &e);
+                            continue;
+                        }
+                    };
+
+                    let module_message_1 =
+                        messages::stream_finder::IncMessage::SetDiagnostics(store.clone());
+                    let module_message_2 =
+                        messages::chat_manager::IncMessage::SetDiagnostics(store);
+                    self.stream_finder_tx.send(module_message_1).await?;
+                    self.chat_manager_tx.send(module_message_2).await?;
+                }
+                messages::alkonost::IncMessage::AddReplay { channel, video_id } => {
+                    let module_message =
+                        messages::chat_manager::IncMessage::AddReplay { channel, video_id };
+                    self.chat_manager_tx.send(module_message).await?;
+                }
+                messages::alkonost::IncMessage::UpdateShadowParams { channel, params } => {
+                    let module_message =
+                        messages::detector::IncMessage::UpdateShadowParams { channel, params };
+                    self.detector_tx.send(module_message).await?;
+                }
             }
         }
     }
@@ -0,0 +1,185 @@
+use pyo3::prelude::*;
+use shared::{
+    currency::{PurchaseAmount, PurchaseTier},
+    types::{Action, MessageContent, User},
+};
+
+/// A single action from the chat: a new message, a deletion, a ban and so on
+#[pyclass(name = "Action", module = "alkonost_py")]
+#[derive(Clone)]
+pub struct PyAction {
+    pub inner: Action,
+}
+
+impl From<Action> for PyAction {
+    fn from(inner: Action) -> Self {
+        Self { inner }
+    }
+}
+
+impl PyAction {
+    fn message(&self) -> Option<&MessageContent> {
+        match &self.inner {
+            Action::NewMessage { message, .. } | Action::ReplaceMessage { message, .. } => {
+                Some(message)
+            }
+            _ => None,
+        }
+    }
+
+    fn author(&self) -> Option<&User> {
+        match &self.inner {
+            Action::ChannelNotice { author, .. } => Some(author),
+            _ => self.message().and_then(|message| message.author()),
+        }
+    }
+
+    fn parsed_amount(&self) -> Option<&PurchaseAmount> {
+        match self.message() {
+            Some(MessageContent::Superchat { parsed_amount, .. })
+            | Some(MessageContent::Sticker { parsed_amount, .. }) => parsed_amount.as_ref(),
+            _ => None,
+        }
+    }
+}
+
+#[pymethods]
+impl PyAction {
+    #[getter]
+    fn kind(&self) -> &'static str {
+        match &self.inner {
+            Action::NewMessage { .. } => "new_message",
+            Action::DeleteMessage { .. } => "delete_message",
+            Action::ReplaceMessage { .. } => "replace_message",
+            Action::BlockUser { .. } => "block_user",
+            Action::CloseBanner { .. } => "close_banner",
+            Action::StartPoll { .. } => "start_poll",
+            Action::FinishPoll { .. } => "finish_poll",
+            Action::ChannelNotice { .. } => "channel_notice",
+            Action::FundraiserProgress { .. } => "fundraiser_progress",
+            Action::ClosePanel { .. } => "close_panel",
+            Action::Unknown { .. } => "unknown",
+        }
+    }
+
+    /// Kind of the message for new and replaced messages
+    #[getter]
+    fn message_kind(&self) -> Option<&'static str> {
+        self.message().map(|message| match message {
+            MessageContent::SimpleMessage { .. } => "simple_message",
+            MessageContent::Membership { .. } => "membership",
+            MessageContent::Superchat { .. } => "superchat",
+            MessageContent::Sticker { .. } => "sticker",
+            MessageContent::Fundraiser { .. } => "fundraiser",
+            MessageContent::GiftPurchase { .. } => "gift_purchase",
+            MessageContent::GiftRedemption { .. } => "gift_redemption",
+            MessageContent::ChatMode { .. } => "chat_mode",
+            MessageContent::PollResult { .. } => "poll_result",
+        })
+    }
+
+    #[getter]
+    fn id(&self) -> Option<String> {
+        self.inner.id_entry().map(|id| id.id.clone())
+    }
+
+    /// Time of the action in microseconds: the time of the message,
+    /// or the time of receiving for actions, that don't have their own time
+    #[getter]
+    fn timestamp(&self) -> u64 {
+        self.inner.timestamp().as_micros()
+    }
+
+    /// Id of the message, that was deleted or replaced by this action
+    #[getter]
+    fn target_id(&self) -> Option<String> {
+        match &self.inner {
+            Action::DeleteMessage { target_id, .. }
+            | Action::ReplaceMessage { target_id, .. }
+            | Action::ClosePanel { target_id, .. } => Some(target_id.clone()),
+            _ => None,
+        }
+    }
+
+    /// Channel id of the author, or of the blocked user
+    #[getter]
+    fn author_channel_id(&self) -> Option<String> {
+        match &self.inner {
+            Action::BlockUser { channel_id, .. } => Some(channel_id.clone()),
+            _ => self.author().map(|author| author.channel_id.clone()),
+        }
+    }
+
+    #[getter]
+    fn author_name(&self) -> Option<String> {
+        self.author()
+            .and_then(|author| author.name.as_ref())
+            .map(|name| name.to_plain_text().text().to_string())
+    }
+
+    #[getter]
+    fn author_has_badges(&self) -> Option<bool> {
+        self.author().map(|author| author.badges.is_some())
+    }
+
+    /// Normalized plain text of a simple message, the same text the detector works with
+    #[getter]
+    fn text(&self) -> Option<String> {
+        match self.message() {
+            Some(MessageContent::SimpleMessage { plain_text, .. }) => {
+                Some(plain_text.text().to_string())
+            }
+            _ => None,
+        }
+    }
+
+    /// HTML of a simple message, as it's shown in the chat
+    #[getter]
+    fn html(&self) -> Option<String> {
+        match self.message() {
+            Some(MessageContent::SimpleMessage { message, .. }) => Some(message.to_html()),
+            _ => None,
+        }
+    }
+
+    /// ISO 4217 code of the currency of a superchat or a sticker
+    #[getter]
+    fn currency(&self) -> Option<String> {
+        self.parsed_amount().map(|amount| amount.currency.clone())
+    }
+
+    /// Amount of a superchat or a sticker as a string like "5.00", that can be passed to `decimal.Decimal`
+    #[getter]
+    fn amount(&self) -> Option<String> {
+        self.parsed_amount().map(|amount| amount.value.to_string())
+    }
+
+    /// Color tier of a superchat or a sticker
+    #[getter]
+    fn tier(&self) -> Option<&'static str> {
+        self.parsed_amount()
+            .and_then(|amount| amount.tier)
+            .map(|tier| match tier {
+                PurchaseTier::Blue => "blue",
+                PurchaseTier::LightBlue => "light_blue",
+                PurchaseTier::Green => "green",
+                PurchaseTier::Yellow => "yellow",
+                PurchaseTier::Orange => "orange",
+                PurchaseTier::Magenta => "magenta",
+                PurchaseTier::Red => "red",
+            })
+    }
+
+    /// Raw JSON of an action, that the parser doesn't know how to handle
+    #[getter]
+    fn raw(&self) -> Option<String> {
+        match &self.inner {
+            Action::Unknown { raw, .. } => Some(raw.clone()),
+            _ => None,
+        }
+    }
+
+    fn __repr__(&self) -> String {
+        format!("{:?}", &self.inner)
+    }
+}
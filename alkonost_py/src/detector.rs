@@ -0,0 +1,96 @@
+use detector::Detector;
+use pyo3::prelude::*;
+use shared::messages::detector::{Decision, DetectorDecision};
+
+use crate::{actions::PyAction, params::PyDetectorParams};
+
+/// Spam detector of a single chat
+#[pyclass(name = "Detector", module = "alkonost_py")]
+pub struct PyDetector {
+    inner: Detector,
+}
+
+#[pymethods]
+impl PyDetector {
+    #[new]
+    #[pyo3(signature = (params, video_id = String::new()))]
+    fn new(params: PyDetectorParams, video_id: String) -> Self {
+        Self {
+            inner: Detector::new(video_id, params.inner),
+        }
+    }
+
+    /// Processes actions in the order they happened in the chat,
+    /// and returns decisions about users, whose status has changed
+    fn process(&mut self, actions: Vec<PyAction>) -> Vec<PyDecision> {
+        let actions = actions.into_iter().map(|action| action.inner).collect();
+        self.inner
+            .process(actions)
+            .into_iter()
+            .map(PyDecision::from)
+            .collect()
+    }
+
+    /// Replaces the params and reanalyzes all users with them
+    fn update_params(&mut self, params: PyDetectorParams) -> Vec<PyDecision> {
+        self.inner
+            .update_params(params.inner)
+            .into_iter()
+            .map(PyDecision::from)
+            .collect()
+    }
+}
+
+/// Decision about a user
+#[pyclass(name = "Decision", module = "alkonost_py", get_all)]
+pub struct PyDecision {
+    /// Channel id of the user
+    channel_id: String,
+    /// Time of the action, that has led to the decision, in microseconds
+    timestamp: u64,
+    kind: &'static str,
+    /// The measured value, that triggered the decision, if there's any
+    value: Option<f64>,
+    /// Streams or channels, that are related to the decision
+    related: Vec<String>,
+}
+
+impl From<DetectorDecision> for PyDecision {
+    fn from(decision: DetectorDecision) -> Self {
+        let (kind, value, related) = match decision.decision {
+            Decision::TooFast(value) => ("too_fast", Some(value as f64), Vec::new()),
+            Decision::TooLong(value) => ("too_long", Some(value as f64), Vec::new()),
+            Decision::TooManyDeleted => ("too_many_deleted", None, Vec::new()),
+            Decision::Similar => ("similar", None, Vec::new()),
+            Decision::EmojiFlood(value) => ("emoji_flood", Some(value as f64), Vec::new()),
+            Decision::RepeatedCharacters(value) => {
+                ("repeated_characters", Some(value as f64), Vec::new())
+            }
+            Decision::TooManyCaps(value) => ("too_many_caps", Some(value as f64), Vec::new()),
+            Decision::Zalgo(value) => ("zalgo", Some(value as f64), Vec::new()),
+            Decision::CrossStreamSpam { streams } => ("cross_stream_spam", None, streams),
+            Decision::BadReputation { channels } => ("bad_reputation", None, channels),
+            Decision::Script { reason } => ("script", None, vec![reason]),
+            Decision::Blocked => ("blocked", None, Vec::new()),
+            Decision::Clear => ("clear", None, Vec::new()),
+        };
+
+        Self {
+            channel_id: decision.channel,
+            timestamp: decision.timestamp.as_micros(),
+            kind,
+            value,
+            related,
+        }
+    }
+}
+
+#[pymethods]
+impl PyDecision {
+    fn __repr__(&self) -> String {
+        format!(
+            "Decision(channel_id={:?}, kind={:?}, value={:?}, related={:?})",
+            &self.channel_id, &self.kind, &self.value, &self.related
+        )
+    }
+}
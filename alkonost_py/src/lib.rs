@@ -0,0 +1,35 @@
+#![allow(proc_macro_derive_resolution_fallback, unused_attributes)]
+// False positive in the code, generated by `pyfunction` for functions returning `PyResult`
+#![allow(clippy::useless_conversion)]
+
+use actions::PyAction;
+use detector::{PyDecision, PyDetector};
+use params::PyDetectorParams;
+use pyo3::{exceptions::PyValueError, prelude::*};
+use shared::types::Timestamp;
+
+mod actions;
+mod detector;
+mod params;
+
+/// Parses a stored response of the chat endpoint into a list of actions,
+/// using the same parser as the chat poller.
+/// `received_at` is the time of receiving the response in microseconds, the current time by default.
+#[pyfunction]
+#[pyo3(signature = (json, received_at = None))]
+fn parse_chat_json(json: &str, received_at: Option<u64>) -> PyResult<Vec<PyAction>> {
+    let received_at = received_at.map_or_else(Timestamp::now, Timestamp::from_micros);
+    let actions = chat_poller::parse_chat_json(json, received_at)
+        .map_err(|e| PyValueError::new_err(e.to_string()))?;
+    Ok(actions.into_iter().map(PyAction::from).collect())
+}
+
+#[pymodule]
+fn alkonost_py(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_function(wrap_pyfunction!(parse_chat_json, m)?)?;
+    m.add_class::<PyAction>()?;
+    m.add_class::<PyDetectorParams>()?;
+    m.add_class::<PyDetector>()?;
+    m.add_class::<PyDecision>()?;
+    Ok(())
+}
@@ -0,0 +1,83 @@
+use pyo3::prelude::*;
+use shared::detector_params::{DetectorParams, FloodParams, ThresholdOverrides};
+
+/// Detector params. Every threshold, that isn't set, keeps its default value.
+#[pyclass(name = "DetectorParams", module = "alkonost_py")]
+#[derive(Clone)]
+pub struct PyDetectorParams {
+    pub inner: DetectorParams,
+}
+
+#[pymethods]
+impl PyDetectorParams {
+    #[new]
+    #[pyo3(signature = (
+        *,
+        deleted_messages_threshold = None,
+        avg_delay_threshold = None,
+        avg_delay_min_message_count = None,
+        avg_length_threshold = None,
+        avg_length_min_message_count = None,
+        similarity_threshold = None,
+        similarity_count_threshold = None,
+        similarity_min_message_length = None,
+        flood_min_message_length = None,
+        emoji_ratio_threshold = None,
+        repeated_run_threshold = None,
+        caps_min_letters = None,
+        caps_ratio_threshold = None,
+        combining_marks_density_threshold = None,
+    ))]
+    #[allow(clippy::too_many_arguments)]
+    fn new(
+        deleted_messages_threshold: Option<usize>,
+        avg_delay_threshold: Option<f32>,
+        avg_delay_min_message_count: Option<usize>,
+        avg_length_threshold: Option<f32>,
+        avg_length_min_message_count: Option<usize>,
+        similarity_threshold: Option<f32>,
+        similarity_count_threshold: Option<usize>,
+        similarity_min_message_length: Option<usize>,
+        flood_min_message_length: Option<usize>,
+        emoji_ratio_threshold: Option<f32>,
+        repeated_run_threshold: Option<usize>,
+        caps_min_letters: Option<usize>,
+        caps_ratio_threshold: Option<f32>,
+        combining_marks_density_threshold: Option<f32>,
+    ) -> Self {
+        let overrides = ThresholdOverrides {
+            deleted_messages_threshold,
+            avg_delay_threshold,
+            avg_delay_min_message_count,
+            avg_length_threshold,
+            avg_length_min_message_count,
+            similarity_threshold,
+            similarity_count_threshold,
+            similarity_min_message_length,
+        };
+
+        let default_flood = FloodParams::default();
+        let flood = FloodParams {
+            min_message_length: flood_min_message_length
+                .unwrap_or(default_flood.min_message_length),
+            emoji_ratio_threshold: emoji_ratio_threshold
+                .unwrap_or(default_flood.emoji_ratio_threshold),
+            repeated_run_threshold: repeated_run_threshold
+                .unwrap_or(default_flood.repeated_run_threshold),
+            caps_min_letters: caps_min_letters.unwrap_or(default_flood.caps_min_letters),
+            caps_ratio_threshold: caps_ratio_threshold
+                .unwrap_or(default_flood.caps_ratio_threshold),
+            combining_marks_density_threshold: combining_marks_density_threshold
+                .unwrap_or(default_flood.combining_marks_density_threshold),
+        };
+
+        let inner = DetectorParams::default()
+            .with_threshold_overrides(&overrides)
+            .with_flood_params(flood);
+        Self { inner }
+    }
+
+    fn __repr__(&self) -> String {
+        format!("{:?}", &self.inner)
+    }
+}
@@ -2,26 +2,53 @@
 
 use std::{collections::HashMap, mem::replace, sync::Arc, time::Duration};
 
-use chat_poller::{ChatPoller, InitResult};
+use chat_poller::{
+    checkpoint::CheckpointStore, error::InitError, ChatPoller, InitResult, ReplayPoller,
+};
 use error::ChatManagerError;
 use shared::{
-    http_client::{HttpClient, RequestSettings},
-    messages::{self, chat_manager::IncMessage},
+    diagnostics::DiagnosticsStore,
+    http_client::{HttpClient, RequestSettings, RetryPolicy},
+    messages::{
+        self,
+        chat_manager::{IncMessage, PollerFailure},
+        chat_poller::PollerKind,
+    },
     ActorWrapper,
 };
 use tokio::{
-    sync::mpsc::{self, Receiver, Sender},
-    time::timeout,
+    sync::mpsc::{self, Receiver, Sender, UnboundedReceiver},
+    time::{sleep_until, Instant},
 };
 
 mod error;
 
+/// Failed poller, that is waiting to be started again
+struct PendingRestart {
+    channel: String,
+    kind: PollerKind,
+    attempt: u32,
+    at: Instant,
+}
+
+/// Poller, that was restarted after a failure
+struct RestartedPoller {
+    attempt: u32,
+    started_at: Instant,
+}
+
 pub struct ChatManager {
     rx: Receiver<IncMessage>,
+    poller_failures: UnboundedReceiver<PollerFailure>,
     check_children_period: Duration,
     http_client: Arc<HttpClient>,
     request_settings: RequestSettings,
+    checkpoints: Option<Arc<CheckpointStore>>,
+    diagnostics: Option<Arc<DiagnosticsStore>>,
     inprogress_chats: HashMap<String, ActorWrapper<messages::chat_poller::IncMessage>>,
+    restart_policy: RetryPolicy,
+    pending_restarts: HashMap<String, PendingRestart>,
+    restarted_pollers: HashMap<String, RestartedPoller>,
     result_tx: Sender<messages::chat_poller::OutMessage>,
 }
 
@@ -30,18 +57,18 @@ impl ChatManager {
         http_client: Arc<HttpClient>,
         request_settings: RequestSettings,
         result_tx: Sender<messages::chat_poller::OutMessage>,
+        poller_failures: UnboundedReceiver<PollerFailure>,
+        diagnostics: Option<Arc<DiagnosticsStore>>,
     ) -> ActorWrapper<IncMessage> {
         let (tx, rx) = mpsc::channel(32);
-        let check_children_period = Duration::from_secs(60);
-
-        let manager = Self {
+        let mut manager = Self::new(
             rx,
-            check_children_period,
+            poller_failures,
             http_client,
             request_settings,
-            inprogress_chats: HashMap::with_capacity(20),
             result_tx,
-        };
+        );
+        manager.diagnostics = diagnostics;
 
         let join_handle = tokio::spawn(async move {
             let _result = manager.run().await;
@@ -51,6 +78,37 @@ impl ChatManager {
         ActorWrapper { join_handle, tx }
     }
 
+    fn new(
+        rx: Receiver<IncMessage>,
+        poller_failures: UnboundedReceiver<PollerFailure>,
+        http_client: Arc<HttpClient>,
+        request_settings: RequestSettings,
+        result_tx: Sender<messages::chat_poller::OutMessage>,
+    ) -> Self {
+        Self {
+            rx,
+            poller_failures,
+            check_children_period: Duration::from_secs(60),
+            http_client,
+            request_settings,
+            checkpoints: None,
+            diagnostics: None,
+            inprogress_chats: HashMap::with_capacity(20),
+            // Pollers retry failed requests on their own,
+            // so by the time a poller fails, YouTube needs a longer break
+            restart_policy: RetryPolicy {
+                max_retries: 10,
+                initial_delay: Duration::from_secs(5),
+                max_delay: Duration::from_secs(300),
+                multiplier: 2.0,
+                jitter: 0.2,
+            },
+            pending_restarts: HashMap::new(),
+            restarted_pollers: HashMap::new(),
+            result_tx,
+        }
+    }
+
     async fn run(mut self) {
         match self.do_run().await {
             Ok(_r) => {
@@ -66,77 +124,302 @@ impl ChatManager {
     }
 
     async fn do_run(&mut self) -> Result<(), ChatManagerError> {
+        let mut next_children_check = Instant::now() + self.check_children_period;
         loop {
-            while let Ok(recv_result) = timeout(self.check_children_period, self.rx.recv()).await {
-                match recv_result {
-                    Some(message) => match message {
-                        IncMessage::Close => return Ok(()),
-                        IncMessage::FoundStreams { channel, streams } => {
-                            let new_streams = streams
-                                .into_iter()
-                                .filter(|video_id| !self.inprogress_chats.contains_key(video_id))
-                                .collect::<Vec<_>>();
-
-                            for video_id in new_streams {
-                                match ChatPoller::init(
-                                    video_id.clone(),
-                                    channel.clone(),
-                                    self.http_client.clone(),
-                                    self.request_settings.clone(),
-                                    self.result_tx.clone(),
-                                )
-                                .await
-                                {
-                                    Ok(r) => match r {
-                                        InitResult::ChatDisabled => {}
-                                        InitResult::Started(inprogress_chat) => {
-                                            self.inprogress_chats.insert(video_id, inprogress_chat);
-                                        }
-                                    },
-                                    Err(e) => {
-                                        // Not a hard error
-                                        shared::tracing_warn!(
-                                            "Couldn't initialize chat poller {}: {}",
-                                            &video_id,
-                                            &e
-                                        );
-                                    }
-                                }
-                            }
-                        }
-                        IncMessage::UpdateUserAgent(user_agent) => {
-                            self.request_settings.user_agent = user_agent.clone();
-                            let poller_message =
-                                messages::chat_poller::IncMessage::UpdateUserAgent(user_agent);
-                            self.send_message_to_pollers(poller_message).await;
+            // The deadline is recalculated after every message, as it may schedule an earlier restart
+            let deadline = self.next_deadline(next_children_check);
+            tokio::select! {
+                recv_result = self.rx.recv() => match recv_result {
+                    Some(message) => {
+                        if !self.handle_message(message).await {
+                            return Ok(());
                         }
-                        IncMessage::UpdateBrowserVersion(version) => {
-                            self.request_settings.browser_version = version.clone();
-                            let poller_message =
-                                messages::chat_poller::IncMessage::UpdateBrowserVersion(version);
-                            self.send_message_to_pollers(poller_message).await;
-                        }
-                        IncMessage::UpdateBrowserNameAndVersion { name, version } => {
-                            self.request_settings.browser_name = name.clone();
-                            self.request_settings.browser_version = name.clone();
-                            let poller_message =
-                                messages::chat_poller::IncMessage::UpdateBrowserNameAndVersion {
-                                    name,
-                                    version,
-                                };
-                            self.send_message_to_pollers(poller_message).await;
-                        }
-                    },
+                    }
                     None => {
                         // Incoming channel was closed. That should never happen,
                         // as the ChatFinder should be closed first, after receiveng the `Close` message
                         return Err(ChatManagerError::IncomingChannelClosed);
                     }
+                },
+                Some(failure) = self.poller_failures.recv() => {
+                    self.schedule_restart(failure).await;
+                }
+                _ = sleep_until(deadline) => {
+                    if Instant::now() >= next_children_check {
+                        self.send_message_to_pollers(messages::chat_poller::IncMessage::Ping)
+                            .await;
+                        next_children_check = Instant::now() + self.check_children_period;
+                    }
+
+                    self.restart_failed_pollers().await;
+                }
+            }
+        }
+    }
+
+    /// Returns `false`, if the manager should be closed
+    async fn handle_message(&mut self, message: IncMessage) -> bool {
+        match message {
+            IncMessage::Close => return false,
+            IncMessage::FoundStreams { channel, streams } => {
+                // Failed pollers are restarted with a backoff instead
+                let new_streams = streams
+                    .into_iter()
+                    .filter(|video_id| {
+                        !self.inprogress_chats.contains_key(video_id)
+                            && !self.pending_restarts.contains_key(video_id)
+                    })
+                    .collect::<Vec<_>>();
+
+                for video_id in new_streams {
+                    if let Err(e) = self
+                        .start_poller(&channel, video_id.clone(), &PollerKind::Live)
+                        .await
+                    {
+                        // Not a hard error
+                        shared::tracing_warn!(
+                            "Couldn't initialize chat poller {}: {}",
+                            &video_id,
+                            &e
+                        );
+                    }
+                }
+            }
+            IncMessage::SetDiagnostics(diagnostics) => {
+                self.diagnostics = diagnostics.clone();
+                let poller_message = messages::chat_poller::IncMessage::SetDiagnostics(diagnostics);
+                self.send_message_to_pollers(poller_message).await;
+            }
+            IncMessage::AddReplay { channel, video_id } => {
+                if self.inprogress_chats.contains_key(&video_id)
+                    || self.pending_restarts.contains_key(&video_id)
+                {
+                    return true;
+                }
+
+                let kind = PollerKind::Replay {
+                    last_offset_ms: None,
+                };
+                match self.start_poller(&channel, video_id.clone(), &kind).await {
+                    Ok(true) => {}
+                    Ok(false) => {
+                        shared::tracing_warn!("Video {} doesn't have a chat replay", &video_id);
+                    }
+                    Err(e) => {
+                        shared::tracing_warn!(
+                            "Couldn't initialize replay poller {}: {}",
+                            &video_id,
+                            &e
+                        );
+                    }
+                }
+            }
+            IncMessage::UpdateUserAgent(user_agent) => {
+                self.request_settings.user_agent = user_agent.clone();
+                let poller_message = messages::chat_poller::IncMessage::UpdateUserAgent(user_agent);
+                self.send_message_to_pollers(poller_message).await;
+            }
+            IncMessage::UpdateBrowserVersion(version) => {
+                self.request_settings.browser_version = version.clone();
+                let poller_message =
+                    messages::chat_poller::IncMessage::UpdateBrowserVersion(version);
+                self.send_message_to_pollers(poller_message).await;
+            }
+            IncMessage::UpdateBrowserNameAndVersion { name, version } => {
+                self.request_settings.browser_name = name.clone();
+                self.request_settings.browser_version = name.clone();
+                let poller_message =
+                    messages::chat_poller::IncMessage::UpdateBrowserNameAndVersion {
+                        name,
+                        version,
+                    };
+                self.send_message_to_pollers(poller_message).await;
+            }
+            IncMessage::SetCheckpointDir(dir) => {
+                // Only new pollers use the new directory
+                self.checkpoints = match dir.map(CheckpointStore::new).transpose() {
+                    Ok(checkpoints) => checkpoints.map(Arc::new),
+                    Err(e) => {
+                        shared::tracing_warn!("Couldn't set the checkpoint directory: {}", &e);
+                        return true;
+                    }
+                };
+            }
+        }
+        true
+    }
+
+    fn next_deadline(&self, next_children_check: Instant) -> Instant {
+        self.pending_restarts
+            .values()
+            .map(|restart| restart.at)
+            .fold(next_children_check, Instant::min)
+    }
+
+    /// Returns `true` if the poller has started
+    async fn start_poller(
+        &mut self,
+        channel: &str,
+        video_id: String,
+        kind: &PollerKind,
+    ) -> Result<bool, InitError> {
+        let init_result = match kind {
+            // Replays aren't checkpointed, so a restarted replay continues from the last offset,
+            // that its failed poller has sent
+            PollerKind::Replay { last_offset_ms } => {
+                ReplayPoller::init(
+                    video_id.clone(),
+                    channel.to_string(),
+                    self.http_client.clone(),
+                    self.request_settings.clone(),
+                    *last_offset_ms,
+                    self.diagnostics.clone(),
+                    self.result_tx.clone(),
+                )
+                .await?
+            }
+            PollerKind::Live => {
+                ChatPoller::init(
+                    video_id.clone(),
+                    channel.to_string(),
+                    self.http_client.clone(),
+                    self.request_settings.clone(),
+                    self.checkpoints.clone(),
+                    self.diagnostics.clone(),
+                    self.result_tx.clone(),
+                )
+                .await?
+            }
+        };
+
+        match init_result {
+            InitResult::ChatDisabled => Ok(false),
+            InitResult::Started(inprogress_chat) => {
+                self.inprogress_chats.insert(video_id, inprogress_chat);
+                Ok(true)
+            }
+        }
+    }
+
+    async fn schedule_restart(&mut self, failure: PollerFailure) {
+        let PollerFailure {
+            channel,
+            video_id,
+            poller,
+            kind,
+        } = failure;
+
+        // The failed poller finishes on its own, so it's never waited for
+        match self.inprogress_chats.get(&video_id) {
+            Some(chat) if chat.join_handle.id() == poller => {
+                self.inprogress_chats.remove(&video_id);
+            }
+            Some(_) => {
+                shared::tracing_info!(
+                    "Chat poller {} has already been replaced, ignoring its failure",
+                    &video_id
+                );
+                return;
+            }
+            // The poller has already been removed, after it had closed its channel
+            None if !self.pending_restarts.contains_key(&video_id) => {}
+            None => return,
+        }
+
+        // A poller, that has been working for a while, gets the whole set of attempts again
+        let attempt = match self.restarted_pollers.remove(&video_id) {
+            Some(restarted) if restarted.started_at.elapsed() < self.restart_policy.max_delay => {
+                restarted.attempt + 1
+            }
+            _ => 1,
+        };
+        self.schedule_attempt(channel, video_id, kind, attempt)
+            .await;
+    }
+
+    async fn schedule_attempt(
+        &mut self,
+        channel: String,
+        video_id: String,
+        kind: PollerKind,
+        attempt: u32,
+    ) {
+        match self.restart_policy.backoff(attempt) {
+            Some(delay) => {
+                shared::tracing_info!(
+                    "Restarting chat poller {} in {:?}, attempt #{}",
+                    &video_id,
+                    &delay,
+                    attempt
+                );
+                let restart = PendingRestart {
+                    channel,
+                    kind,
+                    attempt,
+                    at: Instant::now() + delay,
+                };
+                self.pending_restarts.insert(video_id, restart);
+            }
+            None => {
+                shared::tracing_warn!(
+                    "Chat poller {} has failed too many times, giving up",
+                    &video_id
+                );
+                self.end_stream(channel, video_id).await;
+            }
+        }
+    }
+
+    async fn restart_failed_pollers(&mut self) {
+        let now = Instant::now();
+        let due_restarts = self
+            .pending_restarts
+            .iter()
+            .filter(|(_, restart)| restart.at <= now)
+            .map(|(video_id, _)| video_id.clone())
+            .collect::<Vec<_>>();
+
+        for video_id in due_restarts {
+            let restart = match self.pending_restarts.remove(&video_id) {
+                Some(restart) => restart,
+                None => continue,
+            };
+
+            match self
+                .start_poller(&restart.channel, video_id.clone(), &restart.kind)
+                .await
+            {
+                Ok(true) => {
+                    let restarted = RestartedPoller {
+                        attempt: restart.attempt,
+                        started_at: Instant::now(),
+                    };
+                    self.restarted_pollers.insert(video_id, restarted);
+                }
+                Ok(false) => {
+                    // The chat was closed, while the poller was down
+                    self.end_stream(restart.channel, video_id).await;
+                }
+                Err(e) => {
+                    shared::tracing_warn!("Couldn't restart chat poller {}: {}", &video_id, &e);
+                    self.schedule_attempt(
+                        restart.channel,
+                        video_id,
+                        restart.kind,
+                        restart.attempt + 1,
+                    )
+                    .await;
                 }
             }
+        }
+    }
 
-            self.send_message_to_pollers(messages::chat_poller::IncMessage::Ping)
-                .await;
+    /// Lets the detector know, that the stream of the failed poller won't be polled anymore
+    async fn end_stream(&mut self, channel: String, video_id: String) {
+        self.restarted_pollers.remove(&video_id);
+        let message = messages::chat_poller::OutMessage::StreamEnded { channel, video_id };
+        if let Err(e) = self.result_tx.send(message).await {
+            shared::tracing_error!("Couldn't send `StreamEnded` message: {}", &e);
         }
     }
 
@@ -150,21 +433,10 @@ impl ChatManager {
                     self.inprogress_chats.insert(video_id, inprogress_chat);
                 }
                 Err(_) => {
-                    shared::tracing_info!(
-                        "Chat poller {} has closed its channel, waiting for the task to finish...",
-                        &video_id
-                    );
-                    match inprogress_chat.join_handle.await {
-                        Ok(_r) => {
-                            shared::tracing_info!(
-                                "Chat poller {} finished successfully",
-                                &video_id
-                            );
-                        }
-                        Err(e) => {
-                            shared::tracing_info!("Chat poller {} panicked: {}", &video_id, &e);
-                        }
-                    }
+                    // The poller may still be sending its closing message,
+                    // so it's left to finish on its own
+                    shared::tracing_info!("Chat poller {} has closed its channel", &video_id);
+                    self.restarted_pollers.remove(&video_id);
                 }
             }
         }
@@ -187,3 +459,157 @@ impl ChatManager {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, time::Duration};
+
+    use shared::{
+        http_client::{HttpClient, RequestSettings, RetryPolicy},
+        messages::{
+            chat_manager::PollerFailure,
+            chat_poller::{self, PollerKind},
+        },
+        ActorWrapper, AlkSender,
+    };
+    use tokio::{sync::mpsc, task::Id, time::Instant};
+
+    use super::{ChatManager, RestartedPoller};
+
+    fn manager(result_tx: mpsc::Sender<chat_poller::OutMessage>) -> ChatManager {
+        let (_tx, rx) = mpsc::channel(1);
+        let (_failures_tx, poller_failures) = mpsc::unbounded_channel();
+        let request_settings = RequestSettings {
+            user_agent: "user_agent".to_string(),
+            browser_name: "Firefox".to_string(),
+            browser_version: "90.0".to_string(),
+            retry_policy: RetryPolicy::default(),
+        };
+        let mut manager = ChatManager::new(
+            rx,
+            poller_failures,
+            Arc::new(HttpClient::init().unwrap()),
+            request_settings,
+            result_tx,
+        );
+        manager.restart_policy = RetryPolicy {
+            max_retries: 2,
+            initial_delay: Duration::from_millis(1),
+            max_delay: Duration::from_millis(10),
+            multiplier: 2.0,
+            jitter: 0.0,
+        };
+        manager
+    }
+
+    /// Poller, that never finishes, so waiting for it would hang the test
+    fn start_poller(manager: &mut ChatManager, video_id: &str) -> Id {
+        let (tx, _rx) = mpsc::channel(1);
+        let poller = ActorWrapper {
+            join_handle: tokio::spawn(std::future::pending()),
+            tx: AlkSender::new(tx, "ChatPoller_tx".to_string()),
+        };
+        let id = poller.join_handle.id();
+        manager
+            .inprogress_chats
+            .insert(video_id.to_string(), poller);
+        id
+    }
+
+    /// Does what `restart_failed_pollers` does, once the restarted poller has started
+    fn restart_poller(manager: &mut ChatManager, video_id: &str) -> Id {
+        let restart = manager.pending_restarts.remove(video_id).unwrap();
+        let restarted = RestartedPoller {
+            attempt: restart.attempt,
+            started_at: Instant::now(),
+        };
+        manager
+            .restarted_pollers
+            .insert(video_id.to_string(), restarted);
+        start_poller(manager, video_id)
+    }
+
+    fn failure(video_id: &str, poller: Id) -> PollerFailure {
+        PollerFailure {
+            channel: "channel".to_string(),
+            video_id: video_id.to_string(),
+            poller,
+            kind: PollerKind::Live,
+        }
+    }
+
+    #[tokio::test]
+    async fn restarts_only_the_poller_that_has_failed() {
+        let (result_tx, _result_rx) = mpsc::channel(32);
+        let mut manager = manager(result_tx);
+        let poller = start_poller(&mut manager, "video");
+        let other_poller = tokio::spawn(async {}).id();
+
+        manager
+            .schedule_restart(failure("video", other_poller))
+            .await;
+        assert!(manager.inprogress_chats.contains_key("video"));
+        assert!(manager.pending_restarts.is_empty());
+
+        manager.schedule_restart(failure("video", poller)).await;
+        assert!(manager.inprogress_chats.is_empty());
+        assert_eq!(manager.pending_restarts["video"].attempt, 1);
+
+        // Poller, that was removed after closing its channel, is still restarted, but only once
+        let poller = restart_poller(&mut manager, "video");
+        manager.inprogress_chats.clear();
+        manager.schedule_restart(failure("video", poller)).await;
+        manager.schedule_restart(failure("video", poller)).await;
+        assert_eq!(manager.pending_restarts["video"].attempt, 2);
+    }
+
+    #[tokio::test]
+    async fn backs_off_until_giving_up_on_the_stream() {
+        let (result_tx, mut result_rx) = mpsc::channel(32);
+        let mut manager = manager(result_tx);
+
+        let poller = start_poller(&mut manager, "video");
+        manager.schedule_restart(failure("video", poller)).await;
+        let first_restart = &manager.pending_restarts["video"];
+        assert_eq!(first_restart.attempt, 1);
+        assert!(first_restart.at <= Instant::now() + Duration::from_millis(1));
+
+        let poller = restart_poller(&mut manager, "video");
+        manager.schedule_restart(failure("video", poller)).await;
+        assert_eq!(manager.pending_restarts["video"].attempt, 2);
+
+        // A poller, that has been working for longer than the maximum delay, starts over
+        let poller = restart_poller(&mut manager, "video");
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        manager.schedule_restart(failure("video", poller)).await;
+        assert_eq!(manager.pending_restarts["video"].attempt, 1);
+
+        for _ in 0..2 {
+            let poller = restart_poller(&mut manager, "video");
+            manager.schedule_restart(failure("video", poller)).await;
+        }
+        assert!(manager.pending_restarts.is_empty());
+        assert!(manager.restarted_pollers.is_empty());
+        match result_rx.try_recv().unwrap() {
+            chat_poller::OutMessage::StreamEnded { video_id, .. } => assert_eq!(video_id, "video"),
+            message => panic!("Unexpected message: {:?}", message),
+        }
+    }
+
+    #[tokio::test]
+    async fn restarts_replays_from_the_last_sent_offset() {
+        let (result_tx, _result_rx) = mpsc::channel(32);
+        let mut manager = manager(result_tx);
+        let poller = start_poller(&mut manager, "video");
+        let kind = PollerKind::Replay {
+            last_offset_ms: Some(2500),
+        };
+
+        let replay_failure = PollerFailure {
+            kind: kind.clone(),
+            ..failure("video", poller)
+        };
+        manager.schedule_restart(replay_failure).await;
+        assert_eq!(manager.pending_restarts["video"].kind, kind);
+    }
+}
@@ -1,15 +1,15 @@
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 
-#[derive(Serialize)]
-#[serde(rename_all(serialize = "camelCase"))]
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
 struct MainWebAppInfo {
     graft_url: String,
     web_display_mode: String,
     is_web_native_share_available: bool,
 }
 
-#[derive(Serialize)]
-#[serde(rename_all(serialize = "camelCase"))]
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
 struct Client {
     hl: String,
     gl: String,
@@ -37,22 +37,22 @@ struct Client {
     main_app_web_info: MainWebAppInfo,
 }
 
-#[derive(Serialize)]
-#[serde(rename_all(serialize = "camelCase"))]
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
 struct User {
     locked_safety_mode: bool,
 }
 
-#[derive(Serialize)]
-#[serde(rename_all(serialize = "camelCase"))]
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
 struct Request {
     use_ssl: bool,
     internal_experiment_flags: Vec<String>,
     consistency_token_jars: Vec<String>,
 }
 
-#[derive(Serialize)]
-#[serde(rename_all(serialize = "camelCase"))]
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
 struct AdParam {
     key: String,
     value: String,
@@ -67,14 +67,14 @@ impl AdParam {
     }
 }
 
-#[derive(Serialize)]
-#[serde(rename_all(serialize = "camelCase"))]
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
 struct AdSignalsInfo {
     params: Vec<AdParam>,
 }
 
-#[derive(Serialize)]
-#[serde(rename_all(serialize = "camelCase"))]
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
 struct Context {
     client: Client,
     user: User,
@@ -82,17 +82,26 @@ struct Context {
     ad_signals_info: AdSignalsInfo,
 }
 
-#[derive(Serialize)]
-#[serde(rename_all(serialize = "camelCase"))]
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
 struct WebClientInfo {
     is_document_hidden: bool,
 }
 
-#[derive(Serialize)]
-#[serde(rename_all(serialize = "camelCase"))]
+/// Position of the video player, that's used for paging through a chat replay
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct PlayerState {
+    player_offset_ms: String,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
 pub struct ChatParams {
     context: Context,
     continuation: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    current_player_state: Option<PlayerState>,
     web_client_info: WebClientInfo,
 }
 
@@ -201,6 +210,7 @@ impl ChatParams {
         ChatParams {
             context,
             continuation,
+            current_player_state: None,
             web_client_info,
         }
     }
@@ -208,4 +218,18 @@ impl ChatParams {
     pub fn update_continuation(&mut self, new_continuation: String) {
         self.continuation = new_continuation;
     }
+
+    pub fn continuation(&self) -> &str {
+        &self.continuation
+    }
+
+    pub fn client_version(&self) -> &str {
+        &self.context.client.client_version
+    }
+
+    pub fn update_player_offset(&mut self, offset_ms: u64) {
+        self.current_player_state = Some(PlayerState {
+            player_offset_ms: offset_ms.to_string(),
+        });
+    }
 }
@@ -0,0 +1,115 @@
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{chat_params::ChatParams, error::CheckpointError};
+
+#[derive(Deserialize)]
+pub(crate) struct Checkpoint {
+    pub chat_key: String,
+    pub chat_params: ChatParams,
+}
+
+#[derive(Serialize)]
+struct CheckpointRef<'a> {
+    chat_key: &'a str,
+    chat_params: &'a ChatParams,
+}
+
+/// Directory with the latest `ChatParams` of every active chat, one file per video id,
+/// so that a restarted poller can continue from where the previous one stopped
+pub struct CheckpointStore {
+    dir: PathBuf,
+}
+
+impl CheckpointStore {
+    pub fn new(dir: PathBuf) -> Result<Self, CheckpointError> {
+        fs::create_dir_all(&dir)?;
+        Ok(Self { dir })
+    }
+
+    pub(crate) fn load(&self, video_id: &str) -> Result<Option<Checkpoint>, CheckpointError> {
+        match fs::read(self.path(video_id)) {
+            Ok(content) => Ok(Some(serde_json::from_slice(&content)?)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Writes the file on a blocking thread, so that the poller's task never waits for the disk
+    pub(crate) async fn save(
+        &self,
+        video_id: &str,
+        chat_key: &str,
+        chat_params: &ChatParams,
+    ) -> Result<(), CheckpointError> {
+        let checkpoint = CheckpointRef {
+            chat_key,
+            chat_params,
+        };
+        let content = serde_json::to_vec(&checkpoint)?;
+        let path = self.path(video_id);
+        tokio::task::spawn_blocking(move || shared::fs::write_atomically(&path, &content))
+            .await
+            .map_err(io::Error::other)??;
+        Ok(())
+    }
+
+    /// Checkpoints of the finished chats are no longer needed
+    pub(crate) fn remove(&self, video_id: &str) -> Result<(), CheckpointError> {
+        match fs::remove_file(self.path(video_id)) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+
+    fn path(&self, video_id: &str) -> PathBuf {
+        Path::new(&self.dir).join(format!("{}.json", video_id))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::CheckpointStore;
+    use crate::chat_params::ChatParams;
+
+    #[tokio::test]
+    async fn checkpoint_roundtrip() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = CheckpointStore::new(dir.path().to_path_buf()).unwrap();
+        let mut chat_params = ChatParams::init(
+            "US".to_string(),
+            "127.0.0.1".to_string(),
+            "visitor_data".to_string(),
+            "user_agent".to_string(),
+            "2.20210101".to_string(),
+            "video_id",
+            "Asia/Tokyo".to_string(),
+            "Firefox".to_string(),
+            "90.0".to_string(),
+            0,
+            540,
+            "first".to_string(),
+        );
+        chat_params.update_continuation("second".to_string());
+
+        assert!(store.load("video_id").unwrap().is_none());
+        store
+            .save("video_id", "chat_key", &chat_params)
+            .await
+            .unwrap();
+
+        let checkpoint = store.load("video_id").unwrap().unwrap();
+        assert_eq!(checkpoint.chat_key, "chat_key");
+        assert_eq!(
+            serde_json::to_string(&checkpoint.chat_params).unwrap(),
+            serde_json::to_string(&chat_params).unwrap()
+        );
+
+        store.remove("video_id").unwrap();
+        assert!(store.load("video_id").unwrap().is_none());
+    }
+}
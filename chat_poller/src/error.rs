@@ -2,8 +2,6 @@ use shared::{http_client::HttpClientLoadError, messages::chat_poller::OutMessage
 use thiserror::Error;
 use tokio::sync::mpsc::error::SendError;
 
-use crate::type_converter::ConverterError;
-
 #[derive(Error, Debug)]
 pub enum InitError {
     #[error("Couldn't load chat page: {0}")]
@@ -44,10 +42,10 @@ pub enum PollerError {
     LoadingMessages(#[source] HttpClientLoadError),
     #[error("Couldn't send message to chat manager: {0}")]
     SendToDetector(#[source] SendError<OutMessage>),
-    #[error("Couldn't dump error {0} due to another error {1}")]
-    DumpError(ActionExtractorError, std::io::Error),
     #[error("Error while extracting actions from json {0}")]
     Extractor(ActionExtractorError),
+    #[error("Couldn't refresh chat params: {0}")]
+    RefreshParams(#[source] InitError),
     #[error("Channel closed")]
     ChannelClosed,
 }
@@ -80,8 +78,6 @@ impl From<HttpClientLoadError> for PollerError {
 pub enum ActionExtractorError {
     #[error("Couldn't deserialize chat json: {0}")]
     DeserializeChat(#[source] serde_json::Error),
-    #[error("Couldn't convert actions to shared types: {0}")]
-    Converter(#[source] ConverterError),
 }
 
 impl From<serde_json::Error> for ActionExtractorError {
@@ -90,8 +86,22 @@ impl From<serde_json::Error> for ActionExtractorError {
     }
 }
 
-impl From<ConverterError> for ActionExtractorError {
-    fn from(e: ConverterError) -> Self {
-        ActionExtractorError::Converter(e)
+#[derive(Error, Debug)]
+pub enum CheckpointError {
+    #[error("Couldn't access the checkpoint: {0}")]
+    Io(#[source] std::io::Error),
+    #[error("Couldn't (de)serialize the checkpoint: {0}")]
+    Json(#[source] serde_json::Error),
+}
+
+impl From<std::io::Error> for CheckpointError {
+    fn from(e: std::io::Error) -> Self {
+        CheckpointError::Io(e)
+    }
+}
+
+impl From<serde_json::Error> for CheckpointError {
+    fn from(e: serde_json::Error) -> Self {
+        CheckpointError::Json(e)
     }
 }
@@ -1,14 +1,20 @@
 #![allow(proc_macro_derive_resolution_fallback, unused_attributes)]
 
-use std::{fs::File, io::Write, sync::Arc, time::Duration};
+use std::{
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
 
 use chat_params::ChatParams;
+use checkpoint::CheckpointStore;
 use error::{ActionExtractorError, InitError, PollerError};
 use params_extractor::{ExtractingResult, ParamsExtractor};
+use serde::de::DeserializeOwned;
 use shared::{
+    diagnostics::{self, DiagnosticsStore, FailureBundle},
     http_client::{HttpClient, RequestSettings},
-    messages::chat_poller::{IncMessage, OutMessage},
-    types::Action,
+    messages::chat_poller::{IncMessage, OutMessage, PollerKind},
+    types::{Action, Timestamp},
     ActorWrapper,
 };
 use tokio::{
@@ -18,17 +24,48 @@ use tokio::{
 use type_converter::Converter;
 use youtube_types::root::{ChatJson, Continuation};
 
+/// Reload continuations come without a timeout, so a small delay keeps the poller
+/// from hammering YouTube, if it keeps asking to reload the chat
+const RELOAD_DELAY: Duration = Duration::from_secs(1);
+
+/// How many times in a row the params can be re-extracted without getting a single continuation,
+/// before the chat is considered closed
+const MAX_CONSECUTIVE_REFRESHES: u32 = 2;
+
 mod chat_params;
+pub mod checkpoint;
 pub mod error;
 mod params_extractor;
+mod replay_poller;
 mod type_converter;
 mod youtube_types;
 
+/// Extracts actions from a stored response of the chat endpoint,
+/// using the same parser as the poller. Actions without their own time get `received_at`,
+/// the time, when the response was received, or the time of the latest message of the response,
+/// if it's later.
+pub fn parse_chat_json(
+    json: &str,
+    received_at: Timestamp,
+) -> Result<Vec<Action>, ActionExtractorError> {
+    let (actions, _continuation) = ChatPoller::extract_messages_from_json(json, received_at)?;
+    Ok(actions.unwrap_or_default())
+}
+
+pub use replay_poller::ReplayPoller;
+
 pub enum InitResult {
     Started(ActorWrapper<IncMessage>),
     ChatDisabled,
 }
 
+struct ResumedChat {
+    chat_params: ChatParams,
+    chat_key: String,
+    actions: Option<Vec<Action>>,
+    next_poll_time: Instant,
+}
+
 pub struct ChatPoller {
     channel: String,
     video_id: String,
@@ -38,9 +75,15 @@ pub struct ChatPoller {
     endpoint_url: String,
     next_poll_time: Instant,
     chat_params: ChatParams,
+    chat_key: String,
+    consecutive_refreshes: u32,
+    unknown_actions_count: usize,
+    checkpoints: Option<Arc<CheckpointStore>>,
+    /// Continuation of the latest saved checkpoint
+    saved_continuation: Option<String>,
+    diagnostics: Option<Arc<DiagnosticsStore>>,
     rx: Receiver<IncMessage>,
     result_tx: Sender<OutMessage>,
-    poll_errors_count: u8,
 }
 
 impl ChatPoller {
@@ -48,36 +91,61 @@ impl ChatPoller {
         video_id: String,
         channel: String,
         http_client: Arc<HttpClient>,
-        request_settings: RequestSettings,
+        mut request_settings: RequestSettings,
+        checkpoints: Option<Arc<CheckpointStore>>,
+        mut diagnostics: Option<Arc<DiagnosticsStore>>,
         result_tx: Sender<OutMessage>,
     ) -> Result<InitResult, InitError> {
         let chat_url = format!(
             "https://www.youtube.com/live_chat?is_popout=1&v={}",
             &video_id
         );
-        let extract_result = ParamsExtractor::extract_chat_params(
-            &video_id,
-            &chat_url,
-            &http_client,
-            &request_settings,
-        )
-        .await?;
 
-        let (chat_params, chat_key) = match extract_result {
-            ExtractingResult::ChatDisabled => {
-                return Ok(InitResult::ChatDisabled);
+        let resumed_chat = match &checkpoints {
+            Some(checkpoints) => {
+                ChatPoller::resume(
+                    &video_id,
+                    &chat_url,
+                    checkpoints,
+                    &http_client,
+                    &mut request_settings,
+                    &mut diagnostics,
+                )
+                .await
+            }
+            None => None,
+        };
+
+        let (chat_params, chat_key, first_actions, next_poll_time) = match resumed_chat {
+            Some(resumed_chat) => (
+                resumed_chat.chat_params,
+                resumed_chat.chat_key,
+                resumed_chat.actions,
+                resumed_chat.next_poll_time,
+            ),
+            None => {
+                let extract_result = ParamsExtractor::extract_chat_params(
+                    &video_id,
+                    &chat_url,
+                    &http_client,
+                    &request_settings,
+                )
+                .await?;
+
+                match extract_result {
+                    ExtractingResult::ChatDisabled => {
+                        return Ok(InitResult::ChatDisabled);
+                    }
+                    ExtractingResult::Extracted {
+                        chat_params,
+                        chat_key,
+                    } => (chat_params, chat_key, None, Instant::now()),
+                }
             }
-            ExtractingResult::Extracted {
-                chat_params,
-                chat_key,
-            } => (chat_params, chat_key),
         };
 
         let (tx, rx) = mpsc::channel(32);
-        let endpoint_url = format!(
-            "https://www.youtube.com/youtubei/v1/live_chat/get_live_chat?key={}",
-            &chat_key
-        );
+        let endpoint_url = ChatPoller::endpoint_url(&chat_key);
 
         let poller = Self {
             channel: channel.clone(),
@@ -86,18 +154,37 @@ impl ChatPoller {
             request_settings,
             referer_url: chat_url,
             endpoint_url,
-            next_poll_time: Instant::now(),
+            next_poll_time,
             chat_params,
+            chat_key,
+            consecutive_refreshes: 0,
+            unknown_actions_count: 0,
+            checkpoints,
+            saved_continuation: None,
+            diagnostics,
             rx,
             result_tx,
-            poll_errors_count: 0,
         };
 
         poller
             .result_tx
-            .send(OutMessage::ChatInit { channel, video_id })
+            .send(OutMessage::ChatInit {
+                channel: channel.clone(),
+                video_id: video_id.clone(),
+            })
             .await?;
 
+        if let Some(actions) = first_actions {
+            poller
+                .result_tx
+                .send(OutMessage::NewBatch {
+                    channel,
+                    video_id,
+                    actions,
+                })
+                .await?;
+        }
+
         let join_handle = tokio::spawn(async move {
             poller.run().await;
         });
@@ -109,152 +196,537 @@ impl ChatPoller {
 
     async fn run(mut self) {
         let result = self.do_run().await;
-        match result {
-            Ok(_r) => {
-                // Chat poller finished its work because the stream has ended and the chat room has been closed
-                // or because the poller received `Close` message
-            }
-            Err(e) => {
-                shared::tracing_error!(
-                    "{}: Error, while processing messages: {}",
-                    &self.video_id,
-                    &e
-                );
-            }
-        }
-
-        shared::tracing_info!("{}: Sending `StreamEnded` message...", &self.video_id);
-        let closing_message = OutMessage::StreamEnded {
-            channel: self.channel.clone(),
-            video_id: self.video_id.clone(),
-        };
-        match self.result_tx.send(closing_message).await {
-            Ok(_r) => {
-                // Nothing else to do
-            }
-            Err(e) => {
-                shared::tracing_error!(
-                    "{}: Couldn't send `StreamEnded` message: {}",
-                    &self.video_id,
-                    &e
-                );
-            }
-        }
-
-        shared::tracing_info!("{}: Chat poller has been closed", self.video_id);
+        send_closing_message(
+            result,
+            &self.channel,
+            &self.video_id,
+            PollerKind::Live,
+            &self.result_tx,
+        )
+        .await;
     }
 
     async fn do_run(&mut self) -> Result<(), PollerError> {
         loop {
-            while let Ok(recv_result) = timeout_at(self.next_poll_time, self.rx.recv()).await {
-                match recv_result {
-                    Some(message) => match message {
-                        IncMessage::Close => return Ok(()),
-                        IncMessage::Ping => {
-                            // Do nothing
-                        }
-                        IncMessage::UpdateUserAgent(user_agent) => {
-                            self.request_settings.user_agent = user_agent;
-                        }
-                        IncMessage::UpdateBrowserVersion(version) => {
-                            self.request_settings.browser_version = version;
-                        }
-                        IncMessage::UpdateBrowserNameAndVersion { name, version } => {
-                            self.request_settings.browser_name = name;
-                            self.request_settings.browser_version = version;
-                        }
-                    },
-                    None => {
-                        return Err(PollerError::ChannelClosed);
-                    }
-                }
+            if !handle_messages_until(
+                &mut self.rx,
+                &mut self.request_settings,
+                &mut self.diagnostics,
+                self.next_poll_time,
+            )
+            .await?
+            {
+                return Ok(());
             }
 
-            let chat_json = self.load_new_messages().await?;
-            let (actions, continuation) = match Self::extract_messages_from_json(&chat_json) {
-                Ok((actions, continuation)) => (actions, continuation),
-                Err(e) => {
-                    let mut response_output = match File::create(format!("{}.rsp", &self.video_id))
-                    {
-                        Ok(file) => file,
-                        Err(io_e) => return Err(PollerError::DumpError(e, io_e)),
-                    };
-
-                    match write!(response_output, "{}", &chat_json) {
-                        Ok(_r) => {}
-                        Err(io_e) => return Err(PollerError::DumpError(e, io_e)),
-                    };
-
-                    return Err(e.into());
-                }
+            let loaded = ChatPoller::load_new_messages(
+                &self.video_id,
+                &self.http_client,
+                &mut self.request_settings,
+                &self.endpoint_url,
+                &self.referer_url,
+                &self.chat_params,
+                &mut self.diagnostics,
+                Some(&mut self.rx),
+            )
+            .await?;
+            let (actions, continuation) = match loaded {
+                Some(loaded) => loaded,
+                // Closed while waiting for the next attempt
+                None => return Ok(()),
             };
 
+            if let Some(actions) = actions {
+                log_unknown_actions(&self.video_id, &actions, &mut self.unknown_actions_count);
+                let polling_results = OutMessage::NewBatch {
+                    channel: self.channel.clone(),
+                    video_id: self.video_id.clone(),
+                    actions,
+                };
+                self.result_tx.send(polling_results).await?;
+            }
+
             match continuation {
                 Some(continuation) => {
-                    self.next_poll_time =
-                        Instant::now() + Duration::from_millis(continuation.timeout_ms as u64);
-                    self.chat_params
-                        .update_continuation(continuation.continuation);
+                    if let Continuation::Reload { .. } = &continuation {
+                        shared::tracing_info!("{}: Reloading the chat...", &self.video_id);
+                    }
+                    let (continuation, delay) = continuation.into_parts(RELOAD_DELAY);
+                    self.consecutive_refreshes = 0;
+                    self.next_poll_time = Instant::now() + delay;
+                    self.chat_params.update_continuation(continuation);
                 }
                 None => {
-                    return Ok(());
+                    // Response without a continuation means either that the chat has been closed,
+                    // or that YouTube no longer accepts the current params. Only the chat page can tell.
+                    if !self.refresh_params().await? {
+                        self.remove_checkpoint();
+                        return Ok(());
+                    }
                 }
             }
+            // Saving only after the batch was sent, so that a crash can't skip any messages
+            self.save_checkpoint().await;
+        }
+    }
 
-            if let Some(actions) = actions {
-                let polling_results = OutMessage::NewBatch {
-                    channel: self.channel.clone(),
-                    video_id: self.video_id.clone(),
+    /// Loads the chat page again and replaces the params with the extracted ones.
+    /// Returns `false`, if the chat has been closed.
+    async fn refresh_params(&mut self) -> Result<bool, PollerError> {
+        if self.consecutive_refreshes >= MAX_CONSECUTIVE_REFRESHES {
+            return Ok(false);
+        }
+        self.consecutive_refreshes += 1;
+
+        shared::tracing_info!("{}: Refreshing chat params...", &self.video_id);
+        let extract_result = ParamsExtractor::extract_chat_params(
+            &self.video_id,
+            &self.referer_url,
+            &self.http_client,
+            &self.request_settings,
+        )
+        .await;
+
+        match extract_result {
+            Ok(ExtractingResult::Extracted {
+                chat_params,
+                chat_key,
+            }) => {
+                self.endpoint_url = ChatPoller::endpoint_url(&chat_key);
+                self.chat_params = chat_params;
+                self.chat_key = chat_key;
+                self.next_poll_time = Instant::now();
+                Ok(true)
+            }
+            // The page of a finished stream no longer has a chat to continue
+            Ok(ExtractingResult::ChatDisabled) | Err(InitError::Continuation(_)) => Ok(false),
+            Err(e) => Err(PollerError::RefreshParams(e)),
+        }
+    }
+
+    /// Tries to continue polling with the params from the checkpoint.
+    /// Returns `None` if there's no checkpoint, or if its continuation no longer works.
+    async fn resume(
+        video_id: &str,
+        chat_url: &str,
+        checkpoints: &CheckpointStore,
+        http_client: &HttpClient,
+        request_settings: &mut RequestSettings,
+        diagnostics: &mut Option<Arc<DiagnosticsStore>>,
+    ) -> Option<ResumedChat> {
+        let checkpoint = match checkpoints.load(video_id) {
+            Ok(Some(checkpoint)) => checkpoint,
+            Ok(None) => return None,
+            Err(e) => {
+                shared::tracing_warn!("{}: Couldn't load the checkpoint: {}", video_id, &e);
+                return None;
+            }
+        };
+
+        let loaded = ChatPoller::load_new_messages(
+            video_id,
+            http_client,
+            request_settings,
+            &ChatPoller::endpoint_url(&checkpoint.chat_key),
+            chat_url,
+            &checkpoint.chat_params,
+            diagnostics,
+            None,
+        )
+        .await;
+
+        match loaded {
+            Ok(Some((actions, Some(continuation)))) => {
+                shared::tracing_info!("{}: Resumed from the checkpoint", video_id);
+                let (continuation, delay) = continuation.into_parts(RELOAD_DELAY);
+                let mut chat_params = checkpoint.chat_params;
+                chat_params.update_continuation(continuation);
+                Some(ResumedChat {
+                    chat_params,
+                    chat_key: checkpoint.chat_key,
                     actions,
-                };
-                self.result_tx.send(polling_results).await?;
+                    next_poll_time: Instant::now() + delay,
+                })
+            }
+            Ok(Some((_, None))) => {
+                shared::tracing_info!("{}: Continuation from the checkpoint has expired", video_id);
+                None
+            }
+            Ok(None) => None,
+            Err(e) => {
+                shared::tracing_warn!("{}: Couldn't resume from the checkpoint: {}", video_id, &e);
+                None
             }
         }
     }
 
-    async fn load_new_messages(&mut self) -> Result<String, PollerError> {
-        let body = serde_json::to_string(&self.chat_params)?;
+    /// Saves the params only if the continuation has changed since the last checkpoint
+    async fn save_checkpoint(&mut self) {
+        let checkpoints = match &self.checkpoints {
+            Some(checkpoints) => checkpoints,
+            None => return,
+        };
+        let continuation = self.chat_params.continuation();
+        if self.saved_continuation.as_deref() == Some(continuation) {
+            return;
+        }
 
-        loop {
-            let chat_response = self
-                .http_client
-                .post_request(
-                    &self.endpoint_url,
-                    &self.request_settings.user_agent,
-                    &self.referer_url,
-                    body.clone(),
-                )
-                .await;
-            match chat_response {
-                Ok(response) => {
-                    self.poll_errors_count = 0;
-                    break Ok(response);
-                }
-                Err(e) => {
-                    self.poll_errors_count += 1;
-                    if self.poll_errors_count > 2 {
-                        return Err(e.into());
-                    }
-                    shared::tracing_warn!(
-                        "{}: Encountered an error while polling for new messages. Retrying in 100ms. Attempt #: {}. Error: {}",
-                        &self.video_id,
-                        self.poll_errors_count,
-                        &e
-                    );
-                    tokio::time::sleep(Duration::from_millis(100)).await;
-                }
+        match checkpoints
+            .save(&self.video_id, &self.chat_key, &self.chat_params)
+            .await
+        {
+            Ok(_r) => self.saved_continuation = Some(continuation.to_string()),
+            Err(e) => {
+                shared::tracing_warn!("{}: Couldn't save the checkpoint: {}", &self.video_id, &e)
+            }
+        }
+    }
+
+    fn remove_checkpoint(&self) {
+        if let Some(checkpoints) = &self.checkpoints {
+            if let Err(e) = checkpoints.remove(&self.video_id) {
+                shared::tracing_warn!("{}: Couldn't remove the checkpoint: {}", &self.video_id, &e);
             }
         }
     }
 
+    fn endpoint_url(chat_key: &str) -> String {
+        format!(
+            "https://www.youtube.com/youtubei/v1/live_chat/get_live_chat?key={}",
+            chat_key
+        )
+    }
+
+    /// Requests the next messages with the retry policy, and parses them.
+    /// Responses, that couldn't be parsed, are saved to the `diagnostics`.
+    /// Returns `None`, if the poller was closed while waiting for the next attempt.
+    #[allow(clippy::too_many_arguments)]
+    async fn load_new_messages(
+        video_id: &str,
+        http_client: &HttpClient,
+        request_settings: &mut RequestSettings,
+        endpoint_url: &str,
+        referer_url: &str,
+        chat_params: &ChatParams,
+        diagnostics: &mut Option<Arc<DiagnosticsStore>>,
+        rx: Option<&mut Receiver<IncMessage>>,
+    ) -> Result<Option<(Option<Vec<Action>>, Option<Continuation>)>, PollerError> {
+        let body = serde_json::to_string(chat_params)?;
+
+        let requested_at = SystemTime::now();
+        let chat_json = match post_with_retries(
+            video_id,
+            http_client,
+            request_settings,
+            endpoint_url,
+            referer_url,
+            body,
+            rx.map(|rx| (rx, &mut *diagnostics)),
+        )
+        .await?
+        {
+            Some(chat_json) => chat_json,
+            None => return Ok(None),
+        };
+
+        let extracted = ChatPoller::extract_messages_from_json(&chat_json, Timestamp::now());
+        extracted.map(Some).map_err(|e| {
+            let bundle = failure_bundle::<ChatJson>(
+                "chat_poller",
+                video_id,
+                endpoint_url,
+                chat_params,
+                &chat_json,
+                requested_at,
+                &e,
+            );
+            diagnostics::save_failure(diagnostics.as_deref(), &bundle);
+            e.into()
+        })
+    }
+
     fn extract_messages_from_json(
         json: &str,
+        received_at: Timestamp,
     ) -> Result<(Option<Vec<Action>>, Option<Continuation>), ActionExtractorError> {
         let chat_json = serde_json::from_str::<ChatJson>(json)?;
 
         let continuation = chat_json.continuation;
-        let actions = chat_json.actions.map(Converter::convert).transpose()?;
+        let actions = chat_json
+            .actions
+            .map(|actions| Converter::convert(actions, received_at));
 
         Ok((actions, continuation))
     }
 }
+
+/// Handles incoming messages until the deadline. Returns `false`, if the poller should be closed.
+async fn handle_messages_until(
+    rx: &mut Receiver<IncMessage>,
+    request_settings: &mut RequestSettings,
+    diagnostics: &mut Option<Arc<DiagnosticsStore>>,
+    deadline: Instant,
+) -> Result<bool, PollerError> {
+    while let Ok(recv_result) = timeout_at(deadline, rx.recv()).await {
+        match recv_result {
+            Some(message) => match message {
+                IncMessage::Close => return Ok(false),
+                IncMessage::Ping => {
+                    // Do nothing
+                }
+                IncMessage::UpdateUserAgent(user_agent) => {
+                    request_settings.user_agent = user_agent;
+                }
+                IncMessage::UpdateBrowserVersion(version) => {
+                    request_settings.browser_version = version;
+                }
+                IncMessage::UpdateBrowserNameAndVersion { name, version } => {
+                    request_settings.browser_name = name;
+                    request_settings.browser_version = version;
+                }
+                IncMessage::SetDiagnostics(new_diagnostics) => {
+                    *diagnostics = new_diagnostics;
+                }
+            },
+            None => {
+                return Err(PollerError::ChannelClosed);
+            }
+        }
+    }
+    Ok(true)
+}
+
+/// Performs a POST request with the retry policy. While waiting for the next attempt,
+/// messages from the `inbox` are handled, so that the poller can still be closed or updated.
+/// Returns `None`, if the poller was closed in the meantime.
+async fn post_with_retries(
+    video_id: &str,
+    http_client: &HttpClient,
+    request_settings: &mut RequestSettings,
+    url: &str,
+    referer_url: &str,
+    body: String,
+    mut inbox: Option<(
+        &mut Receiver<IncMessage>,
+        &mut Option<Arc<DiagnosticsStore>>,
+    )>,
+) -> Result<Option<String>, PollerError> {
+    let mut attempts = request_settings.retry_policy.attempts(video_id);
+    loop {
+        let error = match http_client
+            .post_request(url, &request_settings.user_agent, referer_url, body.clone())
+            .await
+        {
+            Ok(response) => return Ok(Some(response)),
+            Err(e) => e,
+        };
+
+        let deadline = Instant::now() + attempts.next_delay(error)?;
+        match &mut inbox {
+            Some((rx, diagnostics)) => {
+                if !handle_messages_until(rx, request_settings, diagnostics, deadline).await? {
+                    return Ok(None);
+                }
+            }
+            None => tokio::time::sleep_until(deadline).await,
+        }
+    }
+}
+
+/// Logs actions, that couldn't be parsed, and adds them to the total count of the poller
+fn log_unknown_actions(video_id: &str, actions: &[Action], total_count: &mut usize) {
+    for action in actions {
+        if let Action::Unknown { raw, reason, .. } = action {
+            *total_count += 1;
+            shared::tracing_warn!(
+                "{}: Unknown action #{}: {}: {}",
+                video_id,
+                total_count,
+                reason,
+                raw
+            );
+        }
+    }
+}
+
+/// Collects everything about the response, that couldn't be parsed, for further analysis
+fn failure_bundle<T: DeserializeOwned>(
+    source: &str,
+    video_id: &str,
+    url: &str,
+    chat_params: &ChatParams,
+    response: &str,
+    requested_at: SystemTime,
+    e: &ActionExtractorError,
+) -> FailureBundle {
+    FailureBundle {
+        source: source.to_string(),
+        id: video_id.to_string(),
+        url: url.to_string(),
+        request_body: serde_json::to_string(chat_params).ok(),
+        response: response.to_string(),
+        error: e.to_string(),
+        error_path: diagnostics::error_path::<T>(response),
+        client_version: Some(chat_params.client_version().to_string()),
+        requested_at: diagnostics::unix_time_ms(requested_at),
+        failed_at: diagnostics::unix_time_ms(SystemTime::now()),
+    }
+}
+
+async fn send_closing_message(
+    result: Result<(), PollerError>,
+    channel: &str,
+    video_id: &str,
+    kind: PollerKind,
+    result_tx: &Sender<OutMessage>,
+) {
+    let closing_message = match result {
+        Ok(_r) => {
+            // Chat poller finished its work because the stream has ended and the chat room has been closed
+            // or because the poller received `Close` message
+            OutMessage::StreamEnded {
+                channel: channel.to_string(),
+                video_id: video_id.to_string(),
+            }
+        }
+        Err(e) => {
+            shared::tracing_error!("{}: Error, while processing messages: {}", video_id, &e);
+            OutMessage::PollerFailed {
+                channel: channel.to_string(),
+                video_id: video_id.to_string(),
+                error: e.to_string(),
+                poller: tokio::task::id(),
+                kind,
+            }
+        }
+    };
+
+    shared::tracing_info!(
+        "{}: Sending closing message: {:?}...",
+        video_id,
+        &closing_message
+    );
+    match result_tx.send(closing_message).await {
+        Ok(_r) => {
+            // Nothing else to do
+        }
+        Err(e) => {
+            shared::tracing_error!("{}: Couldn't send closing message: {}", video_id, &e);
+        }
+    }
+
+    shared::tracing_info!("{}: Chat poller has been closed", video_id);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use shared::{
+        http_client::{HttpClient, RequestSettings, RetryPolicy},
+        messages::chat_poller::IncMessage,
+    };
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+        sync::mpsc,
+    };
+
+    use super::post_with_retries;
+
+    /// Responds with 503 to the first request of every connection,
+    /// and with the user agent of the request to the next one
+    async fn flaky_server() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            let mut failed = false;
+            loop {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                let mut request = vec![0; 4096];
+                let length = socket.read(&mut request).await.unwrap();
+                let request = String::from_utf8_lossy(&request[..length]).to_lowercase();
+                let user_agent = request
+                    .lines()
+                    .find_map(|line| line.strip_prefix("user-agent: "))
+                    .unwrap_or_default()
+                    .to_string();
+
+                let (status, body) = if failed {
+                    ("200 OK", user_agent)
+                } else {
+                    ("503 Service Unavailable", String::new())
+                };
+                failed = !failed;
+                let response = format!(
+                    "HTTP/1.1 {}\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{}",
+                    status,
+                    body.len(),
+                    body
+                );
+                socket.write_all(response.as_bytes()).await.unwrap();
+            }
+        });
+        url
+    }
+
+    fn request_settings(delay: Duration) -> RequestSettings {
+        RequestSettings {
+            user_agent: "old agent".to_string(),
+            browser_name: "Firefox".to_string(),
+            browser_version: "90.0".to_string(),
+            retry_policy: RetryPolicy {
+                initial_delay: delay,
+                max_delay: delay,
+                jitter: 0.0,
+                ..RetryPolicy::default()
+            },
+        }
+    }
+
+    #[tokio::test]
+    async fn handles_incoming_messages_while_waiting_for_a_retry() {
+        let url = flaky_server().await;
+        let http_client = HttpClient::init().unwrap();
+        let (tx, mut rx) = mpsc::channel(1);
+        let mut diagnostics = None;
+
+        // The new user agent is used by the next attempt
+        let mut settings = request_settings(Duration::from_millis(100));
+        tx.send(IncMessage::UpdateUserAgent("new agent".to_string()))
+            .await
+            .unwrap();
+        let response = post_with_retries(
+            "video",
+            &http_client,
+            &mut settings,
+            &url,
+            &url,
+            String::new(),
+            Some((&mut rx, &mut diagnostics)),
+        )
+        .await
+        .unwrap();
+        assert_eq!(response.as_deref(), Some("new agent"));
+
+        // The poller is closed without waiting for the end of the backoff
+        let mut settings = request_settings(Duration::from_secs(60));
+        tx.send(IncMessage::Close).await.unwrap();
+        let response = tokio::time::timeout(
+            Duration::from_secs(10),
+            post_with_retries(
+                "video",
+                &http_client,
+                &mut settings,
+                &url,
+                &url,
+                String::new(),
+                Some((&mut rx, &mut diagnostics)),
+            ),
+        )
+        .await
+        .unwrap()
+        .unwrap();
+        assert!(response.is_none());
+    }
+}
@@ -22,8 +22,11 @@ impl ParamsExtractor {
         http_client: &HttpClient,
         request_settings: &RequestSettings,
     ) -> Result<ExtractingResult, InitError> {
-        let chat_page_content = http_client
-            .get_request(chat_url, &request_settings.user_agent)
+        let chat_page_content = request_settings
+            .retry_policy
+            .retry(video_id, || {
+                http_client.get_request(chat_url, &request_settings.user_agent)
+            })
             .await?;
 
         if !YoutubeRegexes::is_chat_enabled(&chat_page_content) {
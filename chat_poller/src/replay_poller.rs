@@ -0,0 +1,369 @@
+use std::{
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use shared::{
+    diagnostics::{self, DiagnosticsStore},
+    http_client::{HttpClient, RequestSettings},
+    messages::chat_poller::{IncMessage, OutMessage, PollerKind},
+    types::{Action, Timestamp},
+    ActorWrapper,
+};
+use tokio::{
+    sync::mpsc::{self, Receiver, Sender},
+    time::Instant,
+};
+
+use crate::{
+    chat_params::ChatParams,
+    error::{ActionExtractorError, InitError, PollerError},
+    failure_bundle, handle_messages_until, log_unknown_actions,
+    params_extractor::{ExtractingResult, ParamsExtractor},
+    post_with_retries, send_closing_message,
+    type_converter::Converter,
+    youtube_types::root::ReplayChatJson,
+    InitResult,
+};
+
+/// Replay has no timeouts between the pages, so a small delay keeps the poller
+/// from hammering YouTube
+const PAGE_DELAY: Duration = Duration::from_millis(500);
+
+struct ReplayPage {
+    actions: Option<Vec<Action>>,
+    continuation: Option<String>,
+    last_offset_ms: Option<u64>,
+}
+
+/// Loads the chat replay of a finished stream or premiere page by page,
+/// and sends it further the same way the `ChatPoller` sends the live chat
+pub struct ReplayPoller {
+    channel: String,
+    video_id: String,
+    http_client: Arc<HttpClient>,
+    request_settings: RequestSettings,
+    referer_url: String,
+    endpoint_url: String,
+    next_poll_time: Instant,
+    chat_params: ChatParams,
+    unknown_actions_count: usize,
+    /// Offset of the last replay item, that was sent further
+    last_offset_ms: Option<u64>,
+    /// Items up to this offset have been sent by the failed poller, that this one replaces
+    resume_after_ms: Option<u64>,
+    /// Time of the latest action, that was sent further
+    latest_timestamp: Timestamp,
+    diagnostics: Option<Arc<DiagnosticsStore>>,
+    rx: Receiver<IncMessage>,
+    result_tx: Sender<OutMessage>,
+}
+
+impl ReplayPoller {
+    /// Starts from `last_offset_ms`, if it's set, skipping the items, that have already been sent
+    pub async fn init(
+        video_id: String,
+        channel: String,
+        http_client: Arc<HttpClient>,
+        request_settings: RequestSettings,
+        last_offset_ms: Option<u64>,
+        diagnostics: Option<Arc<DiagnosticsStore>>,
+        result_tx: Sender<OutMessage>,
+    ) -> Result<InitResult, InitError> {
+        // Watch page of a finished stream contains the same params as the live chat page,
+        // but its continuations lead to the replay
+        let watch_url = format!("https://www.youtube.com/watch?v={}", &video_id);
+        let extract_result = ParamsExtractor::extract_chat_params(
+            &video_id,
+            &watch_url,
+            &http_client,
+            &request_settings,
+        )
+        .await?;
+
+        let (mut chat_params, chat_key) = match extract_result {
+            ExtractingResult::ChatDisabled => {
+                return Ok(InitResult::ChatDisabled);
+            }
+            ExtractingResult::Extracted {
+                chat_params,
+                chat_key,
+            } => (chat_params, chat_key),
+        };
+        chat_params.update_player_offset(last_offset_ms.unwrap_or(0));
+
+        let (tx, rx) = mpsc::channel(32);
+        let endpoint_url = format!(
+            "https://www.youtube.com/youtubei/v1/live_chat/get_live_chat_replay?key={}",
+            &chat_key
+        );
+
+        let poller = Self {
+            channel: channel.clone(),
+            video_id: video_id.clone(),
+            http_client,
+            request_settings,
+            referer_url: watch_url,
+            endpoint_url,
+            next_poll_time: Instant::now(),
+            chat_params,
+            unknown_actions_count: 0,
+            last_offset_ms,
+            resume_after_ms: last_offset_ms,
+            latest_timestamp: Timestamp::default(),
+            diagnostics,
+            rx,
+            result_tx,
+        };
+
+        poller
+            .result_tx
+            .send(OutMessage::ChatInit { channel, video_id })
+            .await?;
+
+        let join_handle = tokio::spawn(async move {
+            poller.run().await;
+        });
+
+        let tx = shared::AlkSender::new(tx, "ReplayPoller_tx".to_string());
+        let wraper = ActorWrapper { join_handle, tx };
+        Ok(InitResult::Started(wraper))
+    }
+
+    async fn run(mut self) {
+        let result = self.do_run().await;
+        let kind = PollerKind::Replay {
+            last_offset_ms: self.last_offset_ms,
+        };
+        send_closing_message(result, &self.channel, &self.video_id, kind, &self.result_tx).await;
+    }
+
+    async fn do_run(&mut self) -> Result<(), PollerError> {
+        loop {
+            if !handle_messages_until(
+                &mut self.rx,
+                &mut self.request_settings,
+                &mut self.diagnostics,
+                self.next_poll_time,
+            )
+            .await?
+            {
+                return Ok(());
+            }
+
+            let requested_at = SystemTime::now();
+            let chat_json = match self.load_next_page().await? {
+                Some(chat_json) => chat_json,
+                // Closed while waiting for the next attempt
+                None => return Ok(()),
+            };
+            let page = match ReplayPoller::extract_page_from_json(
+                &chat_json,
+                self.resume_after_ms.take(),
+                self.latest_timestamp,
+            ) {
+                Ok(page) => page,
+                Err(e) => {
+                    let bundle = failure_bundle::<ReplayChatJson>(
+                        "replay_poller",
+                        &self.video_id,
+                        &self.endpoint_url,
+                        &self.chat_params,
+                        &chat_json,
+                        requested_at,
+                        &e,
+                    );
+                    diagnostics::save_failure(self.diagnostics.as_deref(), &bundle);
+                    return Err(e.into());
+                }
+            };
+
+            if let Some(actions) = page.actions {
+                log_unknown_actions(&self.video_id, &actions, &mut self.unknown_actions_count);
+                // Actions are sorted by time
+                if let Some(action) = actions.last() {
+                    self.latest_timestamp = self.latest_timestamp.max(action.timestamp());
+                }
+                let polling_results = OutMessage::NewBatch {
+                    channel: self.channel.clone(),
+                    video_id: self.video_id.clone(),
+                    actions,
+                };
+                self.result_tx.send(polling_results).await?;
+            }
+            if page.last_offset_ms.is_some() {
+                self.last_offset_ms = page.last_offset_ms;
+            }
+
+            let continuation = match page.continuation {
+                Some(continuation) => continuation,
+                None => {
+                    shared::tracing_info!("{}: Reached the end of the replay", &self.video_id);
+                    return Ok(());
+                }
+            };
+
+            self.chat_params.update_continuation(continuation);
+            // The next page starts from the last loaded message
+            if let Some(offset_ms) = self.last_offset_ms {
+                self.chat_params.update_player_offset(offset_ms);
+            }
+            self.next_poll_time = Instant::now() + PAGE_DELAY;
+        }
+    }
+
+    /// Returns `None`, if the poller was closed while waiting for the next attempt
+    async fn load_next_page(&mut self) -> Result<Option<String>, PollerError> {
+        let body = serde_json::to_string(&self.chat_params)?;
+
+        post_with_retries(
+            &self.video_id,
+            &self.http_client,
+            &mut self.request_settings,
+            &self.endpoint_url,
+            &self.referer_url,
+            body,
+            Some((&mut self.rx, &mut self.diagnostics)),
+        )
+        .await
+    }
+
+    /// Items up to `resume_after_ms` are skipped.
+    /// `latest_timestamp` is the time of the latest action of the previous pages.
+    fn extract_page_from_json(
+        json: &str,
+        resume_after_ms: Option<u64>,
+        latest_timestamp: Timestamp,
+    ) -> Result<ReplayPage, ActionExtractorError> {
+        let chat_json = serde_json::from_str::<ReplayChatJson>(json)?;
+        let last_offset_ms = chat_json
+            .items
+            .as_ref()
+            .and_then(|items| items.last())
+            .map(|item| item.video_offset_ms);
+
+        Ok(ReplayPage {
+            // Time of receiving has nothing to do with the time of the replay,
+            // so actions without their own time get the time of the latest message of the page,
+            // or of the previous pages, if the page has no messages, instead.
+            // It also keeps the replay the same no matter when it's loaded
+            actions: chat_json.items.map(|items| {
+                let new_items = items
+                    .into_iter()
+                    .filter(|item| {
+                        resume_after_ms.is_none_or(|offset| item.video_offset_ms > offset)
+                    })
+                    .collect();
+                Converter::convert_replay(new_items, latest_timestamp)
+            }),
+            continuation: chat_json.continuation,
+            last_offset_ms,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use shared::types::{Action, Timestamp};
+
+    use super::ReplayPoller;
+
+    #[test]
+    fn extract_replay_page() {
+        let json = r#"{
+            "continuationContents": {
+                "liveChatContinuation": {
+                    "continuations": [
+                        { "liveChatReplayContinuationData": { "continuation": "next" } },
+                        { "playerSeekContinuationData": { "continuation": "seek" } }
+                    ],
+                    "actions": [
+                        {
+                            "replayChatItemAction": {
+                                "actions": [
+                                    { "markChatItemAsDeletedAction": { "targetItemId": "first" } }
+                                ],
+                                "videoOffsetTimeMsec": "1500"
+                            }
+                        },
+                        {
+                            "replayChatItemAction": {
+                                "actions": [
+                                    { "addChatItemAction": { "item": { "liveChatTextMessageRenderer": {
+                                        "id": "second",
+                                        "timestampUsec": "2000",
+                                        "message": { "simpleText": "hello" },
+                                        "authorExternalChannelId": "UCauthor"
+                                    } } } }
+                                ],
+                                "videoOffsetTimeMsec": "2500"
+                            }
+                        }
+                    ]
+                }
+            }
+        }"#;
+        let page = ReplayPoller::extract_page_from_json(json, None, Timestamp::default()).unwrap();
+        assert_eq!(page.continuation.as_deref(), Some("next"));
+        assert_eq!(page.last_offset_ms, Some(2500));
+        assert_eq!(page.actions.unwrap().len(), 2);
+
+        // Restarted poller skips the items, that have already been sent
+        let page =
+            ReplayPoller::extract_page_from_json(json, Some(1500), Timestamp::default()).unwrap();
+        assert_eq!(page.last_offset_ms, Some(2500));
+        match page.actions.unwrap().as_slice() {
+            [Action::NewMessage { id, .. }] => {
+                assert_eq!(id.id, "second");
+                assert_eq!(id.video_offset_ms, Some(2500));
+            }
+            actions => panic!("Unexpected actions: {:?}", actions),
+        }
+
+        // Page without messages gets the time of the previous pages
+        let deletions_page = r#"{
+            "continuationContents": {
+                "liveChatContinuation": {
+                    "continuations": [
+                        { "liveChatReplayContinuationData": { "continuation": "next" } }
+                    ],
+                    "actions": [
+                        {
+                            "replayChatItemAction": {
+                                "actions": [
+                                    { "markChatItemAsDeletedAction": { "targetItemId": "second" } }
+                                ],
+                                "videoOffsetTimeMsec": "3000"
+                            }
+                        }
+                    ]
+                }
+            }
+        }"#;
+        let page = ReplayPoller::extract_page_from_json(
+            deletions_page,
+            None,
+            Timestamp::from_micros(2000),
+        )
+        .unwrap();
+        match page.actions.unwrap().as_slice() {
+            [action @ Action::DeleteMessage { .. }] => {
+                assert_eq!(action.timestamp(), Timestamp::from_micros(2000))
+            }
+            actions => panic!("Unexpected actions: {:?}", actions),
+        }
+
+        let last_page = r#"{
+            "continuationContents": {
+                "liveChatContinuation": {
+                    "continuations": [
+                        { "playerSeekContinuationData": { "continuation": "seek" } }
+                    ]
+                }
+            }
+        }"#;
+        let page =
+            ReplayPoller::extract_page_from_json(last_page, None, Timestamp::default()).unwrap();
+        assert!(page.continuation.is_none());
+    }
+}
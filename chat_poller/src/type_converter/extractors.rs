@@ -1,6 +1,9 @@
 use std::convert::{TryFrom, TryInto};
 
-use shared::types::{MembershipType, UserBadges};
+use shared::{
+    currency::{PurchaseAmount, PurchaseTier},
+    types::{MembershipType, RichText, RichTextSegment, Timestamp, UserBadges},
+};
 use vec1::Vec1;
 
 use crate::youtube_types::{
@@ -9,13 +12,15 @@ use crate::youtube_types::{
         MessageItem, PanelItem, PollToUpdateItem, PollType,
     },
     generic_types::{
-        AuthorBadge, AuthorBadgeIcon, AuthorBadgeIconType, AuthorInfo, LinkUrl, Message,
+        AuthorBadge, AuthorBadgeIcon, AuthorBadgeIconType, AuthorInfo, Emoji, LinkUrl, Message,
         MessageContent,
     },
 };
 
 use super::ConverterError;
 
+// Actions without their own time are converted with an empty `received_at`,
+// and are stamped later by `Converter::convert`, which knows when the batch was received
 type YouTubeAction = crate::youtube_types::actions::Action;
 type CoreAction = shared::types::Action;
 
@@ -30,40 +35,49 @@ impl From<LinkUrl> for String {
     }
 }
 
-impl From<Message> for shared::types::RichText {
+impl From<Emoji> for shared::types::Emoji {
+    fn from(value: Emoji) -> Self {
+        Self {
+            emoji_id: value.emoji_id,
+            shortcuts: value.shortcuts.map(Vec::from).unwrap_or_default(),
+            image_url: value
+                .image
+                .map(|image| image.thumbnails.first().url.clone()),
+            is_custom: value.is_custom_emoji,
+        }
+    }
+}
+
+impl From<Message> for RichText {
     fn from(value: Message) -> Self {
-        match value {
-            Message::SimpleText(text) => html_escape::encode_text(&text).into_owned(),
+        let segments = match value {
+            Message::SimpleText(text) => vec![RichTextSegment::Text {
+                text,
+                bold: false,
+                italics: false,
+            }],
             Message::Runs(runs) => runs
                 .into_iter()
                 .map(|content| match content {
-                    MessageContent::Link { text, url } => {
-                        let url: String = url.into();
-                        format!(
-                            r#"<a href="{}" target="_blank">{}</a>"#,
-                            url,
-                            html_escape::encode_text(&text)
-                        )
-                    }
+                    MessageContent::Link { text, url } => RichTextSegment::Link {
+                        text,
+                        url: url.into(),
+                    },
                     MessageContent::Text {
                         text,
                         bold,
                         italics,
-                    } => {
-                        let mut result = html_escape::encode_text(&text).into_owned();
-                        if bold {
-                            result = format!("<strong>{}</strong>", result);
-                        }
-                        if italics {
-                            result = format!("<em>{}</em>", result);
-                        }
-                        result
-                    }
-                    MessageContent::Emoji(emoji) => emoji.emoji_id,
+                    } => RichTextSegment::Text {
+                        text,
+                        bold,
+                        italics,
+                    },
+                    MessageContent::Emoji(emoji) => RichTextSegment::Emoji(emoji.into()),
                 })
-                .collect::<Vec<String>>()
-                .join(""),
-        }
+                .collect(),
+        };
+
+        RichText { segments }
     }
 }
 
@@ -107,6 +121,7 @@ impl From<PanelItem> for CoreAction {
                 .poll_header_renderer
                 .live_chat_poll_type
                 .into(),
+            received_at: Timestamp::default(),
         }
     }
 }
@@ -124,6 +139,7 @@ impl From<PollToUpdateItem> for CoreAction {
                     ratio: choise.vote_ratio,
                 })
                 .collect(),
+            received_at: Timestamp::default(),
         }
     }
 }
@@ -180,7 +196,8 @@ impl TryFrom<BannerItem> for CoreAction {
             } => CoreAction::ChannelNotice {
                 id: shared::types::IdEntry {
                     id,
-                    timepstamp: timestamp_usec,
+                    timestamp: Timestamp::from_micros(timestamp_usec),
+                    video_offset_ms: None,
                 },
                 author: author_info.try_into()?,
                 message: message.into(),
@@ -202,6 +219,7 @@ impl TryFrom<BannerItem> for CoreAction {
                     .poll_header_renderer
                     .live_chat_poll_type
                     .into(),
+                received_at: Timestamp::default(),
             },
             BannerItemContent::DonationsProgressBarRenderer {
                 raised,
@@ -211,6 +229,7 @@ impl TryFrom<BannerItem> for CoreAction {
                 title: campaign_title,
                 goal_label: goal_reached_label,
                 raised: raised.into(),
+                received_at: Timestamp::default(),
             },
         };
 
@@ -231,11 +250,14 @@ impl TryFrom<MessageItem> for Option<(shared::types::IdEntry, shared::types::Mes
             } => {
                 let id_entry = shared::types::IdEntry {
                     id,
-                    timepstamp: timestamp_usec,
+                    timestamp: Timestamp::from_micros(timestamp_usec),
+                    video_offset_ms: None,
                 };
+                let message: RichText = message.into();
                 let content = shared::types::MessageContent::SimpleMessage {
                     author: author_info.try_into()?,
-                    message: message.into(),
+                    plain_text: message.to_plain_text(),
+                    message,
                 };
                 Some((id_entry, content))
             }
@@ -249,7 +271,8 @@ impl TryFrom<MessageItem> for Option<(shared::types::IdEntry, shared::types::Mes
             } => {
                 let id_entry = shared::types::IdEntry {
                     id,
-                    timepstamp: timestamp_usec,
+                    timestamp: Timestamp::from_micros(timestamp_usec),
+                    video_offset_ms: None,
                 };
                 let author = author_info.try_into()?;
 
@@ -277,15 +300,21 @@ impl TryFrom<MessageItem> for Option<(shared::types::IdEntry, shared::types::Mes
                 message,
                 author_info,
                 purchase_amount_text,
+                header_background_color,
+                body_background_color,
             } => {
                 let id_entry = shared::types::IdEntry {
                     id,
-                    timepstamp: timestamp_usec,
+                    timestamp: Timestamp::from_micros(timestamp_usec),
+                    video_offset_ms: None,
                 };
+                let amount: RichText = purchase_amount_text.into();
+                let tier = purchase_tier(header_background_color, body_background_color);
                 let content = shared::types::MessageContent::Superchat {
                     author: author_info.try_into()?,
                     message: message.map(|m| m.into()),
-                    amount: purchase_amount_text.into(),
+                    parsed_amount: PurchaseAmount::parse(amount.to_plain_text().text(), tier),
+                    amount,
                 };
                 Some((id_entry, content))
             }
@@ -295,15 +324,24 @@ impl TryFrom<MessageItem> for Option<(shared::types::IdEntry, shared::types::Mes
                 author_info,
                 sticker,
                 purchase_amount_text,
+                money_chip_background_color,
+                background_color,
             } => {
                 let id_entry = shared::types::IdEntry {
                     id,
-                    timepstamp: timestamp_usec,
+                    timestamp: Timestamp::from_micros(timestamp_usec),
+                    video_offset_ms: None,
                 };
+                let purchase_amount: RichText = purchase_amount_text.into();
+                let tier = purchase_tier(money_chip_background_color, background_color);
                 let content = shared::types::MessageContent::Sticker {
                     author: author_info.try_into()?,
                     sticker_name: sticker.accessibility.accessibility_data.label,
-                    purchase_amount: purchase_amount_text.into(),
+                    parsed_amount: PurchaseAmount::parse(
+                        purchase_amount.to_plain_text().text(),
+                        tier,
+                    ),
+                    purchase_amount,
                 };
                 Some((id_entry, content))
             }
@@ -317,7 +355,10 @@ impl TryFrom<MessageItem> for Option<(shared::types::IdEntry, shared::types::Mes
                 EngagementMessageIconType::Poll => {
                     let id_entry = shared::types::IdEntry {
                         id,
-                        timepstamp: timestamp_usec.map(|wrapper| wrapper.0).unwrap_or_default(),
+                        timestamp: Timestamp::from_micros(
+                            timestamp_usec.map(|wrapper| wrapper.0).unwrap_or_default(),
+                        ),
+                        video_offset_ms: None,
                     };
 
                     let content = shared::types::MessageContent::PollResult {
@@ -339,7 +380,8 @@ impl TryFrom<MessageItem> for Option<(shared::types::IdEntry, shared::types::Mes
             } => {
                 let id_entry = shared::types::IdEntry {
                     id,
-                    timepstamp: timestamp_usec,
+                    timestamp: Timestamp::from_micros(timestamp_usec),
+                    video_offset_ms: None,
                 };
                 let content = shared::types::MessageContent::ChatMode {
                     text: text.into(),
@@ -364,7 +406,8 @@ impl TryFrom<MessageItem> for Option<(shared::types::IdEntry, shared::types::Mes
             } => {
                 let id_entry = shared::types::IdEntry {
                     id,
-                    timepstamp: timestamp_usec,
+                    timestamp: Timestamp::from_micros(timestamp_usec),
+                    video_offset_ms: None,
                 };
                 let author = author_info.map(|info| info.try_into()).transpose()?;
                 let content = shared::types::MessageContent::Fundraiser {
@@ -374,12 +417,104 @@ impl TryFrom<MessageItem> for Option<(shared::types::IdEntry, shared::types::Mes
                 };
                 Some((id_entry, content))
             }
+            MessageItem::LiveChatSponsorshipsGiftPurchaseAnnouncementRenderer {
+                id,
+                timestamp_usec,
+                author_external_channel_id,
+                header,
+            } => {
+                let id_entry = shared::types::IdEntry {
+                    id,
+                    timestamp: Timestamp::from_micros(timestamp_usec),
+                    video_offset_ms: None,
+                };
+                let header = header.live_chat_sponsorships_header_renderer;
+                let author_info = AuthorInfo {
+                    author_name: header.author_name,
+                    author_external_channel_id,
+                    author_badges: header.author_badges,
+                };
+                let (count, tier) = gift_count_and_tier(&header.primary_text.into())?;
+                let content = shared::types::MessageContent::GiftPurchase {
+                    gifter: author_info.try_into()?,
+                    count,
+                    tier,
+                };
+                Some((id_entry, content))
+            }
+            MessageItem::LiveChatSponsorshipsGiftRedemptionAnnouncementRenderer {
+                id,
+                timestamp_usec,
+                author_info,
+                message,
+            } => {
+                let id_entry = shared::types::IdEntry {
+                    id,
+                    timestamp: Timestamp::from_micros(timestamp_usec),
+                    video_offset_ms: None,
+                };
+                let message: RichText = message.into();
+                // Name of the gifter is the only bold part of the message
+                let gifter = message
+                    .segments
+                    .into_iter()
+                    .find_map(|segment| match segment {
+                        RichTextSegment::Text {
+                            text, bold: true, ..
+                        } => Some(text),
+                        _ => None,
+                    });
+                let content = shared::types::MessageContent::GiftRedemption {
+                    recipient: author_info.try_into()?,
+                    gifter,
+                };
+                Some((id_entry, content))
+            }
         };
 
         Ok(result)
     }
 }
 
+fn purchase_tier(first_color: Option<u32>, second_color: Option<u32>) -> Option<PurchaseTier> {
+    first_color
+        .and_then(PurchaseTier::from_color)
+        .or_else(|| second_color.and_then(PurchaseTier::from_color))
+}
+
+/// Parses texts like "Gifted 5 <Channel name> memberships": the first number is the count,
+/// and the words between it and the last word are the name of the membership
+fn gift_count_and_tier(text: &RichText) -> Result<(u32, Option<String>), ConverterError> {
+    let text: String = text
+        .segments
+        .iter()
+        .filter_map(|segment| match segment {
+            RichTextSegment::Text { text, .. } | RichTextSegment::Link { text, .. } => {
+                Some(text.as_str())
+            }
+            RichTextSegment::Emoji(_) => None,
+        })
+        .collect();
+    let words: Vec<&str> = text.split_whitespace().collect();
+
+    let count_position = words
+        .iter()
+        .position(|word| word.parse::<u32>().is_ok())
+        .ok_or(ConverterError::GiftCount)?;
+    let count = words[count_position]
+        .parse()
+        .map_err(|_| ConverterError::GiftCount)?;
+
+    let tier_words = words
+        .get(count_position + 1..words.len().saturating_sub(1))
+        .unwrap_or_default();
+    let tier = match tier_words {
+        [] => None,
+        words => Some(words.join(" ")),
+    };
+    Ok((count, tier))
+}
+
 impl TryFrom<YouTubeAction> for Option<CoreAction> {
     type Error = ConverterError;
 
@@ -397,12 +532,14 @@ impl TryFrom<YouTubeAction> for Option<CoreAction> {
             Action::MarkChatItemAsDeletedAction { target_item_id } => {
                 Some(CoreAction::DeleteMessage {
                     target_id: target_item_id,
+                    received_at: Timestamp::default(),
                 })
             }
             Action::MarkChatItemsByAuthorAsDeletedAction {
                 external_channel_id,
             } => Some(CoreAction::BlockUser {
                 channel_id: external_channel_id,
+                received_at: Timestamp::default(),
             }),
             Action::ReplaceChatItemAction {
                 target_item_id,
@@ -423,6 +560,7 @@ impl TryFrom<YouTubeAction> for Option<CoreAction> {
             Action::RemoveBannerForLiveChatCommand { target_action_id } => {
                 Some(CoreAction::CloseBanner {
                     banner_id: target_action_id,
+                    received_at: Timestamp::default(),
                 })
             }
             Action::ShowLiveChatActionPanelAction { panel_to_show } => Some(panel_to_show.into()),
@@ -430,6 +568,7 @@ impl TryFrom<YouTubeAction> for Option<CoreAction> {
             Action::CloseLiveChatActionPanelAction { target_panel_id } => {
                 Some(CoreAction::ClosePanel {
                     target_id: target_panel_id,
+                    received_at: Timestamp::default(),
                 })
             }
             Action::ShowLiveChatTooltipCommand {} => None,
@@ -1,6 +1,7 @@
 use std::convert::TryFrom;
 
-use crate::youtube_types::actions::Action;
+use crate::youtube_types::{actions::ActionJson, root::ReplayChatItem};
+use shared::types::Timestamp;
 use thiserror::Error;
 
 type CoreAction = shared::types::Action;
@@ -13,19 +14,75 @@ pub enum ConverterError {
     EmptyUserBadges,
     #[error("Couldn't determine the membership type")]
     MembershipType,
+    #[error("Couldn't find the number of gifted memberships")]
+    GiftCount,
 }
 
 pub struct Converter;
 
 impl Converter {
-    pub fn convert(actions: Vec<Action>) -> Result<Vec<CoreAction>, ConverterError> {
-        let mut result: Vec<CoreAction> = Vec::new();
-        for action in actions {
-            if let Some(core_action) = Option::<CoreAction>::try_from(action)? {
-                result.push(core_action)
+    /// Actions, that couldn't be deserialized or converted, become `Action::Unknown`,
+    /// so that the rest of the batch is still processed.
+    ///
+    /// Actions without their own time, like deletions and blocks, are stamped with `received_at`,
+    /// but never earlier than the latest message of the batch, so that they always come after
+    /// the messages they refer to. The result is sorted by time.
+    pub fn convert(actions: Vec<ActionJson>, received_at: Timestamp) -> Vec<CoreAction> {
+        let result = actions
+            .into_iter()
+            .filter_map(|action_json| Converter::convert_action(action_json, received_at))
+            .collect();
+        Converter::stamp_and_sort(result, received_at)
+    }
+
+    /// Same as `convert`, but messages keep the video offset of their replay item
+    pub fn convert_replay(items: Vec<ReplayChatItem>, received_at: Timestamp) -> Vec<CoreAction> {
+        let mut result = Vec::new();
+        for item in items {
+            for action_json in item.actions {
+                if let Some(mut core_action) = Converter::convert_action(action_json, received_at) {
+                    if let Some(id) = core_action.id_entry_mut() {
+                        id.video_offset_ms = Some(item.video_offset_ms);
+                    }
+                    result.push(core_action);
+                }
+            }
+        }
+        Converter::stamp_and_sort(result, received_at)
+    }
+
+    fn convert_action(action_json: ActionJson, received_at: Timestamp) -> Option<CoreAction> {
+        let converted = action_json
+            .action
+            .map_err(|e| e.to_string())
+            .and_then(|action| Option::<CoreAction>::try_from(action).map_err(|e| e.to_string()));
+
+        match converted {
+            Ok(core_action) => core_action,
+            Err(reason) => Some(CoreAction::Unknown {
+                raw: action_json.raw.to_string(),
+                reason,
+                received_at,
+            }),
+        }
+    }
+
+    fn stamp_and_sort(mut result: Vec<CoreAction>, received_at: Timestamp) -> Vec<CoreAction> {
+        let latest_message = result
+            .iter()
+            .filter_map(CoreAction::id_entry)
+            .map(|id| id.timestamp)
+            .max()
+            .unwrap_or_default();
+        let received_at = received_at.max(latest_message);
+        for action in &mut result {
+            if let Some(timestamp) = action.received_at_mut() {
+                *timestamp = received_at;
             }
         }
 
-        Ok(result)
+        // Sorting is stable, so actions with the same time keep the order, in which YouTube sent them
+        result.sort_by_key(CoreAction::timestamp);
+        result
     }
 }
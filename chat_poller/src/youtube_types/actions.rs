@@ -2,7 +2,31 @@ use std::{fmt::Display, str::FromStr};
 
 use serde::Deserialize;
 
-use super::generic_types::{AuthorInfo, Image, Message};
+use super::generic_types::{AuthorBadge, AuthorInfo, Image, Message};
+
+/// Single action from a chat response. The raw JSON is kept, so that an action
+/// of an unknown type doesn't break the whole batch and can still be reported as is.
+pub struct ActionJson {
+    pub action: Result<Action, serde_json::Error>,
+    pub raw: serde_json::Value,
+}
+
+impl<'de> Deserialize<'de> for ActionJson {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        #[derive(Deserialize)]
+        struct ActionWrapper {
+            #[serde(flatten)]
+            action: Action,
+        }
+
+        let raw = serde_json::Value::deserialize(deserializer)?;
+        let action = ActionWrapper::deserialize(&raw).map(|action_wrapper| action_wrapper.action);
+        Ok(ActionJson { action, raw })
+    }
+}
 
 #[derive(Deserialize)]
 #[serde(rename_all(deserialize = "camelCase"))]
@@ -70,6 +94,9 @@ pub enum MessageItem {
         #[serde(flatten)]
         author_info: AuthorInfo,
         purchase_amount_text: Message,
+        // Colors in ARGB format, that show the tier of the superchat
+        header_background_color: Option<u32>,
+        body_background_color: Option<u32>,
     },
     #[serde(rename_all(deserialize = "camelCase"))]
     LiveChatPaidStickerRenderer {
@@ -80,6 +107,8 @@ pub enum MessageItem {
         author_info: AuthorInfo,
         sticker: Image,
         purchase_amount_text: Message,
+        money_chip_background_color: Option<u32>,
+        background_color: Option<u32>,
     },
     #[serde(rename_all(deserialize = "camelCase"))]
     LiveChatViewerEngagementMessageRenderer {
@@ -120,6 +149,39 @@ pub enum MessageItem {
         #[serde(flatten)]
         author_info: Option<AuthorInfo>,
     },
+    #[serde(rename_all(deserialize = "camelCase"))]
+    LiveChatSponsorshipsGiftPurchaseAnnouncementRenderer {
+        id: String,
+        #[serde(deserialize_with = "from_str")]
+        timestamp_usec: u64,
+        author_external_channel_id: String,
+        // Name and badges of the gifter are in the header,
+        // along with a text like "Gifted 5 <Channel name> memberships"
+        header: SponsorshipsHeader,
+    },
+    #[serde(rename_all(deserialize = "camelCase"))]
+    LiveChatSponsorshipsGiftRedemptionAnnouncementRenderer {
+        id: String,
+        #[serde(deserialize_with = "from_str")]
+        timestamp_usec: u64,
+        #[serde(flatten)]
+        author_info: AuthorInfo,
+        message: Message, // "received a gift membership by <gifter name>"
+    },
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all(deserialize = "camelCase"))]
+pub struct SponsorshipsHeader {
+    pub live_chat_sponsorships_header_renderer: SponsorshipsHeaderRenderer,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all(deserialize = "camelCase"))]
+pub struct SponsorshipsHeaderRenderer {
+    pub author_name: Option<Message>,
+    pub author_badges: Option<Vec<AuthorBadge>>,
+    pub primary_text: Message,
 }
 
 #[derive(Deserialize)]
@@ -8,6 +8,20 @@ pub struct Emoji {
     pub shortcuts: Option<Vec1<String>>,
     #[serde(default)]
     pub is_custom_emoji: bool,
+    pub image: Option<EmojiImage>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all(deserialize = "camelCase"))]
+pub struct EmojiImage {
+    // Unlike other thumbnails, thumbnails of standard emojis don't have width and height
+    pub thumbnails: Vec1<EmojiThumbnail>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all(deserialize = "camelCase"))]
+pub struct EmojiThumbnail {
+    pub url: String,
 }
 
 #[derive(Deserialize)]
@@ -4,12 +4,17 @@ pub mod root;
 
 #[cfg(test)]
 mod tests {
-    use crate::youtube_types::root::ChatJson;
+    use shared::types::{Action, MessageContent, Timestamp};
+
+    use crate::youtube_types::root::{ChatJson, Continuation};
 
     #[test]
     fn deserialize_chat_json() {
         let json = include_str!("../../unimplemented_types.json");
-        let _chat_json = serde_json::from_str::<ChatJson>(json).unwrap();
+        let chat_json = serde_json::from_str::<ChatJson>(json).unwrap();
+        for action_json in chat_json.actions.into_iter().flatten() {
+            action_json.action.unwrap();
+        }
     }
 
     #[test]
@@ -24,11 +29,187 @@ mod tests {
                 let content = std::fs::read_to_string(&path).unwrap();
                 let result = serde_json::from_str::<ChatJson>(&content);
                 match result {
-                    Ok(_) => {}
+                    Ok(chat_json) => {
+                        // Unknown actions don't fail the response, so they are checked separately
+                        for action_json in chat_json.actions.into_iter().flatten() {
+                            if let Err(e) = action_json.action {
+                                panic!("{:?}: {}", &path, &e);
+                            }
+                        }
+                    }
                     Err(e) => {
                         panic!("{:?}: {}", &path, &e);
                     }
                 }
             });
     }
+
+    #[test]
+    fn deserialize_reload_continuation() {
+        let json = r#"{
+            "continuationContents": {
+                "liveChatContinuation": {
+                    "continuations": [
+                        { "reloadContinuationData": { "continuation": "reload" } }
+                    ]
+                }
+            }
+        }"#;
+        let chat_json = serde_json::from_str::<ChatJson>(json).unwrap();
+        assert!(matches!(
+            chat_json.continuation,
+            Some(Continuation::Reload { continuation }) if continuation == "reload"
+        ));
+        assert!(chat_json.actions.is_none());
+    }
+
+    #[test]
+    fn unknown_actions_dont_break_the_batch() {
+        let json = r#"{
+            "continuationContents": {
+                "liveChatContinuation": {
+                    "continuations": [
+                        { "timedContinuationData": { "timeoutMs": 1000, "continuation": "next" } }
+                    ],
+                    "actions": [
+                        { "addChatItemAction": { "item": { "liveChatBrandNewRenderer": { "id": "new" } } } },
+                        { "markChatItemAsDeletedAction": { "targetItemId": "deleted" } }
+                    ]
+                }
+            }
+        }"#;
+        let actions = crate::parse_chat_json(json, Timestamp::from_micros(1000)).unwrap();
+        assert_eq!(actions.len(), 2);
+        assert!(matches!(
+            &actions[0],
+            Action::Unknown { raw, .. } if raw.contains("liveChatBrandNewRenderer")
+        ));
+        assert!(matches!(
+            &actions[1],
+            Action::DeleteMessage { target_id, .. } if target_id == "deleted"
+        ));
+        // Without any messages, the actions get the time of receiving
+        assert_eq!(actions[1].timestamp(), Timestamp::from_micros(1000));
+    }
+
+    #[test]
+    fn convert_gift_memberships() {
+        let json = r#"{
+            "continuationContents": {
+                "liveChatContinuation": {
+                    "continuations": [
+                        { "timedContinuationData": { "timeoutMs": 1000, "continuation": "next" } }
+                    ],
+                    "actions": [
+                        {
+                            "addChatItemAction": {
+                                "item": {
+                                    "liveChatSponsorshipsGiftPurchaseAnnouncementRenderer": {
+                                        "id": "purchase",
+                                        "timestampUsec": "1620000000000000",
+                                        "authorExternalChannelId": "UCgifter",
+                                        "header": {
+                                            "liveChatSponsorshipsHeaderRenderer": {
+                                                "authorName": { "simpleText": "Gifter" },
+                                                "primaryText": {
+                                                    "runs": [
+                                                        { "text": "Gifted ", "bold": true },
+                                                        { "text": "5", "bold": true },
+                                                        { "text": " ", "bold": true },
+                                                        { "text": "Channel Name", "bold": true },
+                                                        { "text": " memberships", "bold": true }
+                                                    ]
+                                                }
+                                            }
+                                        }
+                                    }
+                                }
+                            }
+                        },
+                        {
+                            "addChatItemAction": {
+                                "item": {
+                                    "liveChatSponsorshipsGiftRedemptionAnnouncementRenderer": {
+                                        "id": "redemption",
+                                        "timestampUsec": "1620000001000000",
+                                        "authorExternalChannelId": "UCrecipient",
+                                        "authorName": { "simpleText": "Recipient" },
+                                        "message": {
+                                            "runs": [
+                                                { "text": "received a gift membership by ", "italics": true },
+                                                { "text": "Gifter", "bold": true, "italics": true }
+                                            ]
+                                        }
+                                    }
+                                }
+                            }
+                        }
+                    ]
+                }
+            }
+        }"#;
+        let actions = crate::parse_chat_json(json, Timestamp::now()).unwrap();
+        assert_eq!(actions.len(), 2);
+        assert!(matches!(
+            &actions[0],
+            Action::NewMessage {
+                message: MessageContent::GiftPurchase { gifter, count: 5, tier },
+                ..
+            } if gifter.channel_id == "UCgifter" && tier.as_deref() == Some("Channel Name")
+        ));
+        assert!(matches!(
+            &actions[1],
+            Action::NewMessage {
+                message: MessageContent::GiftRedemption { recipient, gifter },
+                ..
+            } if recipient.channel_id == "UCrecipient" && gifter.as_deref() == Some("Gifter")
+        ));
+    }
+
+    #[test]
+    fn batches_are_sorted_by_time() {
+        let text_message = |id: &str, timestamp: u64| {
+            format!(
+                r#"{{ "addChatItemAction": {{ "item": {{ "liveChatTextMessageRenderer": {{
+                    "id": "{}",
+                    "timestampUsec": "{}",
+                    "message": {{ "simpleText": "hello" }},
+                    "authorExternalChannelId": "UCauthor"
+                }} }} }} }}"#,
+                id, timestamp
+            )
+        };
+        let json = format!(
+            r#"{{
+                "continuationContents": {{
+                    "liveChatContinuation": {{
+                        "continuations": [
+                            {{ "timedContinuationData": {{ "timeoutMs": 1000, "continuation": "next" }} }}
+                        ],
+                        "actions": [
+                            {},
+                            {{ "markChatItemAsDeletedAction": {{ "targetItemId": "late" }} }},
+                            {}
+                        ]
+                    }}
+                }}
+            }}"#,
+            text_message("late", 2000),
+            text_message("early", 1000)
+        );
+
+        let actions = crate::parse_chat_json(&json, Timestamp::from_micros(1500)).unwrap();
+        let ids = actions
+            .iter()
+            .map(|action| match action {
+                Action::NewMessage { id, .. } => id.id.as_str(),
+                Action::DeleteMessage { target_id, .. } => target_id.as_str(),
+                _ => "",
+            })
+            .collect::<Vec<_>>();
+        // Deletion gets the time of the latest message, and stays after the message it deletes
+        assert_eq!(ids, ["early", "late", "late"]);
+        assert!(matches!(&actions[2], Action::DeleteMessage { .. }));
+        assert_eq!(actions[2].timestamp(), Timestamp::from_micros(2000));
+    }
 }
@@ -1,17 +1,51 @@
-use super::actions::Action;
+use std::time::Duration;
+
+use super::actions::ActionJson;
 
 pub struct ChatJson {
     pub continuation: Option<Continuation>,
-    pub actions: Option<Vec<Action>>,
+    pub actions: Option<Vec<ActionJson>>,
+}
+
+pub enum Continuation {
+    /// Regular continuation, that should be used after `timeout_ms`
+    Timed {
+        timeout_ms: u16,
+        continuation: String,
+    },
+    /// YouTube asks to reload the chat, starting from this continuation
+    Reload { continuation: String },
+}
+
+impl Continuation {
+    /// Splits the continuation into its value and the delay before using it
+    pub fn into_parts(self, reload_delay: Duration) -> (String, Duration) {
+        match self {
+            Continuation::Timed {
+                timeout_ms,
+                continuation,
+            } => (continuation, Duration::from_millis(timeout_ms as u64)),
+            Continuation::Reload { continuation } => (continuation, reload_delay),
+        }
+    }
 }
 
-pub struct Continuation {
-    pub timeout_ms: u16,
-    pub continuation: String,
+/// Response of the chat replay endpoint
+pub struct ReplayChatJson {
+    /// `None` after the last page of the replay
+    pub continuation: Option<String>,
+    pub items: Option<Vec<ReplayChatItem>>,
+}
+
+/// Actions of the replay, that are shown at the same moment of the video
+pub struct ReplayChatItem {
+    pub video_offset_ms: u64,
+    pub actions: Vec<ActionJson>,
 }
 
 mod custom_deser_impls {
     use super::*;
+    use crate::youtube_types::actions::WrappedU64;
     use serde::Deserialize;
 
     impl<'de> Deserialize<'de> for ChatJson {
@@ -34,7 +68,7 @@ mod custom_deser_impls {
             #[derive(Deserialize)]
             struct Inner {
                 continuations: Vec<InnerContinuation>,
-                actions: Option<Vec<ActionWrapper>>,
+                actions: Option<Vec<ActionJson>>,
             }
 
             #[derive(Deserialize)]
@@ -54,13 +88,6 @@ mod custom_deser_impls {
                 ReloadContinuationData { continuation: String },
             }
 
-            #[derive(Deserialize)]
-            #[serde(rename_all(deserialize = "camelCase"))]
-            struct ActionWrapper {
-                #[serde(flatten)]
-                action: Action,
-            }
-
             let outer = Outer::deserialize(deserializer)?;
             let chat_json = match outer.continuation_contents {
                 Some(middle) => {
@@ -80,34 +107,23 @@ mod custom_deser_impls {
                         InnerContinuation::TimedContinuationData {
                             timeout_ms,
                             continuation,
-                        } => Continuation {
+                        } => Continuation::Timed {
                             timeout_ms,
                             continuation,
                         },
                         InnerContinuation::InvalidationContinuationData {
                             timeout_ms,
                             continuation,
-                        } => Continuation {
+                        } => Continuation::Timed {
                             timeout_ms,
                             continuation,
                         },
                         InnerContinuation::ReloadContinuationData { continuation } => {
-                            Continuation {
-                                timeout_ms: 0,
-                                continuation,
-                            }
+                            Continuation::Reload { continuation }
                         }
                     };
 
-                    let actions = middle
-                        .live_chat_continuation
-                        .actions
-                        .map(|action_wrappers| {
-                            action_wrappers
-                                .into_iter()
-                                .map(|action_wrapper| action_wrapper.action)
-                                .collect()
-                        });
+                    let actions = middle.live_chat_continuation.actions;
 
                     ChatJson {
                         continuation: Some(continuation),
@@ -123,4 +139,94 @@ mod custom_deser_impls {
             Ok(chat_json)
         }
     }
+
+    impl<'de> Deserialize<'de> for ReplayChatJson {
+        fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+        where
+            D: serde::Deserializer<'de>,
+        {
+            #[derive(Deserialize)]
+            #[serde(rename_all(deserialize = "camelCase"))]
+            struct Outer {
+                continuation_contents: Option<Middle>,
+            }
+
+            #[derive(Deserialize)]
+            #[serde(rename_all(deserialize = "camelCase"))]
+            struct Middle {
+                live_chat_continuation: Inner,
+            }
+
+            #[derive(Deserialize)]
+            struct Inner {
+                #[serde(default)]
+                continuations: Vec<InnerContinuation>,
+                actions: Option<Vec<ReplayActionWrapper>>,
+            }
+
+            #[derive(Deserialize)]
+            #[serde(rename_all(deserialize = "camelCase"))]
+            enum InnerContinuation {
+                #[serde(rename_all(deserialize = "camelCase"))]
+                LiveChatReplayContinuationData { continuation: String },
+                // Used by the player to jump to another part of the replay.
+                // It's the only continuation left on the last page.
+                #[serde(rename_all(deserialize = "camelCase"))]
+                PlayerSeekContinuationData {},
+            }
+
+            #[derive(Deserialize)]
+            #[serde(rename_all(deserialize = "camelCase"))]
+            struct ReplayActionWrapper {
+                replay_chat_item_action: ReplayChatItemAction,
+            }
+
+            #[derive(Deserialize)]
+            #[serde(rename_all(deserialize = "camelCase"))]
+            struct ReplayChatItemAction {
+                actions: Vec<ActionJson>,
+                video_offset_time_msec: WrappedU64,
+            }
+
+            let outer = Outer::deserialize(deserializer)?;
+            let middle = match outer.continuation_contents {
+                Some(middle) => middle,
+                None => {
+                    return Ok(ReplayChatJson {
+                        continuation: None,
+                        items: None,
+                    })
+                }
+            };
+
+            let continuation = middle
+                .live_chat_continuation
+                .continuations
+                .into_iter()
+                .find_map(|continuation| match continuation {
+                    InnerContinuation::LiveChatReplayContinuationData { continuation } => {
+                        Some(continuation)
+                    }
+                    InnerContinuation::PlayerSeekContinuationData {} => None,
+                });
+
+            let items = middle.live_chat_continuation.actions.map(|replay_actions| {
+                replay_actions
+                    .into_iter()
+                    .map(|replay_action| ReplayChatItem {
+                        video_offset_ms: replay_action
+                            .replay_chat_item_action
+                            .video_offset_time_msec
+                            .0,
+                        actions: replay_action.replay_chat_item_action.actions,
+                    })
+                    .collect()
+            });
+
+            Ok(ReplayChatJson {
+                continuation,
+                items,
+            })
+        }
+    }
 }
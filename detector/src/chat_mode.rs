@@ -0,0 +1,120 @@
+use std::collections::BTreeSet;
+
+use shared::types::{ChatMode, RichText, RichTextSegment};
+
+/// Chat modes that are currently enabled in a stream.
+///
+/// YouTube uses the same message type both for enabling and disabling a mode,
+/// and when a mode is disabled the icon is usually `QUESTION_ANSWER` regardless of the mode,
+/// so the text of the message is used to figure out what has actually changed.
+/// Chat params are always requested with `hl=en`, so the text is expected to be in English:
+/// either "Slow mode is on" or "<author> turned on subscribers-only mode".
+#[derive(Default)]
+pub struct ChatModeState {
+    active_modes: BTreeSet<ChatMode>,
+}
+
+impl ChatModeState {
+    pub fn active_modes(&self) -> impl Iterator<Item = &ChatMode> {
+        self.active_modes.iter()
+    }
+
+    /// Updates the state and returns `true` if the set of active modes has changed
+    pub fn update(&mut self, mode: &ChatMode, text: &RichText) -> bool {
+        // Name of the author, who changed the mode, is a separate segment,
+        // so only the last segment is checked, as the name may contain anything
+        let text = match text.segments.last() {
+            Some(RichTextSegment::Text { text, .. }) => text.trim().to_lowercase(),
+            _ => {
+                shared::tracing_warn!("Unexpected chat mode notice: {:?}", text);
+                return false;
+            }
+        };
+
+        let enabled = if text.ends_with(" is on") || text.starts_with("turned on ") {
+            true
+        } else if text.ends_with(" is off") || text.starts_with("turned off ") {
+            false
+        } else {
+            shared::tracing_warn!("Couldn't determine if the chat mode is on: {}", &text);
+            return false;
+        };
+
+        let mode = if text.contains("slow mode") {
+            ChatMode::SlowMode
+        } else if text.contains("subscribers-only") {
+            ChatMode::SubscribersOnly
+        } else if text.contains("members-only") {
+            ChatMode::MembersOnly
+        } else {
+            mode.clone()
+        };
+
+        if mode == ChatMode::QuestionAnswer {
+            shared::tracing_warn!("Couldn't determine which chat mode has changed: {}", &text);
+            return false;
+        }
+
+        if enabled {
+            self.active_modes.insert(mode)
+        } else {
+            self.active_modes.remove(&mode)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use shared::types::{ChatMode, RichText, RichTextSegment};
+
+    use super::ChatModeState;
+
+    fn notice(runs: &[&str]) -> RichText {
+        RichText {
+            segments: runs
+                .iter()
+                .map(|run| RichTextSegment::Text {
+                    text: run.to_string(),
+                    bold: true,
+                    italics: false,
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn follows_youtube_mode_notices() {
+        let mut state = ChatModeState::default();
+
+        assert!(state.update(&ChatMode::SlowMode, &notice(&["Slow mode is on"])));
+        assert!(state.update(
+            &ChatMode::SubscribersOnly,
+            &notice(&["Coffee Offers", " turned on subscribers-only mode"])
+        ));
+        assert!(state.update(
+            &ChatMode::MembersOnly,
+            &notice(&["Members-only mode is on"])
+        ));
+        assert!(!state.update(&ChatMode::SlowMode, &notice(&["Slow mode is on"])));
+        assert_eq!(
+            state.active_modes().cloned().collect::<Vec<_>>(),
+            [
+                ChatMode::SubscribersOnly,
+                ChatMode::SlowMode,
+                ChatMode::MembersOnly
+            ]
+        );
+
+        // Disabled modes come with the `QUESTION_ANSWER` icon
+        assert!(state.update(&ChatMode::QuestionAnswer, &notice(&["Slow mode is off"])));
+        assert!(state.update(
+            &ChatMode::QuestionAnswer,
+            &notice(&["Slow Mode Fan", " turned off subscribers-only mode"])
+        ));
+        assert!(!state.update(&ChatMode::QuestionAnswer, &notice(&["Chat away!"])));
+        assert_eq!(
+            state.active_modes().cloned().collect::<Vec<_>>(),
+            [ChatMode::MembersOnly]
+        );
+    }
+}
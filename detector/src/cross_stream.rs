@@ -0,0 +1,176 @@
+use std::collections::{BTreeSet, HashMap, VecDeque};
+
+use shared::{
+    detector_params::CrossStreamParams,
+    messages::detector::{Decision, DetectorDecision},
+    types::{Action, MessageContent},
+};
+
+struct RecentMessage {
+    video_id: String,
+    timestamp: u64,
+    text: String,
+}
+
+#[derive(Default)]
+struct UserMessages {
+    messages: VecDeque<RecentMessage>,
+    // Amount of streams that were reported in the last decision for this user,
+    // so that the same decision isn't repeated for every new similar message
+    reported_streams: usize,
+}
+
+/// Time-windowed index of recent messages from all active streams of all channels,
+/// that looks for users posting similar messages in several chats.
+#[derive(Default)]
+pub struct CrossStreamIndex {
+    users: HashMap<String, UserMessages>,
+    last_cleanup: u64,
+}
+
+impl CrossStreamIndex {
+    pub fn process_new_messages(
+        &mut self,
+        video_id: &str,
+        actions: &[Action],
+        params: &CrossStreamParams,
+    ) -> Vec<DetectorDecision> {
+        let mut decisions = Vec::new();
+
+        for action in actions {
+            let (id, author, plain_text) = match action {
+                Action::NewMessage { id, message }
+                | Action::ReplaceMessage {
+                    new_id: id,
+                    message,
+                    ..
+                } => match message {
+                    MessageContent::SimpleMessage {
+                        author, plain_text, ..
+                    } => (id, author, plain_text),
+                    _ => continue,
+                },
+                _ => continue,
+            };
+
+            if author.badges.is_some() || plain_text.graphemes() < params.min_message_length {
+                continue;
+            }
+
+            let message = RecentMessage {
+                video_id: video_id.to_string(),
+                timestamp: id.timestamp.as_micros(),
+                text: plain_text.text().to_string(),
+            };
+
+            if let Some(streams) = self.add_message(&author.channel_id, message, params) {
+                let decision = Decision::CrossStreamSpam { streams };
+                decisions.push(DetectorDecision::new(
+                    author.channel_id.clone(),
+                    id.timestamp,
+                    decision,
+                ));
+            }
+        }
+
+        decisions
+    }
+
+    /// Forgets all messages from the stream, that has ended
+    pub fn remove_stream(&mut self, video_id: &str) {
+        for user in self.users.values_mut() {
+            user.messages.retain(|message| message.video_id != video_id);
+        }
+        self.users.retain(|_, user| !user.messages.is_empty());
+    }
+
+    fn add_message(
+        &mut self,
+        channel_id: &str,
+        message: RecentMessage,
+        params: &CrossStreamParams,
+    ) -> Option<Vec<String>> {
+        let window_start = message.timestamp.saturating_sub(params.window_usec);
+        if window_start > self.last_cleanup + params.window_usec {
+            self.remove_expired(window_start);
+        }
+
+        let user = self.users.entry(channel_id.to_string()).or_default();
+        while let Some(oldest) = user.messages.front() {
+            if oldest.timestamp >= window_start {
+                break;
+            }
+            user.messages.pop_front();
+        }
+
+        let mut streams = user
+            .messages
+            .iter()
+            .filter(|recent| recent.video_id != message.video_id)
+            .filter(|recent| {
+                let similarity = strsim::jaro(&recent.text, &message.text) as f32;
+                similarity > params.similarity_threshold
+            })
+            .map(|recent| recent.video_id.clone())
+            .collect::<BTreeSet<_>>();
+        streams.insert(message.video_id.clone());
+        user.messages.push_back(message);
+
+        if streams.len() >= params.chats_threshold && streams.len() > user.reported_streams {
+            user.reported_streams = streams.len();
+            Some(streams.into_iter().collect())
+        } else {
+            None
+        }
+    }
+
+    fn remove_expired(&mut self, window_start: u64) {
+        for user in self.users.values_mut() {
+            user.messages
+                .retain(|message| message.timestamp >= window_start);
+        }
+        self.users.retain(|_, user| !user.messages.is_empty());
+        self.last_cleanup = window_start;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use shared::{detector_params::CrossStreamParams, messages::detector::Decision};
+
+    use super::CrossStreamIndex;
+    use crate::test_utils::message;
+
+    #[test]
+    fn flags_similar_messages_in_several_chats() {
+        let params = CrossStreamParams::default();
+        let mut index = CrossStreamIndex::default();
+        let spam = "Check out my channel for free gifts!";
+
+        let first = index.process_new_messages("stream_1", &[message("bot", 1, spam)], &params);
+        let second = index.process_new_messages("stream_2", &[message("bot", 2, spam)], &params);
+        let third = index.process_new_messages(
+            "stream_3",
+            &[message("bot", 3, spam), message("user", 3, spam)],
+            &params,
+        );
+
+        assert!(first.is_empty());
+        assert!(second.is_empty());
+        assert_eq!(third.len(), 1);
+        assert_eq!(third[0].channel, "bot");
+        match &third[0].decision {
+            Decision::CrossStreamSpam { streams } => {
+                assert_eq!(streams, &["stream_1", "stream_2", "stream_3"])
+            }
+            decision => panic!("Unexpected decision: {:?}", decision),
+        }
+
+        let expired = index.process_new_messages(
+            "stream_4",
+            &[message("bot", 3 + params.window_usec + 1, spam)],
+            &params,
+        );
+        assert!(expired.is_empty());
+    }
+}
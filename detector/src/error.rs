@@ -15,3 +15,41 @@ impl From<SendError<OutMessage>> for DetectorError {
         DetectorError::OutgoingChannelClosed(e)
     }
 }
+
+#[derive(Error, Debug)]
+pub enum ReputationStoreError {
+    #[error("Couldn't access the reputation file: {0}")]
+    Io(#[source] std::io::Error),
+    #[error("Couldn't (de)serialize the reputation: {0}")]
+    Json(#[source] serde_json::Error),
+}
+
+impl From<std::io::Error> for ReputationStoreError {
+    fn from(e: std::io::Error) -> Self {
+        ReputationStoreError::Io(e)
+    }
+}
+
+impl From<serde_json::Error> for ReputationStoreError {
+    fn from(e: serde_json::Error) -> Self {
+        ReputationStoreError::Json(e)
+    }
+}
+
+#[derive(Error, Debug)]
+#[cfg_attr(not(feature = "scripting"), allow(dead_code))]
+pub enum ScriptError {
+    #[cfg(not(feature = "scripting"))]
+    #[error("Detector was built without the `scripting` feature")]
+    Disabled,
+    #[error("Couldn't compile the script: {0}")]
+    Compile(String),
+    #[error("Script failed: {0}")]
+    Runtime(String),
+    #[error("Script returned `{0}` instead of a string or nothing")]
+    WrongReturnType(String),
+    #[error("Script ran longer than {0:?}")]
+    TimeLimit(std::time::Duration),
+    #[error("Script performed more than {0} operations")]
+    TooManyOperations(u64),
+}
@@ -1,27 +1,113 @@
 #![allow(proc_macro_derive_resolution_fallback, unused_attributes)]
 
-use std::collections::HashMap;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex, PoisonError, RwLock},
+    time::Instant,
+};
 
+use cross_stream::CrossStreamIndex;
 use error::DetectorError;
+use reputation::ReputationStore;
+use script::Script;
 use shared::{
-    detector_params::DetectorParams,
+    detector_params::{CrossStreamParams, DetectorParams, ScriptParams},
     messages::detector::{IncMessage, OutMessage},
     ActorWrapper,
 };
-use spam_detector::SpamDetector;
+use stream_worker::{SharedHistory, SharedReputation, StreamWorker, WorkerMessage};
 use tokio::sync::mpsc::{self, Receiver, Sender};
+use user_history::UserHistory;
+use watchlist::Watchlist;
 
+mod chat_mode;
+mod cross_stream;
 mod error;
+mod message_log;
+mod reputation;
+mod script;
+mod shadow;
 mod spam_detector;
+mod stream_worker;
+mod sync_detector;
+#[cfg(test)]
+mod test_utils;
 mod user_data;
+mod user_history;
+mod watchlist;
+
+pub use sync_detector::Detector;
 
 struct ChannelData {
-    streams: HashMap<String, SpamDetector>,
+    streams: HashMap<String, StreamWorker>,
     params: DetectorParams,
+    // History of users, that is shared by all streams of the channel.
+    // Exists only if it's enabled in the `params`.
+    shared_history: Option<SharedHistory>,
+    // Candidate params, that run in shadow mode next to the `params`
+    shadow_params: Option<DetectorParams>,
+}
+
+impl ChannelData {
+    fn new(params: DetectorParams) -> Self {
+        let shared_history = if params.shares_history_between_streams() {
+            Some(Arc::new(Mutex::new(UserHistory::shared())))
+        } else {
+            None
+        };
+
+        Self {
+            streams: HashMap::new(),
+            params,
+            shared_history,
+            shadow_params: None,
+        }
+    }
+
+    /// Updates params and lets every stream reanalyze its users with them
+    async fn update_params(&mut self, params: DetectorParams) {
+        match (
+            params.shares_history_between_streams(),
+            &self.shared_history,
+        ) {
+            (true, None) => self.shared_history = Some(Arc::new(Mutex::new(UserHistory::shared()))),
+            (false, Some(_)) => self.shared_history = None,
+            _ => {}
+        }
+        self.params = params;
+
+        let dispatched_at = Instant::now();
+        for worker in self.streams.values() {
+            worker
+                .send(WorkerMessage::UpdateParams {
+                    params: self.params.clone(),
+                    shared_history: self.shared_history.clone(),
+                    dispatched_at,
+                })
+                .await;
+        }
+    }
+
+    async fn update_shadow_params(&mut self, params: Option<DetectorParams>) {
+        self.shadow_params = params;
+        for worker in self.streams.values() {
+            worker
+                .send(WorkerMessage::UpdateShadowParams(
+                    self.shadow_params.clone(),
+                ))
+                .await;
+        }
+    }
 }
 
 pub struct DetectorManager {
     active_channels: HashMap<String, ChannelData>,
+    cross_stream: CrossStreamIndex,
+    cross_stream_params: CrossStreamParams,
+    reputation: SharedReputation,
+    watchlist: Watchlist,
+    // Scripts outlive the streams, so they're kept even for channels without active streams
+    scripts: HashMap<String, Arc<Script>>,
     rx: Receiver<IncMessage>,
     result_tx: Sender<OutMessage>,
 }
@@ -31,6 +117,11 @@ impl DetectorManager {
         let (tx, rx) = mpsc::channel(32);
         let manager = Self {
             active_channels: HashMap::new(),
+            cross_stream: CrossStreamIndex::default(),
+            cross_stream_params: CrossStreamParams::default(),
+            reputation: Arc::new(RwLock::new(ReputationStore::default())),
+            watchlist: Watchlist::default(),
+            scripts: HashMap::new(),
             rx,
             result_tx,
         };
@@ -53,6 +144,14 @@ impl DetectorManager {
             }
         }
 
+        // Letting the workers finish the batches, that are already queued
+        for (_, channel_data) in self.active_channels.drain() {
+            for (_, worker) in channel_data.streams {
+                worker.close().await;
+            }
+        }
+        self.save_reputation().await;
+
         shared::tracing_info!("Closed");
     }
 
@@ -67,22 +166,58 @@ impl DetectorManager {
 
             match message {
                 IncMessage::Close => return Ok(()),
+                IncMessage::LoadReputation(path) => match ReputationStore::load(path).await {
+                    Ok(store) => {
+                        let mut reputation = self
+                            .reputation
+                            .write()
+                            .unwrap_or_else(PoisonError::into_inner);
+                        let current = std::mem::take(&mut *reputation);
+                        *reputation = current.merge_into(store);
+                    }
+                    Err(e) => {
+                        shared::tracing_warn!("Couldn't load reputation: {}", &e);
+                    }
+                },
                 IncMessage::ChatPoller(poller_message) => {
                     match poller_message {
                         shared::messages::chat_poller::OutMessage::ChatInit {
                             channel,
                             video_id,
                         } => {
-                            self.load_detector_and_params(channel.clone(), video_id.clone())
-                                .await;
-                            let message = OutMessage::NewChat { channel, video_id };
-                            self.result_tx.send(message).await?;
+                            // Restarted pollers keep using the detector of their stream
+                            if self
+                                .load_detector_and_params(channel.clone(), video_id.clone())
+                                .await
+                            {
+                                let message = OutMessage::NewChat { channel, video_id };
+                                self.result_tx.send(message).await?;
+                            }
+                        }
+                        shared::messages::chat_poller::OutMessage::PollerFailed {
+                            channel,
+                            video_id,
+                            error,
+                            ..
+                        } => {
+                            // The stream is kept, as the poller will be restarted.
+                            // If it can't be, `StreamEnded` will follow.
+                            shared::tracing_info!(
+                                "Chat poller {} of channel {} has failed, keeping the stream: {}",
+                                &video_id,
+                                &channel,
+                                &error
+                            );
                         }
                         shared::messages::chat_poller::OutMessage::NewBatch {
                             channel,
                             video_id,
                             actions,
                         } => {
+                            for alert in self.watchlist.find_alerts(&channel, &video_id, &actions) {
+                                self.result_tx.send(alert).await?;
+                            }
+
                             let channel_data = match self.active_channels.get_mut(&channel) {
                                 Some(data) => data,
                                 None => {
@@ -94,8 +229,8 @@ impl DetectorManager {
                                 }
                             };
 
-                            let detector = match channel_data.streams.get_mut(&video_id) {
-                                Some(detector) => detector,
+                            let worker = match channel_data.streams.get(&video_id) {
+                                Some(worker) => worker,
                                 None => {
                                     shared::tracing_warn!(
                                         "Stream data {} for channel {} wasn't initialized",
@@ -106,18 +241,19 @@ impl DetectorManager {
                                 }
                             };
 
-                            let result = detector.process_new_messages(
+                            let cross_stream_decisions = self.cross_stream.process_new_messages(
                                 &video_id,
-                                actions,
-                                &channel_data.params,
+                                &actions,
+                                &self.cross_stream_params,
                             );
 
-                            let message = OutMessage::DetectorResult {
-                                video_id,
-                                decisions: result.decisions,
-                                processed_messages: result.processed_messages,
-                            };
-                            self.result_tx.send(message).await?;
+                            worker
+                                .send(WorkerMessage::NewBatch {
+                                    actions,
+                                    cross_stream_decisions,
+                                    dispatched_at: Instant::now(),
+                                })
+                                .await;
                         }
                         shared::messages::chat_poller::OutMessage::StreamEnded {
                             channel,
@@ -134,15 +270,29 @@ impl DetectorManager {
                                 }
                             };
 
-                            let _ = channel_data.streams.remove(&video_id);
+                            // The worker processes everything, that is still in its queue, first,
+                            // so the reputation is saved with the last batches, and `ChatClosed`
+                            // comes before anything from a new worker of the same stream
+                            if let Some(worker) = channel_data.streams.remove(&video_id) {
+                                worker.close().await;
+                            }
+                            self.cross_stream.remove_stream(&video_id);
 
                             if channel_data.streams.is_empty() {
                                 // TODO: Save detector params for channel
                                 self.active_channels.remove(&channel);
+                                self.reputation
+                                    .write()
+                                    .unwrap_or_else(PoisonError::into_inner)
+                                    .remove_unconfirmed_flags(&channel);
                             }
 
+                            self.save_reputation().await;
                             self.result_tx
-                                .send(OutMessage::ChatClosed { channel, video_id })
+                                .send(OutMessage::ChatClosed {
+                                    channel: channel.clone(),
+                                    video_id: video_id.clone(),
+                                })
                                 .await?;
                         }
                     }
@@ -159,37 +309,273 @@ impl DetectorManager {
                         }
                     };
 
-                    let messages =
-                        channel_data
-                            .streams
-                            .iter_mut()
-                            .filter_map(|(video_id, detector)| {
-                                let result = detector.reanalyze(&params)?;
-                                let message = OutMessage::DetectorResult {
-                                    video_id: video_id.clone(),
-                                    decisions: result.decisions,
-                                    processed_messages: result.processed_messages,
-                                };
-                                Some(message)
-                            });
-
-                    for message in messages {
-                        self.result_tx.send(message).await?;
+                    channel_data.update_params(params).await;
+                }
+                IncMessage::UpdateCrossStreamParams(params) => {
+                    self.cross_stream_params = params;
+                }
+                IncMessage::GetDeletedMessages { video_id } => {
+                    let worker = self
+                        .active_channels
+                        .values()
+                        .find_map(|channel_data| channel_data.streams.get(&video_id));
+
+                    match worker {
+                        Some(worker) => worker.send(WorkerMessage::GetDeletedMessages).await,
+                        None => {
+                            shared::tracing_warn!(
+                                "Can't get deleted messages of uninitialized stream {}",
+                                &video_id
+                            );
+                        }
+                    }
+                }
+                IncMessage::GetMemoryUsage => {
+                    for channel_data in self.active_channels.values() {
+                        for worker in channel_data.streams.values() {
+                            worker.send(WorkerMessage::GetMemoryUsage).await;
+                        }
                     }
                 }
+                IncMessage::AddToWatchlist { channel_id, reason } => {
+                    self.watchlist.add(channel_id, reason);
+                }
+                IncMessage::RemoveFromWatchlist(channel_id) => {
+                    self.watchlist.remove(&channel_id);
+                }
+                IncMessage::UpdateScript { channel, script } => {
+                    self.update_script(channel, script).await;
+                }
+                IncMessage::UpdateShadowParams { channel, params } => {
+                    let channel_data = match self.active_channels.get_mut(&channel) {
+                        Some(data) => data,
+                        None => {
+                            shared::tracing_warn!(
+                                "Can't update shadow params of uninitialized channel {}",
+                                &channel
+                            );
+                            continue;
+                        }
+                    };
+
+                    channel_data.update_shadow_params(params).await;
+                }
             }
         }
     }
 
-    async fn load_detector_and_params(&mut self, channel: String, video_id: String) {
-        let channel_data = self.active_channels.entry(channel).or_insert_with(|| {
-            // Loading detector params for the channel
-            ChannelData {
-                streams: HashMap::new(),
-                params: DetectorParams::default(),
+    async fn save_reputation(&self) {
+        if let Err(e) = ReputationStore::save(&self.reputation).await {
+            shared::tracing_warn!("Couldn't save reputation: {}", &e);
+        }
+    }
+
+    /// Compiles the new script and sends it to every stream of the channel.
+    /// If the script can't be compiled, the old one keeps running.
+    async fn update_script(&mut self, channel: String, script: Option<ScriptParams>) {
+        let script = match script.map(Script::compile).transpose() {
+            Ok(script) => script.map(Arc::new),
+            Err(e) => {
+                shared::tracing_warn!("Couldn't update the script of {}: {}", &channel, &e);
+                return;
             }
-        });
+        };
+
+        if let Some(channel_data) = self.active_channels.get(&channel) {
+            for worker in channel_data.streams.values() {
+                worker
+                    .send(WorkerMessage::UpdateScript(script.clone()))
+                    .await;
+            }
+        }
+
+        match script {
+            Some(script) => {
+                self.scripts.insert(channel, script);
+            }
+            None => {
+                self.scripts.remove(&channel);
+            }
+        }
+    }
+
+    /// Returns `false` if the stream already has a detector
+    async fn load_detector_and_params(&mut self, channel: String, video_id: String) -> bool {
+        let channel_data = self
+            .active_channels
+            .entry(channel.clone())
+            .or_insert_with(|| {
+                // Loading detector params for the channel
+                ChannelData::new(DetectorParams::default())
+            });
+
+        if channel_data.streams.contains_key(&video_id) {
+            return false;
+        }
+
+        let script = self.scripts.get(&channel).cloned();
+        let worker = StreamWorker::spawn(
+            channel,
+            video_id.clone(),
+            channel_data.params.clone(),
+            channel_data.shared_history.clone(),
+            self.reputation.clone(),
+            script,
+            self.result_tx.clone(),
+        );
+        if channel_data.shadow_params.is_some() {
+            worker
+                .send(WorkerMessage::UpdateShadowParams(
+                    channel_data.shadow_params.clone(),
+                ))
+                .await;
+        }
+        channel_data.streams.insert(video_id, worker);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use shared::{
+        messages::{
+            chat_poller,
+            detector::{IncMessage, OutMessage},
+        },
+        types::{Action, IdEntry, MessageContent, RichText, RichTextSegment, Timestamp, User},
+    };
+    use tokio::sync::mpsc;
+
+    use super::DetectorManager;
+    use crate::test_utils::message;
+
+    fn init() -> IncMessage {
+        IncMessage::ChatPoller(chat_poller::OutMessage::ChatInit {
+            channel: "channel".to_string(),
+            video_id: "video".to_string(),
+        })
+    }
+
+    fn batch(actions: Vec<Action>) -> IncMessage {
+        IncMessage::ChatPoller(chat_poller::OutMessage::NewBatch {
+            channel: "channel".to_string(),
+            video_id: "video".to_string(),
+            actions,
+        })
+    }
+
+    fn text(text: &str) -> RichText {
+        RichText {
+            segments: vec![RichTextSegment::Text {
+                text: text.to_string(),
+                bold: false,
+                italics: false,
+            }],
+        }
+    }
+
+    fn delete(target_id: &str, timestamp: u64) -> Action {
+        Action::DeleteMessage {
+            target_id: target_id.to_string(),
+            received_at: Timestamp::from_micros(timestamp),
+        }
+    }
+
+    #[tokio::test]
+    async fn returns_deleted_messages_of_any_kind() {
+        let (result_tx, mut result_rx) = mpsc::channel(32);
+        let mut detector = DetectorManager::init(result_tx);
+        let superchat = Action::NewMessage {
+            id: IdEntry {
+                id: "superchat".to_string(),
+                timestamp: Timestamp::from_micros(2),
+                video_offset_ms: None,
+            },
+            message: MessageContent::Superchat {
+                author: User {
+                    name: None,
+                    channel_id: "supporter".to_string(),
+                    badges: None,
+                },
+                message: Some(text("hi")),
+                amount: text("€2.00"),
+                parsed_amount: None,
+            },
+        };
+
+        let messages = vec![
+            init(),
+            batch(vec![message("spammer", 1, "buy followers"), superchat]),
+            batch(vec![delete("spammer-1", 3), delete("superchat", 4)]),
+            IncMessage::GetDeletedMessages {
+                video_id: "video".to_string(),
+            },
+        ];
+        for message in messages {
+            detector.tx.send(message).await.unwrap();
+        }
+
+        let deleted = loop {
+            if let OutMessage::DeletedMessages { messages, .. } = result_rx.recv().await.unwrap() {
+                break messages;
+            }
+        };
+        let texts = deleted
+            .iter()
+            .map(|message| (message.author.as_str(), message.text.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            texts,
+            [("spammer", "buy followers"), ("supporter", "[€2.00] hi")]
+        );
+
+        detector.tx.send(IncMessage::Close).await.unwrap();
+        detector.join_handle.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn processes_the_queue_before_closing_the_stream() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("reputation.json");
+        let (result_tx, mut result_rx) = mpsc::channel(32);
+        let mut detector = DetectorManager::init(result_tx);
+
+        let mut messages = vec![IncMessage::LoadReputation(path.clone()), init()];
+        for batch_size in 1..=5 {
+            let actions = (0..batch_size)
+                .map(|i| message("viewer", batch_size * 10 + i, "hi"))
+                .collect();
+            messages.push(batch(actions));
+        }
+        messages.push(batch(vec![delete("viewer-10", 60)]));
+        messages.push(IncMessage::ChatPoller(
+            chat_poller::OutMessage::StreamEnded {
+                channel: "channel".to_string(),
+                video_id: "video".to_string(),
+            },
+        ));
+        for message in messages {
+            detector.tx.send(message).await.unwrap();
+        }
+
+        let mut processed = Vec::new();
+        loop {
+            match result_rx.recv().await.unwrap() {
+                OutMessage::DetectorResult {
+                    processed_messages, ..
+                } => processed.push(processed_messages),
+                OutMessage::ChatClosed { .. } => break,
+                _ => {}
+            }
+        }
+        assert_eq!(processed, [1, 2, 3, 4, 5, 1]);
+
+        // Reputation is saved before `ChatClosed`, with the deletion from the last batch
+        let saved: serde_json::Value =
+            serde_json::from_slice(&std::fs::read(&path).unwrap()).unwrap();
+        assert_eq!(saved["viewer"]["deleted_messages"], 1);
 
-        channel_data.streams.insert(video_id, SpamDetector::init());
+        detector.tx.send(IncMessage::Close).await.unwrap();
+        detector.join_handle.await.unwrap();
     }
 }
@@ -0,0 +1,260 @@
+use std::collections::{HashMap, VecDeque};
+
+use shared::{
+    detector_params::MemoryParams,
+    messages::detector::{Decision, DeletedMessage},
+    types::Timestamp,
+};
+
+// Rough size of a map entry and the fixed part of a message, not including the text
+const ENTRY_OVERHEAD: usize = 96;
+
+struct LoggedMessage {
+    author: String,
+    text: String,
+    timestamp: u64,
+}
+
+/// Messages of a single stream, kept so that the original content of deleted messages
+/// can be shown to moderators, along with the decisions about their authors
+#[derive(Default)]
+pub struct MessageLog {
+    messages: HashMap<String, LoggedMessage>,
+    // Ids of the messages in order of arrival, used for eviction.
+    // May contain ids of the messages, that were already deleted or replaced.
+    // They count against the cap as well, so the order never grows past it.
+    order: VecDeque<(u64, String)>,
+    deleted: VecDeque<DeletedMessage>,
+    last_decisions: HashMap<String, Decision>,
+}
+
+impl MessageLog {
+    pub fn add(&mut self, id: String, author: String, text: String, timestamp: u64) {
+        // A message, that is replaced under the same id, keeps its place in the order,
+        // otherwise its first entry would evict it too early
+        if let Some(message) = self.messages.get_mut(&id) {
+            message.author = author;
+            message.text = text;
+            return;
+        }
+
+        let message = LoggedMessage {
+            author,
+            text,
+            timestamp,
+        };
+        self.order.push_back((timestamp, id.clone()));
+        self.messages.insert(id, message);
+    }
+
+    /// Forgets a message, that has been replaced by another one
+    pub fn remove(&mut self, id: &str) {
+        self.messages.remove(id);
+    }
+
+    pub fn author(&self, id: &str) -> Option<&str> {
+        self.messages.get(id).map(|message| message.author.as_str())
+    }
+
+    pub fn record_decision(&mut self, author: &str, decision: &Decision) {
+        self.last_decisions
+            .insert(author.to_string(), decision.clone());
+    }
+
+    /// Moves the message to the list of deleted messages.
+    /// Should be called after the deletion has been analyzed,
+    /// so that the decision, caused by it, is linked to the message.
+    pub fn mark_deleted(&mut self, id: &str, deleted_at: Timestamp) {
+        let message = match self.messages.remove(id) {
+            Some(message) => message,
+            None => return,
+        };
+
+        let related_decision = self.last_decisions.get(&message.author).cloned();
+        self.deleted.push_back(DeletedMessage {
+            id: id.to_string(),
+            author: message.author,
+            text: message.text,
+            timestamp: Timestamp::from_micros(message.timestamp),
+            deleted_at,
+            related_decision,
+        });
+    }
+
+    pub fn deleted_messages(&self) -> impl Iterator<Item = &DeletedMessage> {
+        self.deleted.iter()
+    }
+
+    /// Forgets messages, that are older than the TTL, and then the oldest messages over the cap.
+    /// Returns `true` if the cap was reached.
+    pub fn evict(&mut self, now: u64, params: &MemoryParams) -> bool {
+        let expired = now.saturating_sub(params.message_ttl_usec);
+        let mut limit_reached = false;
+
+        while let Some((timestamp, id)) = self.order.front() {
+            let over_limit = self.order.len() > params.max_messages;
+            if *timestamp >= expired && !over_limit {
+                break;
+            }
+
+            // Ids of the removed messages are only dropped from the order,
+            // as well as the old entries of the ids, that were logged again
+            let is_logged = self
+                .messages
+                .get(id)
+                .is_some_and(|message| message.timestamp == *timestamp);
+            if is_logged {
+                limit_reached |= over_limit;
+                self.messages.remove(id);
+            }
+            self.order.pop_front();
+        }
+
+        while self.deleted.len() > params.max_messages {
+            limit_reached = true;
+            self.deleted.pop_front();
+        }
+
+        limit_reached
+    }
+
+    /// Forgets the decisions about users, who are no longer tracked
+    pub fn forget_decisions(&mut self, authors: &[String]) {
+        for author in authors {
+            self.last_decisions.remove(author);
+        }
+    }
+
+    pub fn messages_count(&self) -> usize {
+        self.messages.len()
+    }
+
+    pub fn deleted_messages_count(&self) -> usize {
+        self.deleted.len()
+    }
+
+    pub fn approximate_size(&self) -> usize {
+        let messages = self
+            .messages
+            .iter()
+            .map(|(id, message)| {
+                ENTRY_OVERHEAD + 2 * id.len() + message.author.len() + message.text.len()
+            })
+            .sum::<usize>();
+        let deleted = self
+            .deleted
+            .iter()
+            .map(|message| {
+                ENTRY_OVERHEAD + message.id.len() + message.author.len() + message.text.len()
+            })
+            .sum::<usize>();
+
+        messages + deleted + self.last_decisions.len() * ENTRY_OVERHEAD
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use shared::{detector_params::MemoryParams, messages::detector::Decision, types::Timestamp};
+
+    use super::MessageLog;
+
+    fn add(log: &mut MessageLog, id: &str, timestamp: u64) {
+        log.add(
+            id.to_string(),
+            "author".to_string(),
+            format!("message {}", id),
+            timestamp,
+        );
+    }
+
+    #[test]
+    fn keeps_the_content_of_deleted_messages() {
+        let mut log = MessageLog::default();
+        log.add(
+            "1".to_string(),
+            "spammer".to_string(),
+            "buy".to_string(),
+            10,
+        );
+        log.add("2".to_string(), "user".to_string(), "hello".to_string(), 20);
+        log.record_decision("spammer", &Decision::TooManyDeleted);
+
+        assert_eq!(log.author("1"), Some("spammer"));
+        log.mark_deleted("1", Timestamp::from_micros(30));
+        log.mark_deleted("unknown", Timestamp::from_micros(30));
+
+        let deleted = log.deleted_messages().collect::<Vec<_>>();
+        assert_eq!(deleted.len(), 1);
+        assert_eq!(deleted[0].id, "1");
+        assert_eq!(deleted[0].author, "spammer");
+        assert_eq!(deleted[0].text, "buy");
+        assert_eq!(deleted[0].timestamp, Timestamp::from_micros(10));
+        assert_eq!(deleted[0].deleted_at, Timestamp::from_micros(30));
+        assert!(matches!(
+            deleted[0].related_decision,
+            Some(Decision::TooManyDeleted)
+        ));
+        assert_eq!(log.author("1"), None);
+        assert_eq!(log.messages_count(), 1);
+    }
+
+    #[test]
+    fn evicts_expired_messages_and_messages_over_the_cap() {
+        let params = MemoryParams {
+            message_ttl_usec: 100,
+            max_messages: 2,
+            ..MemoryParams::default()
+        };
+        let mut log = MessageLog::default();
+        add(&mut log, "expired", 10);
+        add(&mut log, "oldest", 150);
+        add(&mut log, "replaced", 160);
+        add(&mut log, "newest", 170);
+        // Replacing a message under the same id doesn't move it in the order
+        add(&mut log, "replaced", 180);
+        assert_eq!(log.order.len(), 4);
+
+        assert!(log.evict(200, &params));
+        assert_eq!(log.messages_count(), 2);
+        assert_eq!(log.author("replaced"), Some("author"));
+        assert_eq!(log.author("newest"), Some("author"));
+
+        assert!(!log.evict(200, &params));
+        add(&mut log, "latest", 190);
+        assert!(log.evict(200, &params));
+        assert_eq!(log.author("replaced"), None);
+    }
+
+    #[test]
+    fn ids_of_removed_messages_count_against_the_cap() {
+        let params = MemoryParams {
+            message_ttl_usec: 1000,
+            max_messages: 2,
+            ..MemoryParams::default()
+        };
+        let mut log = MessageLog::default();
+        for timestamp in 0..10 {
+            let id = timestamp.to_string();
+            add(&mut log, &id, timestamp);
+            log.remove(&id);
+        }
+        add(&mut log, "kept", 10);
+
+        // Only the ids of the removed messages are dropped, until the order fits into the cap
+        assert!(!log.evict(20, &params));
+        assert_eq!(log.order.len(), 2);
+        assert_eq!(log.author("kept"), Some("author"));
+
+        // Old entry of a re-logged id doesn't evict the new message
+        let mut log = MessageLog::default();
+        add(&mut log, "relogged", 0);
+        log.remove("relogged");
+        add(&mut log, "relogged", 1);
+        add(&mut log, "other", 2);
+        assert!(!log.evict(20, &params));
+        assert_eq!(log.order.len(), 2);
+        assert_eq!(log.author("relogged"), Some("author"));
+        assert_eq!(log.messages_count(), 2);
+    }
+}
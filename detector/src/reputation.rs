@@ -0,0 +1,294 @@
+use std::{
+    collections::{BTreeSet, HashMap},
+    io,
+    path::PathBuf,
+    sync::{PoisonError, RwLock},
+};
+
+use serde::{Deserialize, Serialize};
+use shared::messages::detector::{Decision, DetectorDecision};
+
+use crate::error::ReputationStoreError;
+
+/// What is known about a user from all monitored channels
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Reputation {
+    blocked_in: BTreeSet<String>,
+    flagged_in: BTreeSet<String>,
+    deleted_messages: usize,
+}
+
+impl Reputation {
+    pub fn blocked_in(&self) -> usize {
+        self.blocked_in.len()
+    }
+
+    pub fn flagged_in(&self) -> usize {
+        self.flagged_in.len()
+    }
+
+    pub fn deleted_messages(&self) -> usize {
+        self.deleted_messages
+    }
+
+    /// All channels, where the user was either blocked or flagged
+    pub fn channels(&self) -> Vec<String> {
+        self.blocked_in.union(&self.flagged_in).cloned().collect()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.blocked_in.is_empty() && self.flagged_in.is_empty() && self.deleted_messages == 0
+    }
+}
+
+/// Reputation of users, keyed by their channel id, that is shared by all monitored channels.
+/// Without a file, the store lives only in memory.
+#[derive(Default)]
+pub struct ReputationStore {
+    users: HashMap<String, Reputation>,
+    // Channels, where the detector has flagged a user, but moderators haven't confirmed it yet.
+    // They aren't saved, as moderators act on the decisions during the stream.
+    unconfirmed_flags: HashMap<String, BTreeSet<String>>,
+    path: Option<PathBuf>,
+    has_unsaved_changes: bool,
+}
+
+impl ReputationStore {
+    /// Loads the store from the file, that is also used for all subsequent saves.
+    /// A missing file is treated as an empty store.
+    pub async fn load(path: PathBuf) -> Result<Self, ReputationStoreError> {
+        let users = match tokio::fs::read(&path).await {
+            Ok(content) => serde_json::from_slice(&content)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => HashMap::new(),
+            Err(e) => return Err(e.into()),
+        };
+
+        Ok(Self {
+            users,
+            unconfirmed_flags: HashMap::new(),
+            path: Some(path),
+            has_unsaved_changes: false,
+        })
+    }
+
+    /// Adds everything known to this store to the `other` one, and returns the result
+    pub fn merge_into(self, mut other: Self) -> Self {
+        for (channel_id, reputation) in self.users {
+            let entry = other.users.entry(channel_id).or_default();
+            entry.blocked_in.extend(reputation.blocked_in);
+            entry.flagged_in.extend(reputation.flagged_in);
+            entry.deleted_messages += reputation.deleted_messages;
+            other.has_unsaved_changes = true;
+        }
+        for (channel_id, channels) in self.unconfirmed_flags {
+            other
+                .unconfirmed_flags
+                .entry(channel_id)
+                .or_default()
+                .extend(channels);
+        }
+        other
+    }
+
+    /// Saves the shared store, if it has changed since the last save.
+    /// The store is serialized under the lock, but the file is written without holding it.
+    pub async fn save(store: &RwLock<ReputationStore>) -> Result<(), ReputationStoreError> {
+        let (path, content) = {
+            let mut store = store.write().unwrap_or_else(PoisonError::into_inner);
+            let path = match (&store.path, store.has_unsaved_changes) {
+                (Some(path), true) => path.clone(),
+                _ => return Ok(()),
+            };
+            let content = serde_json::to_vec(&store.users)?;
+            store.has_unsaved_changes = false;
+            (path, content)
+        };
+
+        if let Err(e) = ReputationStore::write(path, content).await {
+            store
+                .write()
+                .unwrap_or_else(PoisonError::into_inner)
+                .has_unsaved_changes = true;
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    pub fn get(&self, user_channel_id: &str) -> Option<&Reputation> {
+        self.users.get(user_channel_id)
+    }
+
+    /// Updates the reputation of users with the decisions, made in the `channel`.
+    /// Automatic decisions become flags only after moderators confirm them
+    /// by deleting a message of the user in the same channel.
+    pub fn record_decisions(&mut self, channel: &str, decisions: &[DetectorDecision]) {
+        for decision in decisions {
+            match &decision.decision {
+                // Reputation is built only from what happened in the chats,
+                // otherwise a single block would spread to every channel forever
+                Decision::BadReputation { .. } => {}
+                Decision::Clear => {
+                    self.remove_unconfirmed_flag(&decision.channel, channel);
+                    if let Some(reputation) = self.users.get_mut(&decision.channel) {
+                        let blocked = reputation.blocked_in.remove(channel);
+                        let flagged = reputation.flagged_in.remove(channel);
+                        self.has_unsaved_changes |= blocked || flagged;
+
+                        if reputation.is_empty() {
+                            self.users.remove(&decision.channel);
+                        }
+                    }
+                }
+                Decision::Blocked => {
+                    // A block is a confirmation on its own
+                    self.remove_unconfirmed_flag(&decision.channel, channel);
+                    let reputation = self.users.entry(decision.channel.clone()).or_default();
+                    self.has_unsaved_changes |= reputation.blocked_in.insert(channel.to_string());
+                }
+                _ => {
+                    self.unconfirmed_flags
+                        .entry(decision.channel.clone())
+                        .or_default()
+                        .insert(channel.to_string());
+                }
+            }
+        }
+    }
+
+    /// Counts messages, deleted by moderators in the `channel`,
+    /// and confirms the flags of their authors in that channel
+    pub fn record_deleted_messages(&mut self, channel: &str, authors: &[String]) {
+        for author in authors {
+            let reputation = self.users.entry(author.clone()).or_default();
+            reputation.deleted_messages += 1;
+            self.has_unsaved_changes = true;
+
+            if let Some(channels) = self.unconfirmed_flags.get_mut(author) {
+                if channels.remove(channel) {
+                    reputation.flagged_in.insert(channel.to_string());
+                }
+                if channels.is_empty() {
+                    self.unconfirmed_flags.remove(author);
+                }
+            }
+        }
+    }
+
+    /// Flags can't be confirmed after the streams of the `channel` have ended,
+    /// so they're dropped instead of being kept for the life of the process
+    pub fn remove_unconfirmed_flags(&mut self, channel: &str) {
+        self.unconfirmed_flags.retain(|_, channels| {
+            channels.remove(channel);
+            !channels.is_empty()
+        });
+    }
+
+    fn remove_unconfirmed_flag(&mut self, user_channel_id: &str, channel: &str) {
+        if let Some(channels) = self.unconfirmed_flags.get_mut(user_channel_id) {
+            channels.remove(channel);
+            if channels.is_empty() {
+                self.unconfirmed_flags.remove(user_channel_id);
+            }
+        }
+    }
+
+    async fn write(path: PathBuf, content: Vec<u8>) -> Result<(), ReputationStoreError> {
+        tokio::task::spawn_blocking(move || shared::fs::write_atomically(&path, &content))
+            .await
+            .map_err(io::Error::other)??;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::RwLock;
+
+    use shared::{
+        messages::detector::{Decision, DetectorDecision},
+        types::Timestamp,
+    };
+
+    use super::ReputationStore;
+
+    fn decision(user: &str, decision: Decision) -> DetectorDecision {
+        DetectorDecision::new(user.to_string(), Timestamp::default(), decision)
+    }
+
+    #[test]
+    fn only_confirmed_decisions_affect_the_reputation() {
+        let mut store = ReputationStore::default();
+        store.record_decisions(
+            "channel_1",
+            &[
+                decision("spammer", Decision::Similar),
+                decision("innocent", Decision::TooFast(1.0)),
+                decision("member", Decision::Blocked),
+                decision("known", Decision::BadReputation { channels: vec![] }),
+            ],
+        );
+
+        assert!(store.get("spammer").is_none());
+        assert!(store.get("known").is_none());
+        assert_eq!(store.get("member").unwrap().blocked_in(), 1);
+
+        store.record_decisions("channel_1", &[decision("innocent", Decision::Clear)]);
+        store.record_deleted_messages(
+            "channel_1",
+            &["spammer".to_string(), "innocent".to_string()],
+        );
+        // Deletions in other channels don't confirm the flag
+        store.record_decisions("channel_2", &[decision("innocent", Decision::Similar)]);
+
+        let spammer = store.get("spammer").unwrap();
+        assert_eq!(spammer.flagged_in(), 1);
+        assert_eq!(spammer.deleted_messages(), 1);
+        let innocent = store.get("innocent").unwrap();
+        assert_eq!(innocent.flagged_in(), 0);
+        assert_eq!(innocent.deleted_messages(), 1);
+
+        store.record_decisions("channel_1", &[decision("spammer", Decision::Clear)]);
+        assert_eq!(store.get("spammer").unwrap().flagged_in(), 0);
+    }
+
+    #[test]
+    fn unconfirmed_flags_are_dropped_with_the_channel() {
+        let mut store = ReputationStore::default();
+        store.record_decisions("channel_1", &[decision("spammer", Decision::Similar)]);
+        store.record_decisions("channel_2", &[decision("spammer", Decision::Similar)]);
+
+        store.remove_unconfirmed_flags("channel_1");
+        assert_eq!(store.unconfirmed_flags.len(), 1);
+        // Deletions after the end of the streams no longer confirm the flag
+        store.record_deleted_messages("channel_1", &["spammer".to_string()]);
+        assert_eq!(store.get("spammer").unwrap().flagged_in(), 0);
+
+        store.remove_unconfirmed_flags("channel_2");
+        assert!(store.unconfirmed_flags.is_empty());
+    }
+
+    #[tokio::test]
+    async fn saves_loads_and_merges_the_store() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("reputation.json");
+
+        let mut store = ReputationStore::load(path.clone()).await.unwrap();
+        store.record_decisions("channel_1", &[decision("spammer", Decision::Blocked)]);
+        let store = RwLock::new(store);
+        ReputationStore::save(&store).await.unwrap();
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1);
+
+        let mut in_memory = ReputationStore::default();
+        in_memory.record_decisions("channel_2", &[decision("spammer", Decision::Blocked)]);
+        in_memory.record_deleted_messages("channel_2", &["spammer".to_string()]);
+        let loaded = ReputationStore::load(path.clone()).await.unwrap();
+        let store = RwLock::new(in_memory.merge_into(loaded));
+        ReputationStore::save(&store).await.unwrap();
+
+        let reloaded = ReputationStore::load(path).await.unwrap();
+        let spammer = reloaded.get("spammer").unwrap();
+        assert_eq!(spammer.blocked_in(), 2);
+        assert_eq!(spammer.deleted_messages(), 1);
+        assert_eq!(spammer.channels(), ["channel_1", "channel_2"]);
+    }
+}
@@ -0,0 +1,240 @@
+use shared::types::{PlainText, User};
+
+use crate::user_data::UserStats;
+
+#[cfg(feature = "scripting")]
+pub use engine::Script;
+
+#[cfg(not(feature = "scripting"))]
+pub use disabled::Script;
+
+/// Read-only view of a message, that is passed to a detection script
+#[cfg_attr(not(feature = "scripting"), allow(dead_code))]
+pub struct MessageView<'a> {
+    pub author: &'a User,
+    pub message: &'a PlainText,
+    pub timestamp: u64,
+    /// Stats of the author before this message
+    pub stats: UserStats,
+}
+
+#[cfg(feature = "scripting")]
+mod engine {
+    use std::{cell::Cell, time::Instant};
+
+    use rhai::{Array, Dynamic, Engine, EvalAltResult, Map, Scope, AST};
+    use shared::{detector_params::ScriptParams, types::UserBadges};
+
+    use super::MessageView;
+    use crate::error::ScriptError;
+
+    thread_local! {
+        // Scripts are shared between the streams of the channel, which run on different threads,
+        // so the deadline of the current run can't be stored in the engine itself
+        static DEADLINE: Cell<Option<Instant>> = const { Cell::new(None) };
+    }
+
+    /// Compiled detection script of a channel
+    pub struct Script {
+        engine: Engine,
+        ast: AST,
+        params: ScriptParams,
+    }
+
+    impl Script {
+        pub fn compile(params: ScriptParams) -> Result<Self, ScriptError> {
+            let mut engine = Engine::new();
+            engine
+                .set_max_operations(params.max_operations)
+                .set_max_call_levels(32)
+                .set_max_expr_depths(64, 32)
+                .set_max_string_size(10_000)
+                .set_max_array_size(1_000)
+                .set_max_map_size(1_000)
+                .on_print(|text| shared::tracing_info!("Detection script: {}", text))
+                .on_debug(|text, _, _| shared::tracing_info!("Detection script: {}", text))
+                .on_progress(|_| {
+                    let timed_out = DEADLINE.with(|deadline| {
+                        deadline
+                            .get()
+                            .is_some_and(|deadline| Instant::now() >= deadline)
+                    });
+                    if timed_out {
+                        Some(Dynamic::UNIT)
+                    } else {
+                        None
+                    }
+                });
+            engine.disable_symbol("eval");
+
+            let ast = engine
+                .compile(&params.source)
+                .map_err(|e| ScriptError::Compile(e.to_string()))?;
+
+            Ok(Self {
+                engine,
+                ast,
+                params,
+            })
+        }
+
+        /// Runs the script on the message. Returns the reason, if the script considers the author a spammer.
+        pub fn check(&self, view: &MessageView) -> Result<Option<String>, ScriptError> {
+            let mut scope = Scope::new();
+            scope.push_constant("message", Script::message_map(view));
+
+            DEADLINE.with(|deadline| deadline.set(Some(Instant::now() + self.params.time_limit)));
+            let result = self
+                .engine
+                .eval_ast_with_scope::<Dynamic>(&mut scope, &self.ast);
+            DEADLINE.with(|deadline| deadline.set(None));
+
+            match result {
+                Ok(value) if value.is_unit() => Ok(None),
+                Ok(value) => match value.into_string() {
+                    Ok(reason) => Ok(Some(reason)),
+                    Err(type_name) => Err(ScriptError::WrongReturnType(type_name.to_string())),
+                },
+                Err(e) => match *e {
+                    EvalAltResult::ErrorTerminated(..) => {
+                        Err(ScriptError::TimeLimit(self.params.time_limit))
+                    }
+                    EvalAltResult::ErrorTooManyOperations(..) => {
+                        Err(ScriptError::TooManyOperations(self.params.max_operations))
+                    }
+                    e => Err(ScriptError::Runtime(e.to_string())),
+                },
+            }
+        }
+
+        fn message_map(view: &MessageView) -> Map {
+            let badges: Array = view
+                .author
+                .badges
+                .iter()
+                .flatten()
+                .map(|badge| {
+                    let badge = match badge {
+                        UserBadges::Verified => "verified",
+                        UserBadges::Owner => "owner",
+                        UserBadges::Moderator => "moderator",
+                        UserBadges::Member => "member",
+                    };
+                    Dynamic::from(badge.to_string())
+                })
+                .collect();
+
+            let mut author = Map::new();
+            author.insert("channel_id".into(), view.author.channel_id.clone().into());
+            author.insert(
+                "name".into(),
+                view.author
+                    .name
+                    .as_ref()
+                    .map(|name| name.to_plain_text().text().to_string())
+                    .unwrap_or_default()
+                    .into(),
+            );
+            author.insert("badges".into(), badges.into());
+
+            let mut user = Map::new();
+            user.insert(
+                "messages_count".into(),
+                (view.stats.messages_count as i64).into(),
+            );
+            user.insert(
+                "deleted_messages_count".into(),
+                (view.stats.deleted_messages_count as i64).into(),
+            );
+            user.insert("avg_length".into(), (view.stats.avg_length as f64).into());
+            user.insert("avg_delay".into(), (view.stats.avg_delay as f64).into());
+
+            let message = view.message;
+            let mut map = Map::new();
+            map.insert("text".into(), message.text().to_string().into());
+            map.insert("graphemes".into(), (message.graphemes() as i64).into());
+            map.insert("emojis".into(), (message.emojis() as i64).into());
+            map.insert("letters".into(), (message.letters() as i64).into());
+            map.insert("emoji_ratio".into(), (message.emoji_ratio() as f64).into());
+            map.insert("caps_ratio".into(), (message.caps_ratio() as f64).into());
+            map.insert(
+                "combining_marks_density".into(),
+                (message.combining_marks_density() as f64).into(),
+            );
+            map.insert(
+                "longest_repeated_run".into(),
+                (message.longest_repeated_run() as i64).into(),
+            );
+            map.insert("timestamp".into(), (view.timestamp as i64).into());
+            map.insert("author".into(), author.into());
+            map.insert("user".into(), user.into());
+            map
+        }
+    }
+}
+
+#[cfg(not(feature = "scripting"))]
+mod disabled {
+    use shared::detector_params::ScriptParams;
+
+    use super::MessageView;
+    use crate::error::ScriptError;
+
+    /// Placeholder, that is used when the detector is built without the `scripting` feature.
+    /// It can't be compiled, so there's never a script to run.
+    pub struct Script {
+        _private: (),
+    }
+
+    impl Script {
+        pub fn compile(_params: ScriptParams) -> Result<Self, ScriptError> {
+            Err(ScriptError::Disabled)
+        }
+
+        pub fn check(&self, _view: &MessageView) -> Result<Option<String>, ScriptError> {
+            Ok(None)
+        }
+    }
+}
+
+#[cfg(all(test, feature = "scripting"))]
+mod tests {
+    use std::time::Duration;
+
+    use shared::detector_params::ScriptParams;
+
+    use super::*;
+    use crate::{error::ScriptError, user_data::UserData};
+
+    #[test]
+    fn script_returns_reason_and_respects_limits() {
+        let author = User {
+            name: None,
+            channel_id: "user".to_string(),
+            badges: None,
+        };
+        let message = PlainText::from("BUY NOW");
+        let view = || MessageView {
+            author: &author,
+            message: &message,
+            timestamp: 1,
+            stats: UserData::new().stats(),
+        };
+
+        let script = Script::compile(ScriptParams::new(
+            r#"if message.caps_ratio > 0.5 && message.author.badges.is_empty() { "caps" }"#
+                .to_string(),
+        ))
+        .unwrap();
+        assert_eq!(script.check(&view()).unwrap(), Some("caps".to_string()));
+
+        let mut params = ScriptParams::new("loop {}".to_string());
+        params.max_operations = 0;
+        params.time_limit = Duration::from_millis(1);
+        let script = Script::compile(params).unwrap();
+        assert!(matches!(
+            script.check(&view()),
+            Err(ScriptError::TimeLimit(_))
+        ));
+    }
+}
@@ -0,0 +1,136 @@
+use std::{collections::HashSet, sync::RwLock};
+
+use shared::{
+    detector_params::DetectorParams,
+    messages::detector::{Decision, DetectorDecision, ShadowStats},
+    types::Action,
+};
+
+use crate::{reputation::ReputationStore, script::Script, spam_detector::SpamDetector};
+
+/// Second spam detector of a stream, that runs candidate params on the same messages.
+/// It always uses the history of its own stream, and its decisions never affect the reputation.
+pub struct ShadowDetector {
+    detector: SpamDetector,
+    params: DetectorParams,
+    flagged_by_production: HashSet<String>,
+    flagged_by_shadow: HashSet<String>,
+}
+
+impl ShadowDetector {
+    pub fn new(params: DetectorParams) -> Self {
+        Self {
+            detector: SpamDetector::init(),
+            params,
+            flagged_by_production: HashSet::new(),
+            flagged_by_shadow: HashSet::new(),
+        }
+    }
+
+    pub fn process_new_messages(
+        &mut self,
+        video_id: &str,
+        actions: Vec<Action>,
+        reputation: &RwLock<ReputationStore>,
+        script: Option<&Script>,
+    ) -> Vec<DetectorDecision> {
+        let decisions = self
+            .detector
+            .process_new_messages(video_id, actions, &self.params, None, reputation, script)
+            .decisions;
+        ShadowDetector::update_flagged(&mut self.flagged_by_shadow, &decisions);
+        decisions
+    }
+
+    /// Replaces the candidate params and reanalyzes the users with them
+    pub fn update_params(
+        &mut self,
+        video_id: &str,
+        params: DetectorParams,
+    ) -> Vec<DetectorDecision> {
+        self.params = params;
+        let decisions = self
+            .detector
+            .reanalyze(video_id, &self.params, None)
+            .map(|result| result.decisions)
+            .unwrap_or_default();
+        ShadowDetector::update_flagged(&mut self.flagged_by_shadow, &decisions);
+        decisions
+    }
+
+    /// Decisions of the production params, that are compared against the shadow ones.
+    /// Cross-stream decisions shouldn't be recorded, as the shadow detector never makes them.
+    pub fn record_production_decisions(&mut self, decisions: &[DetectorDecision]) {
+        ShadowDetector::update_flagged(&mut self.flagged_by_production, decisions);
+    }
+
+    pub fn stats(&self) -> ShadowStats {
+        let flagged_by_both = self
+            .flagged_by_production
+            .intersection(&self.flagged_by_shadow)
+            .count();
+
+        ShadowStats {
+            flagged_by_both,
+            flagged_only_by_production: self.flagged_by_production.len() - flagged_by_both,
+            flagged_only_by_shadow: self.flagged_by_shadow.len() - flagged_by_both,
+        }
+    }
+
+    fn update_flagged(flagged: &mut HashSet<String>, decisions: &[DetectorDecision]) {
+        for decision in decisions {
+            match decision.decision {
+                // Blocks come from moderators, so both params always see them
+                Decision::Clear | Decision::Blocked => {
+                    flagged.remove(&decision.channel);
+                }
+                _ => {
+                    flagged.insert(decision.channel.clone());
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::RwLock;
+
+    use shared::detector_params::{DetectorParams, ThresholdOverrides};
+
+    use super::ShadowDetector;
+    use crate::{reputation::ReputationStore, spam_detector::SpamDetector, test_utils::message};
+
+    #[test]
+    fn counts_users_flagged_only_by_candidate_params() {
+        let params = DetectorParams::default();
+        let candidate = DetectorParams::default().with_threshold_overrides(&ThresholdOverrides {
+            avg_length_threshold: Some(10.0),
+            avg_length_min_message_count: Some(1),
+            ..ThresholdOverrides::default()
+        });
+        let reputation = RwLock::new(ReputationStore::default());
+        let actions = vec![
+            message("talkative", 1, "A rather long message for a chat"),
+            message("quiet", 2, "hi"),
+        ];
+
+        let mut production = SpamDetector::init();
+        let mut shadow = ShadowDetector::new(candidate);
+        let production_decisions = production
+            .process_new_messages("video", actions.clone(), &params, None, &reputation, None)
+            .decisions;
+        shadow.record_production_decisions(&production_decisions);
+        let shadow_decisions = shadow.process_new_messages("video", actions, &reputation, None);
+
+        assert!(production_decisions.is_empty());
+        assert_eq!(shadow_decisions.len(), 1);
+        assert_eq!(shadow_decisions[0].channel, "talkative");
+
+        let stats = shadow.stats();
+        assert_eq!(stats.flagged_by_both, 0);
+        assert_eq!(stats.flagged_only_by_production, 0);
+        assert_eq!(stats.flagged_only_by_shadow, 1);
+        assert_eq!(stats.agreement(), 0.0);
+    }
+}
@@ -1,114 +1,257 @@
-use std::collections::HashMap;
+use std::sync::{Mutex, PoisonError, RwLock};
 
 use shared::{
-    detector_params::DetectorParams, messages::detector::DetectorDecision, types::Action,
+    detector_params::DetectorParams,
+    messages::detector::{Decision, DeletedMessage, DetectorDecision, MemoryUsage},
+    types::{Action, MembershipType, MessageContent, RichText, Timestamp, User},
 };
 
-use crate::user_data::{UserData, UserMessage};
+use crate::{
+    chat_mode::ChatModeState,
+    message_log::MessageLog,
+    reputation::ReputationStore,
+    script::{MessageView, Script},
+    user_data::UserMessage,
+    user_history::{lock, UserHistory},
+};
 
 pub struct ProcessingResult {
     pub decisions: Vec<DetectorDecision>,
     pub processed_messages: usize,
+    /// Authors of the messages, that were deleted in this batch
+    pub deleted_messages_authors: Vec<String>,
+    /// The stream has just hit the hard memory cap
+    pub memory_limit_reached: bool,
+}
+
+// Looking for inactive users requires going through all of them,
+// so it's done only once in a while, unless the cap is exceeded
+const USER_EVICTION_INTERVAL_USEC: u64 = 60 * 1_000_000;
+
+enum ExtractedAction {
+    /// `author` is kept only for simple messages, as they are the only ones checked by scripts
+    UserMessage {
+        channel_id: String,
+        message: UserMessage,
+        author: Option<User>,
+    },
+    DeletedMessage {
+        author: String,
+        target_id: String,
+    },
+    ChatModeChanged,
+    Skip,
 }
 
 pub struct SpamDetector {
-    history: HashMap<String, UserData>,
-    message_to_user: HashMap<String, String>,
+    history: UserHistory,
+    message_log: MessageLog,
+    chat_mode: ChatModeState,
+    // Timestamp of the latest message in the stream
+    last_timestamp: u64,
+    last_user_eviction: u64,
+    memory_limit_reached: bool,
 }
 
 impl SpamDetector {
     pub fn init() -> Self {
         Self {
-            history: HashMap::new(),
-            message_to_user: HashMap::new(),
+            history: UserHistory::per_stream(),
+            message_log: MessageLog::default(),
+            chat_mode: ChatModeState::default(),
+            last_timestamp: 0,
+            last_user_eviction: 0,
+            memory_limit_reached: false,
         }
     }
 
+    /// Processes new actions from the stream. If `shared_history` is provided,
+    /// it's used instead of the history of this stream.
+    /// Users with a bad `reputation` are marked as suspicious when they first appear in the history.
+    /// The `script` of the channel checks messages of regular users before the built-in rules.
+    ///
+    /// Shared history and reputation are used by other streams at the same time,
+    /// so they're locked for every message separately, instead of the whole batch.
     pub fn process_new_messages(
         &mut self,
         video_id: &str,
         actions: Vec<Action>,
         params: &DetectorParams,
+        shared_history: Option<&Mutex<UserHistory>>,
+        reputation: &RwLock<ReputationStore>,
+        script: Option<&Script>,
     ) -> ProcessingResult {
         let mut result = ProcessingResult {
             decisions: Vec::new(),
             processed_messages: 0,
+            deleted_messages_authors: Vec::new(),
+            memory_limit_reached: false,
         };
 
-        let user_messages = actions
-            .into_iter()
-            .filter_map(|action| match action {
-                Action::NewMessage { id, message }
-                | Action::ReplaceMessage {
-                    new_id: id,
-                    message,
-                    ..
-                } => match message {
-                    shared::types::MessageContent::SimpleMessage { author, message } => {
-                        self.message_to_user
-                            .insert(id.id, author.channel_id.clone());
+        let mut effective_params = self.effective_params(params);
 
-                        let message = UserMessage::Regular {
-                            message,
-                            timestamp: id.timepstamp,
-                            author_has_badges: author.badges.is_some(),
-                        };
-                        Some((author.channel_id, message))
+        for action in actions {
+            let timestamp = action.timestamp();
+            let (channel_id, message, author, deleted_id) =
+                match self.extract_user_message(video_id, action) {
+                    ExtractedAction::UserMessage {
+                        channel_id,
+                        message,
+                        author,
+                    } => (channel_id, message, author, None),
+                    ExtractedAction::DeletedMessage { author, target_id } => {
+                        (author, UserMessage::Delete, None, Some(target_id))
                     }
-                    shared::types::MessageContent::Membership { author, .. }
-                    | shared::types::MessageContent::Superchat { author, .. }
-                    | shared::types::MessageContent::Sticker { author, .. } => {
-                        Some((author.channel_id, UserMessage::Support))
+                    ExtractedAction::ChatModeChanged => {
+                        effective_params = self.effective_params(params);
+                        continue;
                     }
-                    shared::types::MessageContent::Fundraiser { author, .. } => match author {
-                        Some(user) => Some((user.channel_id, UserMessage::Support)),
-                        None => None,
-                    },
-                    shared::types::MessageContent::ChatMode { .. }
-                    | shared::types::MessageContent::PollResult { .. } => None,
-                },
-                Action::DeleteMessage { target_id } => match self.message_to_user.get(&target_id) {
-                    Some(author) => Some((author.clone(), UserMessage::Delete)),
-                    None => {
-                        shared::tracing_warn!(
-                            "{}: Couldn't find author of the deleted message",
-                            &video_id
-                        );
-                        None
-                    }
-                },
-                Action::BlockUser { channel_id } => Some((channel_id, UserMessage::Blocked)),
-                Action::CloseBanner { .. }
-                | Action::StartPoll { .. }
-                | Action::FinishPoll { .. }
-                | Action::ChannelNotice { .. }
-                | Action::FundraiserProgress { .. }
-                | Action::ClosePanel { .. } => None,
-            })
-            .collect::<Vec<_>>();
-
-        for (channel_id, message) in user_messages {
+                    ExtractedAction::Skip => continue,
+                };
+
             result.processed_messages += 1;
 
-            let user_data = self.get_user_data(channel_id.clone());
-            if let Some(decision) = user_data.analyze_new_message(message, params) {
-                let detector_decision = DetectorDecision::new(channel_id, decision);
+            if deleted_id.is_some() {
+                result.deleted_messages_authors.push(channel_id.clone());
+            }
+
+            let mut locked_history = shared_history.map(lock);
+            let history = match locked_history.as_deref_mut() {
+                Some(history) => history,
+                None => &mut self.history,
+            };
+            let is_new_user = !history.contains(&channel_id);
+            let user_data =
+                history.get_user_data(channel_id.clone(), video_id, self.last_timestamp);
+
+            if is_new_user {
+                let bad_reputation = reputation
+                    .read()
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .get(&channel_id)
+                    .filter(|known_user| {
+                        effective_params.has_bad_reputation(
+                            &known_user.blocked_in(),
+                            &known_user.flagged_in(),
+                            &known_user.deleted_messages(),
+                        )
+                    })
+                    .map(|known_user| known_user.channels());
+                if let Some(channels) = bad_reputation {
+                    user_data.mark_suspicious();
+                    let decision = Decision::BadReputation { channels };
+                    self.message_log.record_decision(&channel_id, &decision);
+                    result.decisions.push(DetectorDecision::new(
+                        channel_id.clone(),
+                        timestamp,
+                        decision,
+                    ));
+                }
+            }
+
+            // Scripts may run for a while, so they check a snapshot of the user,
+            // and the shared history isn't locked in the meantime
+            let view = match (script, &author, &message) {
+                (
+                    Some(script),
+                    Some(author),
+                    UserMessage::Regular {
+                        message: text,
+                        timestamp,
+                        ..
+                    },
+                ) if user_data.is_regular() => Some((
+                    script,
+                    MessageView {
+                        author,
+                        message: text,
+                        timestamp: *timestamp,
+                        stats: user_data.stats(),
+                    },
+                )),
+                _ => None,
+            };
+            let script_reason = match view {
+                Some((script, view)) => {
+                    drop(locked_history.take());
+                    let reason = SpamDetector::run_script(video_id, script, &view);
+                    locked_history = shared_history.map(lock);
+                    reason
+                }
+                None => None,
+            };
+
+            let history = match locked_history.as_deref_mut() {
+                Some(history) => history,
+                None => &mut self.history,
+            };
+            let user_data =
+                history.get_user_data(channel_id.clone(), video_id, self.last_timestamp);
+
+            if let Some(reason) = script_reason {
+                user_data.mark_suspicious();
+                let decision = Decision::Script { reason };
+                self.message_log.record_decision(&channel_id, &decision);
+                result.decisions.push(DetectorDecision::new(
+                    channel_id.clone(),
+                    timestamp,
+                    decision,
+                ));
+            }
+
+            if let Some(decision) = user_data.analyze_new_message(message, &effective_params) {
+                self.message_log.record_decision(&channel_id, &decision);
+                let detector_decision = DetectorDecision::new(channel_id, timestamp, decision);
                 result.decisions.push(detector_decision);
             }
+
+            if let Some(target_id) = deleted_id {
+                self.message_log.mark_deleted(&target_id, timestamp);
+            }
         }
 
+        let mut shared_history = shared_history.map(lock);
+        let history = match shared_history.as_deref_mut() {
+            Some(history) => history,
+            None => &mut self.history,
+        };
+        let limit_reached = SpamDetector::evict(
+            &mut self.message_log,
+            history,
+            &mut self.last_user_eviction,
+            self.last_timestamp,
+            params,
+        );
+        // Warning only once, until the stream gets back under the cap
+        result.memory_limit_reached = limit_reached && !self.memory_limit_reached;
+        self.memory_limit_reached = limit_reached;
+
         result
     }
 
-    pub fn reanalyze(&mut self, params: &DetectorParams) -> Option<ProcessingResult> {
-        let new_decisions = self
-            .history
-            .iter_mut()
-            .filter_map(|(channel, user_data)| {
-                let decision = user_data.reanalyze(params)?;
-                Some(DetectorDecision::new(channel.clone(), decision))
-            })
-            .collect::<Vec<_>>();
+    /// Reanalyzes users of the stream. If `shared_history` is provided,
+    /// only the users, who were last seen in this stream, are reanalyzed.
+    pub fn reanalyze(
+        &mut self,
+        video_id: &str,
+        params: &DetectorParams,
+        shared_history: Option<&mut UserHistory>,
+    ) -> Option<ProcessingResult> {
+        let params = self.effective_params(params);
+        let history = match shared_history {
+            Some(history) => history,
+            None => &mut self.history,
+        };
+        // Reanalysis isn't caused by any message, so its decisions get the time of the latest one
+        let new_decisions = history.reanalyze(
+            video_id,
+            &params,
+            Timestamp::from_micros(self.last_timestamp),
+        );
+
+        for decision in &new_decisions {
+            self.record_decision(decision);
+        }
 
         if new_decisions.is_empty() {
             None
@@ -116,13 +259,340 @@ impl SpamDetector {
             let result = ProcessingResult {
                 decisions: new_decisions,
                 processed_messages: 0,
+                deleted_messages_authors: Vec::new(),
+                memory_limit_reached: false,
             };
 
             Some(result)
         }
     }
 
-    fn get_user_data(&mut self, channel_id: String) -> &mut UserData {
-        self.history.entry(channel_id).or_insert_with(UserData::new)
+    /// Moves the history of this stream into the `shared_history`, that has just been enabled
+    pub fn share_history(
+        &mut self,
+        video_id: &str,
+        shared_history: &mut UserHistory,
+        params: &DetectorParams,
+    ) {
+        let history = std::mem::replace(&mut self.history, UserHistory::per_stream());
+        shared_history.merge_stream(video_id, history, params.memory_params());
+    }
+
+    /// Takes the users, who were last seen in this stream, back from the `shared_history`,
+    /// that has just been disabled
+    pub fn unshare_history(&mut self, video_id: &str, shared_history: &mut UserHistory) {
+        self.history = shared_history.split_stream(video_id);
+    }
+
+    /// Remembers a decision, that was made about a user of this stream outside of it
+    pub fn record_decision(&mut self, decision: &DetectorDecision) {
+        self.message_log
+            .record_decision(&decision.channel, &decision.decision);
+    }
+
+    pub fn deleted_messages(&self) -> impl Iterator<Item = &DeletedMessage> {
+        self.message_log.deleted_messages()
+    }
+
+    /// Memory usage of the stream. Shared history is counted in full for every stream, that uses it.
+    pub fn memory_usage(&self, shared_history: Option<&UserHistory>) -> MemoryUsage {
+        let history = shared_history.unwrap_or(&self.history);
+        MemoryUsage {
+            messages: self.message_log.messages_count(),
+            deleted_messages: self.message_log.deleted_messages_count(),
+            users: history.users_count(),
+            approximate_bytes: self.message_log.approximate_size() + history.approximate_size(),
+        }
+    }
+
+    /// Returns the reason, if the script reported a regular user. Errors of the script are only logged.
+    fn run_script(video_id: &str, script: &Script, view: &MessageView) -> Option<String> {
+        match script.check(view) {
+            Ok(reason) => reason,
+            Err(e) => {
+                shared::tracing_warn!("{}: Detection script failed: {}", &video_id, &e);
+                None
+            }
+        }
+    }
+
+    /// Returns `true` if any of the hard caps was reached
+    fn evict(
+        message_log: &mut MessageLog,
+        history: &mut UserHistory,
+        last_user_eviction: &mut u64,
+        now: u64,
+        params: &DetectorParams,
+    ) -> bool {
+        let memory = params.memory_params();
+        let mut limit_reached = message_log.evict(now, memory);
+
+        if now >= *last_user_eviction + USER_EVICTION_INTERVAL_USEC
+            || history.users_count() > memory.max_users
+        {
+            let (evicted, users_limit_reached) = history.evict(memory);
+            message_log.forget_decisions(&evicted);
+            limit_reached |= users_limit_reached;
+            *last_user_eviction = now;
+        }
+
+        limit_reached
+    }
+
+    /// Text of the content, that is kept in the message log, so it can be shown, if the content is deleted.
+    /// Paid content is logged with its amount, and notices without an author aren't logged at all.
+    fn logged_text(message: &MessageContent) -> Option<String> {
+        let text = |text: &RichText| text.to_plain_text().text().to_string();
+        let with_amount = |amount: &RichText, content: String| {
+            format!("[{}] {}", text(amount), content)
+                .trim_end()
+                .to_string()
+        };
+
+        let logged_text = match message {
+            MessageContent::SimpleMessage { plain_text, .. } => plain_text.text().to_string(),
+            MessageContent::Superchat {
+                message, amount, ..
+            } => with_amount(amount, message.as_ref().map(text).unwrap_or_default()),
+            MessageContent::Sticker {
+                sticker_name,
+                purchase_amount,
+                ..
+            } => with_amount(purchase_amount, sticker_name.clone()),
+            MessageContent::Membership {
+                membership_type: MembershipType::NewMember { greeting },
+                ..
+            } => text(greeting),
+            MessageContent::Membership {
+                membership_type:
+                    MembershipType::Member {
+                        period, message, ..
+                    },
+                ..
+            } => match message {
+                Some(message) => format!("[{}] {}", text(period), text(message)),
+                None => text(period),
+            },
+            MessageContent::Fundraiser { text: content, .. } => text(content),
+            MessageContent::GiftPurchase { count, tier, .. } => match tier {
+                Some(tier) => format!("Gifted {} {} memberships", count, tier),
+                None => format!("Gifted {} memberships", count),
+            },
+            MessageContent::GiftRedemption { gifter, .. } => match gifter {
+                Some(gifter) => format!("Received a gifted membership from {}", gifter),
+                None => "Received a gifted membership".to_string(),
+            },
+            MessageContent::ChatMode { .. } | MessageContent::PollResult { .. } => return None,
+        };
+        Some(logged_text)
+    }
+
+    /// Params with the overrides for the chat modes, that are currently active in the stream
+    pub fn effective_params(&self, params: &DetectorParams) -> DetectorParams {
+        params.for_chat_modes(self.chat_mode.active_modes())
+    }
+
+    fn extract_user_message(&mut self, video_id: &str, action: Action) -> ExtractedAction {
+        if let Action::ReplaceMessage {
+            target_id, new_id, ..
+        } = &action
+        {
+            if target_id != &new_id.id {
+                self.message_log.remove(target_id);
+            }
+        }
+        if let Action::NewMessage { id, .. } | Action::ReplaceMessage { new_id: id, .. } = &action {
+            self.last_timestamp = self.last_timestamp.max(id.timestamp.as_micros());
+        }
+
+        match action {
+            Action::NewMessage { id, message }
+            | Action::ReplaceMessage {
+                new_id: id,
+                message,
+                ..
+            } => {
+                if let (Some(author), Some(text)) =
+                    (message.author(), SpamDetector::logged_text(&message))
+                {
+                    self.message_log.add(
+                        id.id.clone(),
+                        author.channel_id.clone(),
+                        text,
+                        id.timestamp.as_micros(),
+                    );
+                }
+
+                match message {
+                    MessageContent::SimpleMessage {
+                        author, plain_text, ..
+                    } => {
+                        let message = UserMessage::Regular {
+                            message: plain_text,
+                            timestamp: id.timestamp.as_micros(),
+                            author_has_badges: author.badges.is_some(),
+                        };
+                        ExtractedAction::UserMessage {
+                            channel_id: author.channel_id.clone(),
+                            message,
+                            author: Some(author),
+                        }
+                    }
+                    MessageContent::Membership { author, .. }
+                    | MessageContent::Superchat { author, .. }
+                    | MessageContent::Sticker { author, .. }
+                    | MessageContent::GiftPurchase { gifter: author, .. }
+                    | MessageContent::GiftRedemption {
+                        recipient: author, ..
+                    } => ExtractedAction::UserMessage {
+                        channel_id: author.channel_id,
+                        message: UserMessage::Support,
+                        author: None,
+                    },
+                    MessageContent::Fundraiser { author, .. } => match author {
+                        Some(user) => ExtractedAction::UserMessage {
+                            channel_id: user.channel_id,
+                            message: UserMessage::Support,
+                            author: None,
+                        },
+                        None => ExtractedAction::Skip,
+                    },
+                    MessageContent::ChatMode { text, mode, .. } => {
+                        if self.chat_mode.update(&mode, &text) {
+                            ExtractedAction::ChatModeChanged
+                        } else {
+                            ExtractedAction::Skip
+                        }
+                    }
+                    MessageContent::PollResult { .. } => ExtractedAction::Skip,
+                }
+            }
+            Action::DeleteMessage { target_id, .. } => match self.message_log.author(&target_id) {
+                Some(author) => ExtractedAction::DeletedMessage {
+                    author: author.to_string(),
+                    target_id,
+                },
+                None => {
+                    shared::tracing_warn!(
+                        "{}: Couldn't find author of the deleted message",
+                        &video_id
+                    );
+                    ExtractedAction::Skip
+                }
+            },
+            Action::BlockUser { channel_id, .. } => ExtractedAction::UserMessage {
+                channel_id,
+                message: UserMessage::Blocked,
+                author: None,
+            },
+            Action::CloseBanner { .. }
+            | Action::StartPoll { .. }
+            | Action::FinishPoll { .. }
+            | Action::ChannelNotice { .. }
+            | Action::FundraiserProgress { .. }
+            | Action::ClosePanel { .. }
+            | Action::Unknown { .. } => ExtractedAction::Skip,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{Arc, Mutex, RwLock},
+        thread,
+    };
+
+    use shared::{
+        detector_params::{DetectorParams, MemoryParams},
+        types::Action,
+    };
+
+    use super::SpamDetector;
+    use crate::{
+        reputation::ReputationStore,
+        test_utils::message,
+        user_history::{lock, UserHistory},
+    };
+
+    #[test]
+    fn replaced_messages_are_logged_once() {
+        let params = DetectorParams::default();
+        let reputation = RwLock::new(ReputationStore::default());
+        let mut detector = SpamDetector::init();
+        let replacement = match message("user", 2, "edited") {
+            Action::NewMessage { id, message } => Action::ReplaceMessage {
+                target_id: "user-1".to_string(),
+                new_id: id,
+                message,
+            },
+            _ => unreachable!(),
+        };
+
+        let actions = vec![message("user", 1, "original"), replacement];
+        detector.process_new_messages("video", actions, &params, None, &reputation, None);
+
+        assert_eq!(detector.memory_usage(None).messages, 1);
+    }
+
+    #[test]
+    fn reports_the_memory_limit_once_until_the_stream_is_back_under_it() {
+        let params = DetectorParams::default().with_memory_params(MemoryParams {
+            max_messages: 2,
+            ..MemoryParams::default()
+        });
+        let reputation = RwLock::new(ReputationStore::default());
+        let mut detector = SpamDetector::init();
+        let mut process = |actions| {
+            detector
+                .process_new_messages("video", actions, &params, None, &reputation, None)
+                .memory_limit_reached
+        };
+
+        assert!(!process(vec![
+            message("first", 1, "hi"),
+            message("second", 2, "hi")
+        ]));
+        assert!(process(vec![message("third", 3, "hi")]));
+        assert!(!process(vec![message("fourth", 4, "hi")]));
+        assert!(!process(vec![]));
+        assert!(process(vec![message("fifth", 5, "hi")]));
+    }
+
+    #[test]
+    fn streams_with_a_shared_history_process_messages_at_the_same_time() {
+        let shared_history = Arc::new(Mutex::new(UserHistory::shared()));
+        let reputation = Arc::new(RwLock::new(ReputationStore::default()));
+
+        let streams = ["stream_1", "stream_2"]
+            .iter()
+            .enumerate()
+            .map(|(i, video_id)| {
+                let shared_history = shared_history.clone();
+                let reputation = reputation.clone();
+                thread::spawn(move || {
+                    let params = DetectorParams::default();
+                    let mut detector = SpamDetector::init();
+                    for n in 0..10 {
+                        let timestamp = (n * 2 + i as u64) * 1_000_000;
+                        detector.process_new_messages(
+                            video_id,
+                            vec![message("viewer", timestamp, "hello there")],
+                            &params,
+                            Some(&shared_history),
+                            &reputation,
+                            None,
+                        );
+                    }
+                })
+            });
+        for stream in streams.collect::<Vec<_>>() {
+            stream.join().unwrap();
+        }
+
+        let mut history = lock(&shared_history);
+        assert_eq!(history.users_count(), 1);
+        let user_data = history.get_user_data("viewer".to_string(), "stream_1", 0);
+        assert_eq!(user_data.stats().messages_count, 20);
     }
 }
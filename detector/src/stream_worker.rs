@@ -0,0 +1,371 @@
+use std::{
+    sync::{Arc, Mutex, PoisonError, RwLock},
+    time::Instant,
+};
+
+use shared::{
+    detector_params::DetectorParams,
+    messages::detector::{DetectorDecision, OutMessage, ProcessingLatency},
+    types::Action,
+};
+use tokio::{
+    sync::mpsc::{self, Receiver, Sender},
+    task::JoinHandle,
+};
+
+use crate::{
+    error::DetectorError,
+    reputation::ReputationStore,
+    script::Script,
+    shadow::ShadowDetector,
+    spam_detector::SpamDetector,
+    user_history::{lock, UserHistory},
+};
+
+/// Number of messages, that can wait in the queue of a worker,
+/// before the manager has to wait for the worker
+const QUEUE_SIZE: usize = 64;
+
+pub type SharedHistory = Arc<Mutex<UserHistory>>;
+pub type SharedReputation = Arc<RwLock<ReputationStore>>;
+
+pub enum WorkerMessage {
+    NewBatch {
+        actions: Vec<Action>,
+        cross_stream_decisions: Vec<DetectorDecision>,
+        dispatched_at: Instant,
+    },
+    UpdateParams {
+        params: DetectorParams,
+        shared_history: Option<SharedHistory>,
+        dispatched_at: Instant,
+    },
+    /// Replaces the script for the next batches. Users, that were already analyzed, aren't rechecked.
+    UpdateScript(Option<Arc<Script>>),
+    /// Starts, updates or stops the shadow detector
+    UpdateShadowParams(Option<DetectorParams>),
+    GetDeletedMessages,
+    GetMemoryUsage,
+    /// Stops the worker after all queued messages
+    Close,
+}
+
+/// Handle to the task, that runs the spam detector of a single stream.
+/// The queue is bounded, so that a stream, that can't keep up, slows down the manager
+/// instead of growing the queue without limits.
+/// Its length shows up as the `queued` part of the processing latency.
+pub struct StreamWorker {
+    tx: Sender<WorkerMessage>,
+    join_handle: JoinHandle<()>,
+}
+
+impl StreamWorker {
+    pub fn spawn(
+        channel: String,
+        video_id: String,
+        params: DetectorParams,
+        shared_history: Option<SharedHistory>,
+        reputation: SharedReputation,
+        script: Option<Arc<Script>>,
+        result_tx: Sender<OutMessage>,
+    ) -> Self {
+        let (tx, rx) = mpsc::channel(QUEUE_SIZE);
+        let worker = Worker {
+            channel,
+            video_id,
+            detector: SpamDetector::init(),
+            shadow: None,
+            params,
+            shared_history,
+            reputation,
+            script,
+            rx,
+            result_tx,
+        };
+
+        let join_handle = tokio::spawn(async move {
+            worker.run().await;
+        });
+
+        Self { tx, join_handle }
+    }
+
+    pub async fn send(&self, message: WorkerMessage) {
+        if self.tx.send(message).await.is_err() {
+            shared::tracing_error!("Stream worker has stopped unexpectedly");
+        }
+    }
+
+    /// Waits until the worker processes everything, that is still in its queue, and stops
+    pub async fn close(self) {
+        self.send(WorkerMessage::Close).await;
+        if let Err(e) = self.join_handle.await {
+            shared::tracing_error!("Stream worker panicked: {}", &e);
+        }
+    }
+}
+
+struct Worker {
+    channel: String,
+    video_id: String,
+    detector: SpamDetector,
+    shadow: Option<ShadowDetector>,
+    params: DetectorParams,
+    shared_history: Option<SharedHistory>,
+    reputation: SharedReputation,
+    script: Option<Arc<Script>>,
+    rx: Receiver<WorkerMessage>,
+    result_tx: Sender<OutMessage>,
+}
+
+impl Worker {
+    async fn run(mut self) {
+        match self.do_run().await {
+            Ok(_r) => {
+                // Worker finished it's work due to the end of the stream or `Close` message
+            }
+            Err(e) => {
+                shared::tracing_warn!(
+                    "{}: Error, while processing messages: {}",
+                    &self.video_id,
+                    &e
+                );
+            }
+        }
+    }
+
+    async fn do_run(&mut self) -> Result<(), DetectorError> {
+        loop {
+            let message = match self.rx.recv().await {
+                Some(message) => message,
+                None => {
+                    return Err(DetectorError::IncomingChannelClosed);
+                }
+            };
+
+            match message {
+                WorkerMessage::NewBatch {
+                    actions,
+                    cross_stream_decisions,
+                    dispatched_at,
+                } => {
+                    self.process_batch(actions, cross_stream_decisions, dispatched_at)
+                        .await?
+                }
+                WorkerMessage::UpdateParams {
+                    params,
+                    shared_history,
+                    dispatched_at,
+                } => {
+                    match (&self.shared_history, &shared_history) {
+                        (None, Some(new)) => {
+                            self.detector
+                                .share_history(&self.video_id, &mut lock(new), &params)
+                        }
+                        (Some(old), None) => self
+                            .detector
+                            .unshare_history(&self.video_id, &mut lock(old)),
+                        _ => {}
+                    }
+                    self.params = params;
+                    self.shared_history = shared_history;
+                    self.reanalyze(dispatched_at).await?;
+                }
+                WorkerMessage::UpdateScript(script) => self.script = script,
+                WorkerMessage::UpdateShadowParams(params) => {
+                    self.update_shadow_params(params).await?
+                }
+                WorkerMessage::GetDeletedMessages => {
+                    let message = OutMessage::DeletedMessages {
+                        video_id: self.video_id.clone(),
+                        messages: self.detector.deleted_messages().cloned().collect(),
+                    };
+                    self.result_tx.send(message).await?;
+                }
+                WorkerMessage::GetMemoryUsage => {
+                    let usage = {
+                        let shared_history = self.shared_history.as_deref().map(lock);
+                        self.detector.memory_usage(shared_history.as_deref())
+                    };
+                    let message = OutMessage::MemoryUsage {
+                        video_id: self.video_id.clone(),
+                        usage,
+                    };
+                    self.result_tx.send(message).await?;
+                }
+                WorkerMessage::Close => return Ok(()),
+            }
+        }
+    }
+
+    async fn process_batch(
+        &mut self,
+        actions: Vec<Action>,
+        cross_stream_decisions: Vec<DetectorDecision>,
+        dispatched_at: Instant,
+    ) -> Result<(), DetectorError> {
+        let started_at = Instant::now();
+        let shadow_actions = self.shadow.as_ref().map(|_| actions.clone());
+
+        // Shared history and reputation are locked by the detector only for a single message,
+        // so other streams aren't blocked by the whole batch
+        let mut result = self.detector.process_new_messages(
+            &self.video_id,
+            actions,
+            &self.params,
+            self.shared_history.as_deref(),
+            &self.reputation,
+            self.script.as_deref(),
+        );
+        let memory_usage = if result.memory_limit_reached {
+            let shared_history = self.shared_history.as_deref().map(lock);
+            Some(self.detector.memory_usage(shared_history.as_deref()))
+        } else {
+            None
+        };
+
+        if let Some(shadow) = &mut self.shadow {
+            shadow.record_production_decisions(&result.decisions);
+        }
+
+        for decision in &cross_stream_decisions {
+            self.detector.record_decision(decision);
+        }
+        result.decisions.extend(cross_stream_decisions);
+
+        // Most batches have nothing to record, so the global write lock is taken only when needed
+        if !result.decisions.is_empty() || !result.deleted_messages_authors.is_empty() {
+            let mut reputation = self
+                .reputation
+                .write()
+                .unwrap_or_else(PoisonError::into_inner);
+            reputation.record_decisions(&self.channel, &result.decisions);
+            reputation.record_deleted_messages(&self.channel, &result.deleted_messages_authors);
+        }
+
+        let latency = ProcessingLatency {
+            queued: started_at.duration_since(dispatched_at),
+            processing: started_at.elapsed(),
+        };
+
+        if let Some(usage) = memory_usage {
+            shared::tracing_warn!(
+                "{}: Memory limit reached, evicting: {:?}",
+                &self.video_id,
+                &usage
+            );
+            let message = OutMessage::MemoryLimitReached {
+                video_id: self.video_id.clone(),
+                usage,
+            };
+            self.result_tx.send(message).await?;
+        }
+
+        let message = OutMessage::DetectorResult {
+            video_id: self.video_id.clone(),
+            decisions: result.decisions,
+            processed_messages: result.processed_messages,
+            latency,
+        };
+        self.result_tx.send(message).await?;
+
+        // Shadow detector runs after the production result is sent, so it never delays it
+        if let Some(actions) = shadow_actions {
+            self.process_shadow_batch(actions).await?;
+        }
+        Ok(())
+    }
+
+    async fn process_shadow_batch(&mut self, actions: Vec<Action>) -> Result<(), DetectorError> {
+        let shadow = match &mut self.shadow {
+            Some(shadow) => shadow,
+            None => return Ok(()),
+        };
+
+        let decisions = shadow.process_new_messages(
+            &self.video_id,
+            actions,
+            &self.reputation,
+            self.script.as_deref(),
+        );
+
+        let message = OutMessage::ShadowResult {
+            video_id: self.video_id.clone(),
+            decisions,
+            stats: shadow.stats(),
+        };
+        self.result_tx.send(message).await?;
+        Ok(())
+    }
+
+    /// New shadow detector starts with an empty history,
+    /// while the existing one reanalyzes its users with the new params
+    async fn update_shadow_params(
+        &mut self,
+        params: Option<DetectorParams>,
+    ) -> Result<(), DetectorError> {
+        let params = match params {
+            Some(params) => params,
+            None => {
+                self.shadow = None;
+                return Ok(());
+            }
+        };
+
+        let shadow = match &mut self.shadow {
+            Some(shadow) => shadow,
+            None => {
+                self.shadow = Some(ShadowDetector::new(params));
+                return Ok(());
+            }
+        };
+
+        let decisions = shadow.update_params(&self.video_id, params);
+        if decisions.is_empty() {
+            return Ok(());
+        }
+
+        let message = OutMessage::ShadowResult {
+            video_id: self.video_id.clone(),
+            decisions,
+            stats: shadow.stats(),
+        };
+        self.result_tx.send(message).await?;
+        Ok(())
+    }
+
+    async fn reanalyze(&mut self, dispatched_at: Instant) -> Result<(), DetectorError> {
+        let started_at = Instant::now();
+
+        let result = {
+            let mut shared_history = self.shared_history.as_deref().map(lock);
+            self.detector
+                .reanalyze(&self.video_id, &self.params, shared_history.as_deref_mut())
+        };
+
+        let result = match result {
+            Some(result) => result,
+            None => return Ok(()),
+        };
+
+        self.reputation
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .record_decisions(&self.channel, &result.decisions);
+        if let Some(shadow) = &mut self.shadow {
+            shadow.record_production_decisions(&result.decisions);
+        }
+
+        let message = OutMessage::DetectorResult {
+            video_id: self.video_id.clone(),
+            decisions: result.decisions,
+            processed_messages: 0,
+            latency: ProcessingLatency {
+                queued: started_at.duration_since(dispatched_at),
+                processing: started_at.elapsed(),
+            },
+        };
+        self.result_tx.send(message).await?;
+        Ok(())
+    }
+}
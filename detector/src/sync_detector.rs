@@ -0,0 +1,90 @@
+use std::sync::RwLock;
+
+use shared::{
+    detector_params::DetectorParams,
+    messages::detector::{DeletedMessage, DetectorDecision, MemoryUsage},
+    types::Action,
+};
+
+use crate::{reputation::ReputationStore, spam_detector::SpamDetector};
+
+/// Spam detector of a single chat, that can be used without the `DetectorManager` actor
+/// and without a tokio runtime.
+///
+/// It runs the same per-stream logic as the actor: chat modes, floods, memory limits
+/// and the audit of deleted messages. Cross-stream detection, shared history, reputation
+/// and the watchlist need several chats, so they are available only through the actor.
+///
+/// ```
+/// use detector::Detector;
+/// use shared::{
+///     detector_params::DetectorParams,
+///     messages::detector::Decision,
+///     types::{Action, Timestamp},
+/// };
+///
+/// let mut detector = Detector::new("video_id".to_string(), DetectorParams::default());
+/// let decisions = detector.process(vec![Action::BlockUser {
+///     channel_id: "spammer".to_string(),
+///     received_at: Timestamp::from_micros(1_000_000),
+/// }]);
+///
+/// assert_eq!(decisions[0].channel, "spammer");
+/// assert_eq!(decisions[0].timestamp, Timestamp::from_micros(1_000_000));
+/// assert!(matches!(decisions[0].decision, Decision::Blocked));
+/// ```
+pub struct Detector {
+    video_id: String,
+    params: DetectorParams,
+    detector: SpamDetector,
+    // Always empty, as reputation is built from several channels
+    reputation: RwLock<ReputationStore>,
+}
+
+impl Detector {
+    /// `video_id` is used only to tell apart the detectors in logs
+    pub fn new(video_id: String, params: DetectorParams) -> Self {
+        Self {
+            video_id,
+            params,
+            detector: SpamDetector::init(),
+            reputation: RwLock::default(),
+        }
+    }
+
+    /// Processes actions in the order they happened in the chat,
+    /// and returns decisions about users, whose status has changed
+    pub fn process(&mut self, actions: Vec<Action>) -> Vec<DetectorDecision> {
+        self.detector
+            .process_new_messages(
+                &self.video_id,
+                actions,
+                &self.params,
+                None,
+                &self.reputation,
+                None,
+            )
+            .decisions
+    }
+
+    /// Replaces the params and reanalyzes all users with them
+    pub fn update_params(&mut self, params: DetectorParams) -> Vec<DetectorDecision> {
+        self.params = params;
+        self.detector
+            .reanalyze(&self.video_id, &self.params, None)
+            .map(|result| result.decisions)
+            .unwrap_or_default()
+    }
+
+    pub fn params(&self) -> &DetectorParams {
+        &self.params
+    }
+
+    pub fn deleted_messages(&self) -> impl Iterator<Item = &DeletedMessage> {
+        self.detector.deleted_messages()
+    }
+
+    pub fn memory_usage(&self) -> MemoryUsage {
+        self.detector.memory_usage(None)
+    }
+}
@@ -0,0 +1,21 @@
+use shared::types::{Action, IdEntry, MessageContent, PlainText, RichText, Timestamp, User};
+
+/// New plain message from a user without badges, with an id made of the author and the time
+pub fn message(channel_id: &str, timestamp: u64, text: &str) -> Action {
+    Action::NewMessage {
+        id: IdEntry {
+            id: format!("{}-{}", channel_id, timestamp),
+            timestamp: Timestamp::from_micros(timestamp),
+            video_offset_ms: None,
+        },
+        message: MessageContent::SimpleMessage {
+            author: User {
+                name: None,
+                channel_id: channel_id.to_string(),
+                badges: None,
+            },
+            message: RichText::default(),
+            plain_text: PlainText::from(text),
+        },
+    }
+}
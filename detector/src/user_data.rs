@@ -1,10 +1,14 @@
 use std::mem;
 
-use shared::{detector_params::DetectorParams, messages::detector::Decision};
+use shared::{
+    detector_params::{DetectorParams, MemoryParams},
+    messages::detector::Decision,
+    types::PlainText,
+};
 
 pub enum UserMessage {
     Regular {
-        message: String,
+        message: PlainText,
         timestamp: u64,
         author_has_badges: bool,
     },
@@ -14,27 +18,61 @@ pub enum UserMessage {
 }
 
 enum UserStatus {
-    Immune, // Members, moderators, verified users and users who sent superchat or sticker
+    Immune, // Members, moderators, verified users, users who sent superchat or sticker and gift recipients
     Blocked {
         // User was blocked by moderators or by the streamer
         // But there's still a chance, that the ban was a mistake, and they can be unblocked
-        history: Vec<(u64, String)>,
+        history: Vec<(u64, PlainText)>,
         delete_messages_count: usize,
     },
     Suspicious {
         // Collects data just as a regular user, but doesn't analyze it
-        history: Vec<(u64, String)>,
+        history: Vec<(u64, PlainText)>,
         delete_messages_count: usize,
     },
     Regular {
         // Regular user. Collect and analyze their messages
-        history: Vec<(u64, String)>,
+        history: Vec<(u64, PlainText)>,
         delete_messages_count: usize,
     },
 }
 
+impl UserStatus {
+    fn into_history(self) -> (Vec<(u64, PlainText)>, usize) {
+        match self {
+            UserStatus::Immune => (Vec::new(), 0),
+            UserStatus::Blocked {
+                history,
+                delete_messages_count,
+            }
+            | UserStatus::Suspicious {
+                history,
+                delete_messages_count,
+            }
+            | UserStatus::Regular {
+                history,
+                delete_messages_count,
+            } => (history, delete_messages_count),
+        }
+    }
+}
+
+/// Statistics about the user's messages in the history, that are available to detection scripts
+#[cfg_attr(not(feature = "scripting"), allow(dead_code))]
+pub struct UserStats {
+    pub messages_count: usize,
+    pub deleted_messages_count: usize,
+    pub avg_length: f32,
+    /// In microseconds
+    pub avg_delay: f32,
+}
+
+// Rough size of a user entry and a message in their history, not including the text
+const ENTRY_OVERHEAD: usize = 96;
+
 pub struct UserData {
     status: UserStatus,
+    last_active: u64,
 }
 
 impl UserData {
@@ -44,9 +82,135 @@ impl UserData {
                 history: Vec::new(),
                 delete_messages_count: 0,
             },
+            last_active: 0,
         }
     }
 
+    pub fn touch(&mut self, timestamp: u64) {
+        self.last_active = self.last_active.max(timestamp);
+    }
+
+    pub fn last_active(&self) -> u64 {
+        self.last_active
+    }
+
+    /// Suspicious and blocked users are kept, as moderators may still act on the decisions about them
+    pub fn is_evictable(&self) -> bool {
+        matches!(self.status, UserStatus::Immune | UserStatus::Regular { .. })
+    }
+
+    pub fn approximate_size(&self) -> usize {
+        let history_size = match &self.status {
+            UserStatus::Immune => 0,
+            UserStatus::Blocked { history, .. }
+            | UserStatus::Suspicious { history, .. }
+            | UserStatus::Regular { history, .. } => history
+                .iter()
+                .map(|(_, message)| ENTRY_OVERHEAD + message.text().len())
+                .sum(),
+        };
+
+        ENTRY_OVERHEAD + history_size
+    }
+
+    /// Only regular users are analyzed: others are either trusted or already reported
+    pub fn is_regular(&self) -> bool {
+        matches!(self.status, UserStatus::Regular { .. })
+    }
+
+    pub fn stats(&self) -> UserStats {
+        let (history, deleted_messages_count) = match &self.status {
+            UserStatus::Immune => (&[][..], 0),
+            UserStatus::Blocked {
+                history,
+                delete_messages_count,
+            }
+            | UserStatus::Suspicious {
+                history,
+                delete_messages_count,
+            }
+            | UserStatus::Regular {
+                history,
+                delete_messages_count,
+            } => (history.as_slice(), *delete_messages_count),
+        };
+
+        let (avg_length, avg_delay) = match history {
+            [] => (0.0, 0.0),
+            [(first_timestamp, _), .., (last_timestamp, _)] => {
+                let lengths: usize = history.iter().map(|(_, message)| message.graphemes()).sum();
+                let delays = last_timestamp.saturating_sub(*first_timestamp);
+                (
+                    lengths as f32 / history.len() as f32,
+                    delays as f32 / (history.len() - 1) as f32,
+                )
+            }
+            [(_, message)] => (message.graphemes() as f32, 0.0),
+        };
+
+        UserStats {
+            messages_count: history.len(),
+            deleted_messages_count,
+            avg_length,
+            avg_delay,
+        }
+    }
+
+    /// Merges data about the same user from another history.
+    /// Immunity and blocks are kept, messages are merged by time and deleted messages are summed up.
+    pub fn merge(&mut self, other: UserData, params: &MemoryParams) {
+        self.last_active = self.last_active.max(other.last_active);
+        let status = mem::replace(&mut self.status, UserStatus::Immune);
+        self.status = match (status, other.status) {
+            (UserStatus::Immune, _) | (_, UserStatus::Immune) => UserStatus::Immune,
+            (status, other_status) => {
+                let is_blocked = matches!(status, UserStatus::Blocked { .. })
+                    || matches!(other_status, UserStatus::Blocked { .. });
+                let is_suspicious = matches!(status, UserStatus::Suspicious { .. })
+                    || matches!(other_status, UserStatus::Suspicious { .. });
+
+                let (mut history, mut delete_messages_count) = status.into_history();
+                let (other_history, other_delete_messages_count) = other_status.into_history();
+                for (timestamp, message) in other_history {
+                    UserData::insert_message(&mut history, timestamp, message, params);
+                }
+                delete_messages_count += other_delete_messages_count;
+
+                if is_blocked {
+                    UserStatus::Blocked {
+                        history,
+                        delete_messages_count,
+                    }
+                } else if is_suspicious {
+                    UserStatus::Suspicious {
+                        history,
+                        delete_messages_count,
+                    }
+                } else {
+                    UserStatus::Regular {
+                        history,
+                        delete_messages_count,
+                    }
+                }
+            }
+        };
+    }
+
+    /// Marks a regular user as suspicious without analyzing their messages
+    pub fn mark_suspicious(&mut self) {
+        let old_status = mem::replace(&mut self.status, UserStatus::Immune);
+        self.status = match old_status {
+            UserStatus::Regular {
+                history,
+                delete_messages_count,
+            } => UserStatus::Suspicious {
+                history,
+                delete_messages_count,
+            },
+            status => status,
+        };
+    }
+
     pub fn reanalyze(&mut self, params: &DetectorParams) -> Option<Decision> {
         let old_status = mem::replace(&mut self.status, UserStatus::Immune);
         let (new_status, decision) = match old_status {
@@ -54,7 +218,12 @@ impl UserData {
             UserStatus::Suspicious {
                 history,
                 delete_messages_count,
-            } => match UserData::make_decision(&history, &delete_messages_count, params) {
+            } => match UserData::make_decision(
+                &history,
+                &delete_messages_count,
+                params,
+                UserData::check_history_flood(&history, params),
+            ) {
                 Some(decision) => {
                     let new_status = UserStatus::Suspicious {
                         history,
@@ -73,7 +242,12 @@ impl UserData {
             UserStatus::Regular {
                 history,
                 delete_messages_count,
-            } => match UserData::make_decision(&history, &delete_messages_count, params) {
+            } => match UserData::make_decision(
+                &history,
+                &delete_messages_count,
+                params,
+                UserData::check_history_flood(&history, params),
+            ) {
                 Some(decision) => {
                     let new_status = UserStatus::Suspicious {
                         history,
@@ -113,7 +287,17 @@ impl UserData {
         params: &DetectorParams,
     ) -> (UserStatus, Option<Decision>) {
         match status {
-            UserStatus::Immune => (UserStatus::Immune, None),
+            UserStatus::Immune => match message {
+                // Moderators may still block a trusted user, and the block has to reach the reputation
+                UserMessage::Blocked => (
+                    UserStatus::Blocked {
+                        history: Vec::new(),
+                        delete_messages_count: 0,
+                    },
+                    Some(Decision::Blocked),
+                ),
+                _ => (UserStatus::Immune, None),
+            },
             UserStatus::Blocked {
                 history,
                 delete_messages_count,
@@ -154,7 +338,8 @@ impl UserData {
                 } => match author_has_badges {
                     true => (UserStatus::Immune, Some(Decision::Clear)),
                     false => {
-                        history.push((timestamp, message));
+                        let memory = params.memory_params();
+                        UserData::insert_message(&mut history, timestamp, message, memory);
                         let new_status = UserStatus::Suspicious {
                             history,
                             delete_messages_count,
@@ -200,13 +385,12 @@ impl UserData {
                         return (UserStatus::Immune, None);
                     }
 
-                    history.push((timestamp, message));
+                    // Older messages have already been checked for floods with the same params
+                    let flood = UserData::check_flood(&message, params);
+                    let memory = params.memory_params();
+                    UserData::insert_message(&mut history, timestamp, message, memory);
 
-                    match UserData::make_decision(
-                        &history,
-                        &delete_messages_count,
-                        params,
-                    ) {
+                    match UserData::make_decision(&history, &delete_messages_count, params, flood) {
                         Some(decision) => {
                             let new_status = UserStatus::Suspicious {
                                 history,
@@ -227,16 +411,38 @@ impl UserData {
         }
     }
 
+    /// Inserts a message, keeping the history sorted by time, and forgets the oldest ones over the cap.
+    /// Only batches are sorted, so a history, shared by several streams, gets messages out of order.
+    fn insert_message(
+        history: &mut Vec<(u64, PlainText)>,
+        timestamp: u64,
+        message: PlainText,
+        params: &MemoryParams,
+    ) {
+        let index = history.partition_point(|(other, _)| *other <= timestamp);
+        history.insert(index, (timestamp, message));
+
+        let over_limit = history.len().saturating_sub(params.max_messages_per_user);
+        history.drain(..over_limit);
+    }
+
+    /// Flood rules check every message on its own, so their result is passed as `flood`,
+    /// and new messages can be checked without going through the whole history again
     fn make_decision(
-        history: &[(u64, String)],
+        history: &[(u64, PlainText)],
         delete_messages_count: &usize,
         params: &DetectorParams,
+        flood: Option<Decision>,
     ) -> Option<Decision> {
         if params.is_too_many_deleted_messages(delete_messages_count) {
             let decision = Decision::TooManyDeleted;
             return Some(decision);
         }
 
+        if flood.is_some() {
+            return flood;
+        }
+
         struct Acc {
             last_timestamp: u64,
             sum_of_delays: u64,
@@ -254,7 +460,7 @@ impl UserData {
                 acc.sum_of_delays += timestamp - acc.last_timestamp
             }
             acc.last_timestamp = *timestamp;
-            acc.sum_of_lengths += message.len();
+            acc.sum_of_lengths += message.graphemes();
             acc
         });
 
@@ -275,7 +481,7 @@ impl UserData {
             let mut similar_count = 0;
             for (index, (_, message_1)) in history.iter().enumerate() {
                 for (_, message_2) in history.iter().skip(index + 1) {
-                    let similarity = strsim::jaro(message_1, message_2) as f32;
+                    let similarity = strsim::jaro(message_1.text(), message_2.text()) as f32;
                     if params.are_messages_similar(&similarity) {
                         similar_count += 1;
                         break;
@@ -291,4 +497,115 @@ impl UserData {
 
         None
     }
+
+    fn check_history_flood(
+        history: &[(u64, PlainText)],
+        params: &DetectorParams,
+    ) -> Option<Decision> {
+        history
+            .iter()
+            .rev()
+            .find_map(|(_, message)| UserData::check_flood(message, params))
+    }
+
+    fn check_flood(message: &PlainText, params: &DetectorParams) -> Option<Decision> {
+        let emoji_ratio = message.emoji_ratio();
+        if params.is_emoji_flood(&emoji_ratio, &message.graphemes()) {
+            return Some(Decision::EmojiFlood(emoji_ratio));
+        }
+
+        let repeated_run = message.longest_repeated_run();
+        if params.is_repeated_characters_flood(&repeated_run) {
+            return Some(Decision::RepeatedCharacters(repeated_run));
+        }
+
+        let caps_ratio = message.caps_ratio();
+        if params.is_caps_flood(&caps_ratio, &message.letters()) {
+            return Some(Decision::TooManyCaps(caps_ratio));
+        }
+
+        let combining_marks_density = message.combining_marks_density();
+        if params.is_zalgo(&combining_marks_density, &message.graphemes()) {
+            return Some(Decision::Zalgo(combining_marks_density));
+        }
+
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use shared::{
+        detector_params::{DetectorParams, FloodParams},
+        messages::detector::Decision,
+        types::{PlainText, PlainTextBuilder},
+    };
+
+    use super::{UserData, UserMessage};
+
+    fn analyze(
+        user: &mut UserData,
+        message: impl Into<PlainText>,
+        params: &DetectorParams,
+    ) -> Option<Decision> {
+        let message = UserMessage::Regular {
+            message: message.into(),
+            timestamp: 1,
+            author_has_badges: false,
+        };
+        user.analyze_new_message(message, params)
+    }
+
+    #[test]
+    fn flood_rules() {
+        let params = DetectorParams::default();
+        let decision = |text: &str| analyze(&mut UserData::new(), text, &params);
+
+        let mut emojis = PlainTextBuilder::default();
+        emojis.push_text("lol");
+        for _ in 0..6 {
+            emojis.push_emoji(":_pog:");
+            emojis.push_emoji(":_kek:");
+        }
+        assert!(matches!(
+            analyze(&mut UserData::new(), emojis.build(), &params),
+            Some(Decision::EmojiFlood(ratio)) if ratio == 0.8
+        ));
+
+        assert!(decision("Nice stream, thanks!").is_none());
+        assert!(matches!(
+            decision("nooooooooooooooo"),
+            Some(Decision::RepeatedCharacters(15))
+        ));
+        assert!(matches!(
+            decision("WHY IS NOBODY TALKING ABOUT THIS"),
+            Some(Decision::TooManyCaps(ratio)) if ratio == 1.0
+        ));
+        let zalgo = "hello"
+            .chars()
+            .map(|c| format!("{}\u{0301}\u{0316}\u{0352}", c))
+            .collect::<String>();
+        assert!(matches!(
+            decision(&format!("{} there", zalgo)),
+            Some(Decision::Zalgo(density)) if density > 1.0
+        ));
+        // Short messages are not judged by ratios
+        assert!(decision("OMG WOW").is_none());
+    }
+
+    #[test]
+    fn flood_in_earlier_messages_is_found_on_reanalysis() {
+        let lenient = DetectorParams::default().with_flood_params(FloodParams {
+            caps_ratio_threshold: 1.1,
+            ..Default::default()
+        });
+        let mut user = UserData::new();
+
+        assert!(analyze(&mut user, "WHY IS NOBODY TALKING ABOUT THIS", &lenient).is_none());
+        assert!(analyze(&mut user, "anyway, hello", &lenient).is_none());
+        assert!(matches!(
+            user.reanalyze(&DetectorParams::default()),
+            Some(Decision::TooManyCaps(_))
+        ));
+    }
 }
@@ -0,0 +1,357 @@
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    sync::{Mutex, MutexGuard, PoisonError},
+};
+
+use shared::{
+    detector_params::{DetectorParams, MemoryParams},
+    messages::detector::DetectorDecision,
+    types::Timestamp,
+};
+
+use crate::user_data::UserData;
+
+pub struct UserHistory {
+    users: HashMap<String, UserData>,
+    // Tracked only when the history is shared between several streams,
+    // so that decisions made during the reanalysis can be attributed to a stream
+    last_streams: Option<HashMap<String, String>>,
+    // Latest activity of any user, used as the clock for the eviction.
+    // Streams, that share the history, may be far apart in time, like a replay next to a live stream,
+    // so none of their clocks fits the whole history.
+    latest_activity: u64,
+}
+
+impl UserHistory {
+    pub fn per_stream() -> Self {
+        Self {
+            users: HashMap::new(),
+            last_streams: None,
+            latest_activity: 0,
+        }
+    }
+
+    pub fn shared() -> Self {
+        Self {
+            users: HashMap::new(),
+            last_streams: Some(HashMap::new()),
+            latest_activity: 0,
+        }
+    }
+
+    pub fn contains(&self, channel_id: &str) -> bool {
+        self.users.contains_key(channel_id)
+    }
+
+    pub fn get_user_data(
+        &mut self,
+        channel_id: String,
+        video_id: &str,
+        timestamp: u64,
+    ) -> &mut UserData {
+        if let Some(last_streams) = &mut self.last_streams {
+            match last_streams.get_mut(&channel_id) {
+                Some(last_stream) if last_stream == video_id => {}
+                Some(last_stream) => *last_stream = video_id.to_string(),
+                None => {
+                    last_streams.insert(channel_id.clone(), video_id.to_string());
+                }
+            }
+        }
+
+        self.latest_activity = self.latest_activity.max(timestamp);
+        let user_data = self.users.entry(channel_id).or_insert_with(UserData::new);
+        user_data.touch(timestamp);
+        user_data
+    }
+
+    /// Merges the history of the `video_id` stream into this shared history,
+    /// when sharing has just been enabled
+    pub fn merge_stream(
+        &mut self,
+        video_id: &str,
+        stream_history: UserHistory,
+        params: &MemoryParams,
+    ) {
+        self.latest_activity = self.latest_activity.max(stream_history.latest_activity);
+        for (channel_id, user_data) in stream_history.users {
+            let is_latest = match self.users.get(&channel_id) {
+                Some(known) => known.last_active() <= user_data.last_active(),
+                None => true,
+            };
+            if let (true, Some(last_streams)) = (is_latest, &mut self.last_streams) {
+                last_streams.insert(channel_id.clone(), video_id.to_string());
+            }
+
+            match self.users.entry(channel_id) {
+                Entry::Occupied(mut entry) => entry.get_mut().merge(user_data, params),
+                Entry::Vacant(entry) => {
+                    entry.insert(user_data);
+                }
+            }
+        }
+    }
+
+    /// Takes the users, who were last seen in the `video_id` stream, out of this shared history
+    /// into a history of that stream, when sharing has just been disabled
+    pub fn split_stream(&mut self, video_id: &str) -> UserHistory {
+        let mut stream_history = UserHistory::per_stream();
+        let last_streams = match &mut self.last_streams {
+            Some(last_streams) => last_streams,
+            None => return stream_history,
+        };
+
+        let users = &mut self.users;
+        last_streams.retain(|channel_id, last_stream| {
+            if last_stream != video_id {
+                return true;
+            }
+            if let Some(user_data) = users.remove(channel_id) {
+                stream_history.latest_activity =
+                    stream_history.latest_activity.max(user_data.last_active());
+                stream_history.users.insert(channel_id.clone(), user_data);
+            }
+            false
+        });
+        stream_history
+    }
+
+    /// Forgets users, who have been inactive for longer than the TTL,
+    /// and then the least recently active users over the cap.
+    /// Suspicious and blocked users are evicted only if the cap can't be kept otherwise.
+    /// Returns evicted users and whether the cap was reached.
+    pub fn evict(&mut self, params: &MemoryParams) -> (Vec<String>, bool) {
+        let inactive_since = self
+            .latest_activity
+            .saturating_sub(params.inactive_user_ttl_usec);
+        let is_expired = |user_data: &UserData| {
+            user_data.is_evictable() && user_data.last_active() < inactive_since
+        };
+        let mut evicted = self
+            .users
+            .iter()
+            .filter(|(_, user_data)| is_expired(user_data))
+            .map(|(channel_id, _)| channel_id.clone())
+            .collect::<Vec<_>>();
+
+        let over_limit = (self.users.len() - evicted.len()).saturating_sub(params.max_users);
+        if over_limit > 0 {
+            let mut candidates = self
+                .users
+                .iter()
+                .filter(|(_, user_data)| !is_expired(user_data))
+                .map(|(channel_id, user_data)| {
+                    let priority = !user_data.is_evictable();
+                    (priority, user_data.last_active(), channel_id.clone())
+                })
+                .collect::<Vec<_>>();
+            candidates.sort_unstable();
+            evicted.extend(
+                candidates
+                    .into_iter()
+                    .take(over_limit)
+                    .map(|(_, _, channel_id)| channel_id),
+            );
+        }
+
+        for channel_id in &evicted {
+            self.users.remove(channel_id);
+            if let Some(last_streams) = &mut self.last_streams {
+                last_streams.remove(channel_id);
+            }
+        }
+
+        (evicted, over_limit > 0)
+    }
+
+    pub fn users_count(&self) -> usize {
+        self.users.len()
+    }
+
+    pub fn approximate_size(&self) -> usize {
+        self.users
+            .iter()
+            .map(|(channel_id, user_data)| channel_id.len() + user_data.approximate_size())
+            .sum()
+    }
+
+    /// Reanalyzes users with the params of the `video_id` stream.
+    /// A shared history reanalyzes only the users, who were last seen in that stream,
+    /// so that every stream can reanalyze its own part of the history.
+    /// New decisions are stamped with `timestamp`.
+    pub fn reanalyze(
+        &mut self,
+        video_id: &str,
+        params: &DetectorParams,
+        timestamp: Timestamp,
+    ) -> Vec<DetectorDecision> {
+        let last_streams = &self.last_streams;
+        self.users
+            .iter_mut()
+            .filter(|(channel, _)| match last_streams {
+                Some(last_streams) => {
+                    last_streams
+                        .get(channel.as_str())
+                        .map(|last_stream| last_stream.as_str())
+                        == Some(video_id)
+                }
+                None => true,
+            })
+            .filter_map(|(channel, user_data)| {
+                let decision = user_data.reanalyze(params)?;
+                Some(DetectorDecision::new(channel.clone(), timestamp, decision))
+            })
+            .collect()
+    }
+}
+
+// Processing never panics while holding the lock, but if it somehow does,
+// the history is still usable, so the poisoning is ignored
+pub fn lock(history: &Mutex<UserHistory>) -> MutexGuard<'_, UserHistory> {
+    history.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+#[cfg(test)]
+mod tests {
+    use shared::{
+        detector_params::{DetectorParams, MemoryParams},
+        types::PlainText,
+    };
+
+    use super::UserHistory;
+    use crate::user_data::UserMessage;
+
+    fn send(history: &mut UserHistory, channel_id: &str, video_id: &str, timestamp: u64) {
+        let message = UserMessage::Regular {
+            message: PlainText::from("hello"),
+            timestamp,
+            author_has_badges: false,
+        };
+        history
+            .get_user_data(channel_id.to_string(), video_id, timestamp)
+            .analyze_new_message(message, &DetectorParams::default());
+    }
+
+    #[test]
+    fn evicts_inactive_users_and_users_over_the_cap() {
+        let params = MemoryParams {
+            message_ttl_usec: 100,
+            inactive_user_ttl_usec: 100,
+            max_messages: 10,
+            max_users: 2,
+            max_messages_per_user: 10,
+        };
+        let mut history = UserHistory::per_stream();
+        history.get_user_data("inactive".to_string(), "stream", 10);
+        history
+            .get_user_data("suspicious".to_string(), "stream", 20)
+            .mark_suspicious();
+        history.get_user_data("old".to_string(), "stream", 150);
+        history.get_user_data("recent".to_string(), "stream", 180);
+        history.get_user_data("newest".to_string(), "stream", 190);
+
+        let (mut evicted, limit_reached) = history.evict(&params);
+        evicted.sort();
+
+        assert_eq!(evicted, ["inactive", "old", "recent"]);
+        assert!(limit_reached);
+        assert!(history.contains("suspicious"));
+        assert!(history.contains("newest"));
+    }
+
+    #[test]
+    fn evicts_suspicious_users_only_to_keep_the_cap() {
+        let params = MemoryParams {
+            max_users: 1,
+            ..MemoryParams::default()
+        };
+        let mut history = UserHistory::per_stream();
+        for (channel_id, timestamp) in [("first", 10), ("second", 20), ("third", 30)] {
+            history
+                .get_user_data(channel_id.to_string(), "stream", timestamp)
+                .mark_suspicious();
+        }
+
+        let (mut evicted, limit_reached) = history.evict(&params);
+        evicted.sort();
+
+        assert_eq!(evicted, ["first", "second"]);
+        assert!(limit_reached);
+        assert!(history.contains("third"));
+    }
+
+    #[test]
+    fn shared_history_is_evicted_against_its_own_clock() {
+        let params = MemoryParams {
+            inactive_user_ttl_usec: 100,
+            ..MemoryParams::default()
+        };
+        let mut history = UserHistory::shared();
+        // Replay of an old stream next to a live one
+        history.get_user_data("replay_user".to_string(), "replay", 10);
+        history.get_user_data("live_user".to_string(), "live", 1_000);
+        history.get_user_data("late_replay_user".to_string(), "replay", 950);
+
+        let (evicted, limit_reached) = history.evict(&params);
+        assert_eq!(evicted, ["replay_user"]);
+        assert!(!limit_reached);
+
+        // Split history keeps the clock of its users
+        let (evicted, _) = history.split_stream("replay").evict(&params);
+        assert!(evicted.is_empty());
+    }
+
+    #[test]
+    fn keeps_only_the_latest_messages_of_a_user() {
+        let mut history = UserHistory::per_stream();
+        let max_messages = DetectorParams::default()
+            .memory_params()
+            .max_messages_per_user as u64;
+        for second in 0..max_messages + 10 {
+            send(&mut history, "user", "stream", (second + 1) * 10_000_000);
+        }
+
+        let stats = history
+            .get_user_data("user".to_string(), "stream", 0)
+            .stats();
+        assert_eq!(stats.messages_count as u64, max_messages);
+    }
+
+    #[test]
+    fn shared_history_is_sorted_across_streams() {
+        let mut history = UserHistory::shared();
+        // Every stream sends its batches in order, but they interleave
+        send(&mut history, "user", "a", 3_000_000);
+        send(&mut history, "user", "b", 1_000_000);
+        send(&mut history, "user", "a", 4_000_000);
+        send(&mut history, "user", "b", 2_000_000);
+
+        let stats = history.get_user_data("user".to_string(), "b", 0).stats();
+        assert_eq!(stats.messages_count, 4);
+        assert_eq!(stats.avg_delay, 1_000_000.0);
+    }
+
+    #[test]
+    fn stream_histories_are_merged_into_a_shared_one_and_split_back() {
+        let mut shared = UserHistory::shared();
+        send(&mut shared, "both", "b", 2_000_000);
+        send(&mut shared, "only_b", "b", 2_000_000);
+
+        let mut stream_a = UserHistory::per_stream();
+        send(&mut stream_a, "both", "a", 1_000_000);
+        send(&mut stream_a, "both", "a", 3_000_000);
+        send(&mut stream_a, "only_a", "a", 1_000_000);
+        shared.merge_stream("a", stream_a, &MemoryParams::default());
+
+        assert_eq!(shared.users_count(), 3);
+        let stats = shared.get_user_data("both".to_string(), "a", 0).stats();
+        assert_eq!(stats.messages_count, 3);
+        assert_eq!(stats.avg_delay, 1_000_000.0);
+
+        let stream_a = shared.split_stream("a");
+        assert!(stream_a.contains("both"));
+        assert!(stream_a.contains("only_a"));
+        assert!(!stream_a.contains("only_b"));
+        assert!(shared.contains("only_b"));
+    }
+}
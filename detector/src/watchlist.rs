@@ -0,0 +1,124 @@
+use std::collections::HashMap;
+
+use shared::{
+    messages::detector::OutMessage,
+    types::{Action, IdEntry, MessageContent},
+};
+
+/// Known bad actors, that moderators want to be alerted about,
+/// keyed by their channel id, with the reason they were listed
+#[derive(Default)]
+pub struct Watchlist {
+    users: HashMap<String, String>,
+}
+
+impl Watchlist {
+    pub fn add(&mut self, channel_id: String, reason: String) {
+        self.users.insert(channel_id, reason);
+    }
+
+    pub fn remove(&mut self, channel_id: &str) {
+        self.users.remove(channel_id);
+    }
+
+    /// Returns an alert for every message in the batch, posted by a user from the watchlist
+    pub fn find_alerts(
+        &self,
+        channel: &str,
+        video_id: &str,
+        actions: &[Action],
+    ) -> Vec<OutMessage> {
+        if self.users.is_empty() {
+            return Vec::new();
+        }
+
+        actions
+            .iter()
+            .filter_map(|action| match action {
+                Action::NewMessage { id, message }
+                | Action::ReplaceMessage {
+                    new_id: id,
+                    message,
+                    ..
+                } => self.alert(channel, video_id, id, message),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn alert(
+        &self,
+        channel: &str,
+        video_id: &str,
+        id: &IdEntry,
+        message: &MessageContent,
+    ) -> Option<OutMessage> {
+        let author = message.author()?;
+        let reason = self.users.get(&author.channel_id)?;
+
+        Some(OutMessage::WatchlistAlert {
+            channel: channel.to_string(),
+            video_id: video_id.to_string(),
+            id: id.clone(),
+            message: Box::new(message.clone()),
+            reason: reason.clone(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use shared::{messages::detector::OutMessage, types::Action};
+
+    use super::Watchlist;
+    use crate::test_utils::message;
+
+    fn alerted_ids(alerts: &[OutMessage]) -> Vec<(&str, &str)> {
+        alerts
+            .iter()
+            .map(|alert| match alert {
+                OutMessage::WatchlistAlert { id, reason, .. } => (id.id.as_str(), reason.as_str()),
+                other => panic!("Unexpected message: {:?}", other),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn alerts_about_listed_users_until_they_are_removed() {
+        let mut watchlist = Watchlist::default();
+        let replaced = match message("troll", 3, "edited") {
+            Action::NewMessage { id, message } => Action::ReplaceMessage {
+                target_id: "temporary".to_string(),
+                new_id: id,
+                message,
+            },
+            _ => unreachable!(),
+        };
+        let actions = [
+            message("troll", 1, "hi"),
+            message("user", 2, "hello"),
+            replaced,
+        ];
+
+        assert!(watchlist
+            .find_alerts("channel", "video", &actions)
+            .is_empty());
+
+        watchlist.add("troll".to_string(), "Ban evasion".to_string());
+        let alerts = watchlist.find_alerts("channel", "video", &actions);
+        assert_eq!(
+            alerted_ids(&alerts),
+            [("troll-1", "Ban evasion"), ("troll-3", "Ban evasion")]
+        );
+        assert!(matches!(
+            &alerts[0],
+            OutMessage::WatchlistAlert { channel, video_id, .. }
+                if channel == "channel" && video_id == "video"
+        ));
+
+        watchlist.remove("troll");
+        assert!(watchlist
+            .find_alerts("channel", "video", &actions)
+            .is_empty());
+    }
+}
@@ -0,0 +1,145 @@
+use std::str::FromStr;
+
+use lazy_regex::{lazy_regex, Lazy, Regex};
+use rust_decimal::Decimal;
+
+// Symbol or code of the currency can be either before or after the number,
+// separated by a regular or a non-breaking space
+static AMOUNT: Lazy<Regex> = lazy_regex!(
+    r#"^(?P<prefix>[^\d\s.,]*)\s*(?P<number>\d[\d,]*(?:\.\d+)?)\s*(?P<suffix>[^\d\s.,]*)$"#
+);
+
+/// Currency symbols, that YouTube shows instead of ISO codes in the "en" locale.
+/// Other currencies are shown with their ISO codes, like "PLN 20.00".
+const SYMBOLS: &[(&str, &str)] = &[
+    ("$", "USD"),
+    ("US$", "USD"),
+    ("CA$", "CAD"),
+    ("A$", "AUD"),
+    ("NZ$", "NZD"),
+    ("HK$", "HKD"),
+    ("NT$", "TWD"),
+    ("MX$", "MXN"),
+    ("R$", "BRL"),
+    ("€", "EUR"),
+    ("£", "GBP"),
+    ("¥", "JPY"),
+    ("JP¥", "JPY"),
+    ("CN¥", "CNY"),
+    ("₩", "KRW"),
+    ("₹", "INR"),
+    ("₱", "PHP"),
+    ("₪", "ILS"),
+    ("₫", "VND"),
+    ("₦", "NGN"),
+    ("₴", "UAH"),
+    ("₺", "TRY"),
+    ("₽", "RUB"),
+    ("฿", "THB"),
+    ("zł", "PLN"),
+];
+
+/// Color tier of a superchat or a sticker. The higher the amount, the higher the tier.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum PurchaseTier {
+    Blue,
+    LightBlue,
+    Green,
+    Yellow,
+    Orange,
+    Magenta,
+    Red,
+}
+
+impl PurchaseTier {
+    /// Tier by either the header or the body background color in ARGB format
+    pub fn from_color(color: u32) -> Option<Self> {
+        let tier = match color {
+            0xFF1565C0 | 0xFF1E88E5 => PurchaseTier::Blue,
+            0xFF00B8D4 | 0xFF00E5FF => PurchaseTier::LightBlue,
+            0xFF00BFA5 | 0xFF1DE9B6 => PurchaseTier::Green,
+            0xFFFFB300 | 0xFFFFCA28 => PurchaseTier::Yellow,
+            0xFFE65100 | 0xFFF57C00 => PurchaseTier::Orange,
+            0xFFC2185B | 0xFFE91E63 => PurchaseTier::Magenta,
+            0xFFD00000 | 0xFFE62117 => PurchaseTier::Red,
+            _ => return None,
+        };
+        Some(tier)
+    }
+}
+
+/// Purchase amount of a superchat or a sticker
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PurchaseAmount {
+    /// ISO 4217 code
+    pub currency: String,
+    pub value: Decimal,
+    pub tier: Option<PurchaseTier>,
+}
+
+impl PurchaseAmount {
+    /// Parses display strings like "¥1,000" or "CA$5.00".
+    /// Returns `None`, if the format or the currency is unknown.
+    pub fn parse(text: &str, tier: Option<PurchaseTier>) -> Option<Self> {
+        let (currency, value) = parse_amount(text)?;
+        Some(Self {
+            currency,
+            value,
+            tier,
+        })
+    }
+}
+
+/// Splits a display string into an ISO 4217 code and a decimal amount.
+/// Chat is always requested in the "en" locale, so commas are always group separators.
+pub fn parse_amount(text: &str) -> Option<(String, Decimal)> {
+    let captures = AMOUNT.captures(text.trim())?;
+    let currency = match (&captures["prefix"], &captures["suffix"]) {
+        (symbol, "") | ("", symbol) if !symbol.is_empty() => currency_code(symbol)?,
+        _ => return None,
+    };
+    let value = Decimal::from_str(&captures["number"].replace(',', "")).ok()?;
+    Some((currency, value))
+}
+
+fn currency_code(symbol: &str) -> Option<String> {
+    if let Some((_, code)) = SYMBOLS.iter().find(|(known, _)| *known == symbol) {
+        return Some(code.to_string());
+    }
+
+    let is_code = symbol.len() == 3 && symbol.chars().all(|c| c.is_ascii_uppercase());
+    if is_code {
+        Some(symbol.to_string())
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use rust_decimal::Decimal;
+
+    use super::{parse_amount, PurchaseAmount, PurchaseTier};
+
+    fn amount(currency: &str, value: &str) -> Option<(String, Decimal)> {
+        Some((currency.to_string(), Decimal::from_str(value).unwrap()))
+    }
+
+    #[test]
+    fn parses_youtube_currency_formats() {
+        assert_eq!(parse_amount("¥1,000"), amount("JPY", "1000"));
+        assert_eq!(parse_amount("CA$5.00"), amount("CAD", "5.00"));
+        assert_eq!(parse_amount("$1,234.56"), amount("USD", "1234.56"));
+        assert_eq!(parse_amount("₩50,000"), amount("KRW", "50000"));
+        assert_eq!(parse_amount("PLN\u{a0}20.00"), amount("PLN", "20.00"));
+        assert_eq!(parse_amount("20.00 zł"), amount("PLN", "20.00"));
+        assert_eq!(parse_amount("5.00"), None);
+        assert_eq!(parse_amount("Abc 5.00"), None);
+        assert_eq!(parse_amount("$5.00€"), None);
+
+        let superchat = PurchaseAmount::parse("€2.00", PurchaseTier::from_color(4279592384));
+        assert_eq!(superchat.unwrap().tier, Some(PurchaseTier::Blue));
+    }
+}
@@ -1,3 +1,149 @@
+use std::{
+    collections::{BTreeSet, HashMap},
+    time::Duration,
+};
+
+use crate::types::ChatMode;
+
+/// Thresholds that replace the base `DetectorParams` while a chat mode is active.
+/// Fields set to `None` keep the base value.
+#[derive(Debug, Clone, Default)]
+pub struct ThresholdOverrides {
+    pub deleted_messages_threshold: Option<usize>,
+    pub avg_delay_threshold: Option<f32>,
+    pub avg_delay_min_message_count: Option<usize>,
+    pub avg_length_threshold: Option<f32>,
+    pub avg_length_min_message_count: Option<usize>,
+    pub similarity_threshold: Option<f32>,
+    pub similarity_count_threshold: Option<usize>,
+    pub similarity_min_message_length: Option<usize>,
+}
+
+/// Thresholds for the rules, that check every message on its own,
+/// looking for floods of emojis, repeated characters, caps or combining marks.
+#[derive(Debug, Clone)]
+pub struct FloodParams {
+    /// Ratio based rules are skipped for messages shorter than this amount of graphemes
+    pub min_message_length: usize,
+    pub emoji_ratio_threshold: f32,
+    pub repeated_run_threshold: usize,
+    /// Caps rule is skipped for messages with fewer letters
+    pub caps_min_letters: usize,
+    pub caps_ratio_threshold: f32,
+    pub combining_marks_density_threshold: f32,
+}
+
+impl Default for FloodParams {
+    fn default() -> Self {
+        Self {
+            min_message_length: 8,
+            emoji_ratio_threshold: 0.8,
+            repeated_run_threshold: 12,
+            caps_min_letters: 15,
+            caps_ratio_threshold: 0.8,
+            combining_marks_density_threshold: 1.0,
+        }
+    }
+}
+
+/// Params for detecting users, who post similar messages in several monitored chats at once.
+/// Unlike `DetectorParams`, these params are global for all channels.
+#[derive(Debug, Clone)]
+pub struct CrossStreamParams {
+    /// How long messages are kept in the index, in microseconds
+    pub window_usec: u64,
+    /// Messages shorter than this amount of graphemes are ignored
+    pub min_message_length: usize,
+    pub similarity_threshold: f32,
+    /// Amount of different chats with similar messages from the same user, that triggers a decision
+    pub chats_threshold: usize,
+}
+
+impl Default for CrossStreamParams {
+    fn default() -> Self {
+        Self {
+            window_usec: 10 * 60 * 1_000_000,
+            min_message_length: 10,
+            similarity_threshold: 0.85,
+            chats_threshold: 3,
+        }
+    }
+}
+
+/// Thresholds for the reputation, that users earn across all monitored channels.
+/// A user, who reaches any of them, is marked as suspicious as soon as they appear in the chat.
+#[derive(Debug, Clone)]
+pub struct ReputationParams {
+    /// Amount of channels, where the user was blocked by moderators
+    pub blocked_channels_threshold: usize,
+    /// Amount of channels, where the detector has flagged the user
+    pub flagged_channels_threshold: usize,
+    /// Total amount of deleted messages in all channels
+    pub deleted_messages_threshold: usize,
+}
+
+impl Default for ReputationParams {
+    fn default() -> Self {
+        Self {
+            blocked_channels_threshold: 1,
+            flagged_channels_threshold: 2,
+            deleted_messages_threshold: 10,
+        }
+    }
+}
+
+/// Limits for the state, that every stream keeps in memory.
+/// Time is measured by the timestamps of the messages, in microseconds.
+#[derive(Debug, Clone)]
+pub struct MemoryParams {
+    /// Messages older than this are forgotten, so their deletion can't be attributed to the author
+    pub message_ttl_usec: u64,
+    /// Regular and immune users, who haven't been active for this long, are forgotten.
+    /// Suspicious and blocked users are kept, unless the cap on users is reached.
+    pub inactive_user_ttl_usec: u64,
+    /// Hard cap on the amount of messages. When reached, the oldest messages are evicted.
+    pub max_messages: usize,
+    /// Hard cap on the amount of users. When reached, the least recently active users are evicted,
+    /// starting with regular and immune ones.
+    pub max_users: usize,
+    /// Hard cap on the amount of messages in the history of a single user.
+    /// When reached, the oldest messages of the user are forgotten.
+    pub max_messages_per_user: usize,
+}
+
+impl Default for MemoryParams {
+    fn default() -> Self {
+        Self {
+            message_ttl_usec: 30 * 60 * 1_000_000,
+            inactive_user_ttl_usec: 60 * 60 * 1_000_000,
+            max_messages: 100_000,
+            max_users: 50_000,
+            max_messages_per_user: 50,
+        }
+    }
+}
+
+/// Detection rule of a channel, written in Rhai.
+/// Only available if the `detector` is built with the `scripting` feature.
+#[derive(Debug, Clone)]
+pub struct ScriptParams {
+    pub source: String,
+    /// Scripts, that run longer than this, are terminated
+    pub time_limit: Duration,
+    /// Scripts, that perform more operations than this, are terminated
+    pub max_operations: u64,
+}
+
+impl ScriptParams {
+    pub fn new(source: String) -> Self {
+        Self {
+            source,
+            time_limit: Duration::from_millis(10),
+            max_operations: 100_000,
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct DetectorParams {
     deleted_messages_threshold: usize,
@@ -8,6 +154,11 @@ pub struct DetectorParams {
     similarity_threshold: f32,
     similarity_count_threshold: usize,
     similarity_min_message_length: usize,
+    chat_mode_overrides: HashMap<ChatMode, ThresholdOverrides>,
+    flood: FloodParams,
+    share_history_between_streams: bool,
+    reputation: Option<ReputationParams>,
+    memory: MemoryParams,
 }
 
 impl DetectorParams {
@@ -30,6 +181,105 @@ impl DetectorParams {
             similarity_threshold,
             similarity_count_threshold,
             similarity_min_message_length,
+            chat_mode_overrides: HashMap::new(),
+            flood: FloodParams::default(),
+            share_history_between_streams: false,
+            reputation: None,
+            memory: MemoryParams::default(),
+        }
+    }
+
+    /// Sets the thresholds that should be used while `mode` is enabled in the chat
+    pub fn with_chat_mode_overrides(
+        mut self,
+        mode: ChatMode,
+        overrides: ThresholdOverrides,
+    ) -> Self {
+        self.chat_mode_overrides.insert(mode, overrides);
+        self
+    }
+
+    pub fn with_flood_params(mut self, flood: FloodParams) -> Self {
+        self.flood = flood;
+        self
+    }
+
+    /// If enabled, all concurrent streams of the channel share the same user history,
+    /// so that a spammer who works several streams at once is counted only once
+    pub fn with_shared_history(mut self, share_history_between_streams: bool) -> Self {
+        self.share_history_between_streams = share_history_between_streams;
+        self
+    }
+
+    pub fn shares_history_between_streams(&self) -> bool {
+        self.share_history_between_streams
+    }
+
+    /// Opts the channel in to the reputation, shared between all monitored channels.
+    /// `None` disables it, which is the default.
+    pub fn with_reputation(mut self, reputation: Option<ReputationParams>) -> Self {
+        self.reputation = reputation;
+        self
+    }
+
+    pub fn with_memory_params(mut self, memory: MemoryParams) -> Self {
+        self.memory = memory;
+        self
+    }
+
+    pub fn memory_params(&self) -> &MemoryParams {
+        &self.memory
+    }
+
+    pub fn chat_mode_overrides(&self, mode: &ChatMode) -> Option<&ThresholdOverrides> {
+        self.chat_mode_overrides.get(mode)
+    }
+
+    /// Returns a copy of the params with the overrides of every active chat mode applied.
+    /// Overrides are applied in the order of `ChatMode`, no matter the order of `modes`,
+    /// so if several modes override the same threshold, the latter mode wins.
+    pub fn for_chat_modes<'a>(&self, modes: impl IntoIterator<Item = &'a ChatMode>) -> Self {
+        let modes = modes.into_iter().collect::<BTreeSet<_>>();
+        let mut params = self.clone();
+        for overrides in modes
+            .into_iter()
+            .filter_map(|mode| self.chat_mode_overrides(mode))
+        {
+            params.apply_overrides(overrides);
+        }
+        params
+    }
+
+    /// Replaces the base thresholds with the ones, that are set in the `overrides`
+    pub fn with_threshold_overrides(mut self, overrides: &ThresholdOverrides) -> Self {
+        self.apply_overrides(overrides);
+        self
+    }
+
+    fn apply_overrides(&mut self, overrides: &ThresholdOverrides) {
+        if let Some(value) = overrides.deleted_messages_threshold {
+            self.deleted_messages_threshold = value;
+        }
+        if let Some(value) = overrides.avg_delay_threshold {
+            self.avg_delay_threshold = value;
+        }
+        if let Some(value) = overrides.avg_delay_min_message_count {
+            self.avg_delay_min_message_count = value;
+        }
+        if let Some(value) = overrides.avg_length_threshold {
+            self.avg_length_threshold = value;
+        }
+        if let Some(value) = overrides.avg_length_min_message_count {
+            self.avg_length_min_message_count = value;
+        }
+        if let Some(value) = overrides.similarity_threshold {
+            self.similarity_threshold = value;
+        }
+        if let Some(value) = overrides.similarity_count_threshold {
+            self.similarity_count_threshold = value;
+        }
+        if let Some(value) = overrides.similarity_min_message_length {
+            self.similarity_min_message_length = value;
         }
     }
 
@@ -62,10 +312,65 @@ impl DetectorParams {
     pub fn too_many_similar_messages(&self, similar_messages_count: &usize) -> bool {
         similar_messages_count >= &self.similarity_count_threshold
     }
+
+    pub fn is_emoji_flood(&self, emoji_ratio: &f32, message_length: &usize) -> bool {
+        message_length >= &self.flood.min_message_length
+            && emoji_ratio >= &self.flood.emoji_ratio_threshold
+    }
+
+    pub fn is_repeated_characters_flood(&self, repeated_run: &usize) -> bool {
+        repeated_run >= &self.flood.repeated_run_threshold
+    }
+
+    pub fn is_caps_flood(&self, caps_ratio: &f32, letters_count: &usize) -> bool {
+        letters_count >= &self.flood.caps_min_letters
+            && caps_ratio >= &self.flood.caps_ratio_threshold
+    }
+
+    pub fn has_bad_reputation(
+        &self,
+        blocked_in_channels: &usize,
+        flagged_in_channels: &usize,
+        deleted_messages_count: &usize,
+    ) -> bool {
+        match &self.reputation {
+            Some(reputation) => {
+                blocked_in_channels >= &reputation.blocked_channels_threshold
+                    || flagged_in_channels >= &reputation.flagged_channels_threshold
+                    || deleted_messages_count >= &reputation.deleted_messages_threshold
+            }
+            None => false,
+        }
+    }
+
+    pub fn is_zalgo(&self, combining_marks_density: &f32, message_length: &usize) -> bool {
+        message_length >= &self.flood.min_message_length
+            && combining_marks_density >= &self.flood.combining_marks_density_threshold
+    }
 }
 
 impl Default for DetectorParams {
     fn default() -> Self {
+        let mut chat_mode_overrides = HashMap::new();
+        // Regular users can't send messages faster than the slow mode allows,
+        // so even a few fast messages mean that someone is bypassing it
+        chat_mode_overrides.insert(
+            ChatMode::SlowMode,
+            ThresholdOverrides {
+                avg_delay_min_message_count: Some(3),
+                ..Default::default()
+            },
+        );
+        // Almost everyone who can write in members-only chat is immune,
+        // so the rest should be checked more strictly
+        chat_mode_overrides.insert(
+            ChatMode::MembersOnly,
+            ThresholdOverrides {
+                deleted_messages_threshold: Some(2),
+                ..Default::default()
+            },
+        );
+
         Self {
             deleted_messages_threshold: 4,
             avg_delay_threshold: 5000.0,
@@ -75,6 +380,49 @@ impl Default for DetectorParams {
             similarity_threshold: 0.85,
             similarity_count_threshold: 3,
             similarity_min_message_length: 10,
+            chat_mode_overrides,
+            flood: FloodParams::default(),
+            share_history_between_streams: false,
+            reputation: None,
+            memory: MemoryParams::default(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{DetectorParams, ThresholdOverrides};
+    use crate::types::ChatMode;
+
+    #[test]
+    fn chat_mode_overrides_are_merged_in_a_fixed_order() {
+        let params = DetectorParams::default()
+            .with_chat_mode_overrides(
+                ChatMode::SlowMode,
+                ThresholdOverrides {
+                    avg_length_threshold: Some(10.0),
+                    avg_delay_threshold: Some(1000.0),
+                    ..Default::default()
+                },
+            )
+            .with_chat_mode_overrides(
+                ChatMode::MembersOnly,
+                ThresholdOverrides {
+                    avg_length_threshold: Some(20.0),
+                    ..Default::default()
+                },
+            );
+
+        for modes in [
+            [ChatMode::SlowMode, ChatMode::MembersOnly],
+            [ChatMode::MembersOnly, ChatMode::SlowMode],
+        ] {
+            let merged = params.for_chat_modes(&modes);
+            assert_eq!(merged.avg_length_threshold, 20.0);
+            assert_eq!(merged.avg_delay_threshold, 1000.0);
         }
+
+        let base = params.for_chat_modes(&[]);
+        assert_eq!(base.avg_length_threshold, 30.0);
     }
 }
@@ -0,0 +1,241 @@
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::{de::DeserializeOwned, Serialize};
+use thiserror::Error;
+use tokio::sync::mpsc::UnboundedSender;
+
+/// Bundles are removed, starting from the oldest, once their total size exceeds this limit
+pub const DEFAULT_MAX_SIZE: u64 = 100 * 1024 * 1024;
+
+/// Directory in the current working directory, where the bundles are saved, until another one is set
+pub const DEFAULT_DIR: &str = "diagnostics";
+
+#[derive(Error, Debug)]
+pub enum DiagnosticsError {
+    #[error("Couldn't access the diagnostics directory: {0}")]
+    Io(#[source] io::Error),
+    #[error("Couldn't serialize the failure bundle: {0}")]
+    Json(#[source] serde_json::Error),
+}
+
+impl From<io::Error> for DiagnosticsError {
+    fn from(e: io::Error) -> Self {
+        DiagnosticsError::Io(e)
+    }
+}
+
+impl From<serde_json::Error> for DiagnosticsError {
+    fn from(e: serde_json::Error) -> Self {
+        DiagnosticsError::Json(e)
+    }
+}
+
+/// Everything, that's needed to reproduce a failure to parse a YouTube response,
+/// or to turn the response into a test fixture
+#[derive(Debug, Clone, Serialize)]
+pub struct FailureBundle {
+    /// Module, that couldn't parse the response
+    pub source: String,
+    /// Video id or channel id
+    pub id: String,
+    pub url: String,
+    pub request_body: Option<String>,
+    pub response: String,
+    pub error: String,
+    /// Path to the value, that couldn't be deserialized, like `continuationContents.liveChatContinuation`
+    pub error_path: Option<String>,
+    pub client_version: Option<String>,
+    /// Milliseconds since the Unix epoch
+    pub requested_at: u64,
+    /// Milliseconds since the Unix epoch
+    pub failed_at: u64,
+}
+
+/// Notification about a bundle, that has been saved to the diagnostics directory
+#[derive(Debug, Clone)]
+pub struct SavedBundle {
+    pub source: String,
+    pub id: String,
+    pub error: String,
+    pub path: PathBuf,
+}
+
+/// Directory with the failure bundles, one file per failure
+#[derive(Debug)]
+pub struct DiagnosticsStore {
+    dir: PathBuf,
+    max_size: u64,
+    events_tx: Option<UnboundedSender<SavedBundle>>,
+}
+
+impl DiagnosticsStore {
+    pub fn new(dir: PathBuf, max_size: u64) -> Result<Self, DiagnosticsError> {
+        fs::create_dir_all(&dir)?;
+        Ok(Self {
+            dir,
+            max_size,
+            events_tx: None,
+        })
+    }
+
+    /// Sends `SavedBundle` to `events_tx` after every saved bundle
+    pub fn with_events(mut self, events_tx: UnboundedSender<SavedBundle>) -> Self {
+        self.events_tx = Some(events_tx);
+        self
+    }
+
+    pub fn save(&self, bundle: &FailureBundle) -> Result<PathBuf, DiagnosticsError> {
+        // Names start with the time of the failure, so that the oldest bundles come first
+        let path = Path::new(&self.dir).join(format!(
+            "{:013}_{}_{}.json",
+            bundle.failed_at, &bundle.source, &bundle.id
+        ));
+        fs::write(&path, serde_json::to_vec_pretty(bundle)?)?;
+        self.rotate()?;
+
+        if let Some(events_tx) = &self.events_tx {
+            let event = SavedBundle {
+                source: bundle.source.clone(),
+                id: bundle.id.clone(),
+                error: bundle.error.clone(),
+                path: path.clone(),
+            };
+            // Nobody listens to the events after the app has started closing
+            let _ = events_tx.send(event);
+        }
+        Ok(path)
+    }
+
+    /// Removes the oldest bundles, until the total size fits into the limit
+    fn rotate(&self) -> Result<(), DiagnosticsError> {
+        let mut bundles = Vec::new();
+        for entry in fs::read_dir(&self.dir)? {
+            let entry = entry?;
+            let path = entry.path();
+            if path
+                .extension()
+                .is_some_and(|extension| extension == "json")
+            {
+                bundles.push((path, entry.metadata()?.len()));
+            }
+        }
+        bundles.sort();
+
+        let mut total_size: u64 = bundles.iter().map(|(_, size)| size).sum();
+        for (path, size) in bundles {
+            if total_size <= self.max_size {
+                break;
+            }
+            fs::remove_file(&path)?;
+            total_size -= size;
+        }
+        Ok(())
+    }
+}
+
+/// Saves the bundle, if the diagnostics directory is set, and logs the failure either way
+pub fn save_failure(diagnostics: Option<&DiagnosticsStore>, bundle: &FailureBundle) {
+    let diagnostics = match diagnostics {
+        Some(diagnostics) => diagnostics,
+        None => {
+            crate::tracing_error!(
+                "{}: Couldn't parse the response from {}: {}",
+                &bundle.id,
+                &bundle.url,
+                &bundle.error
+            );
+            return;
+        }
+    };
+
+    match diagnostics.save(bundle) {
+        Ok(path) => crate::tracing_error!(
+            "{}: Couldn't parse the response from {}: {}. Saved to {}",
+            &bundle.id,
+            &bundle.url,
+            &bundle.error,
+            path.display()
+        ),
+        Err(e) => crate::tracing_error!(
+            "{}: Couldn't parse the response from {}: {}. Couldn't save it: {}",
+            &bundle.id,
+            &bundle.url,
+            &bundle.error,
+            &e
+        ),
+    }
+}
+
+/// Path to the value in `json`, that can't be deserialized into `T`
+pub fn error_path<T: DeserializeOwned>(json: &str) -> Option<String> {
+    let deserializer = &mut serde_json::Deserializer::from_str(json);
+    match serde_path_to_error::deserialize::<_, T>(deserializer) {
+        Ok(_) => None,
+        Err(e) => Some(e.path().to_string()),
+    }
+}
+
+pub fn unix_time_ms(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::Deserialize;
+
+    use super::{error_path, DiagnosticsStore, FailureBundle};
+
+    #[derive(Deserialize)]
+    #[allow(dead_code)]
+    struct Outer {
+        inner: Vec<Inner>,
+    }
+
+    #[derive(Deserialize)]
+    #[allow(dead_code)]
+    struct Inner {
+        value: u64,
+    }
+
+    #[test]
+    fn saves_bundles_and_removes_the_oldest_ones() {
+        let json = r#"{ "inner": [{ "value": 1 }, { "value": "two" }] }"#;
+        assert_eq!(error_path::<Outer>(json).as_deref(), Some("inner[1].value"));
+
+        let dir = tempfile::tempdir().unwrap();
+        let bundle = |failed_at| FailureBundle {
+            source: "test".to_string(),
+            id: "id".to_string(),
+            url: "https://www.youtube.com".to_string(),
+            request_body: None,
+            response: json.to_string(),
+            error: "invalid type".to_string(),
+            error_path: error_path::<Outer>(json),
+            client_version: None,
+            requested_at: failed_at,
+            failed_at,
+        };
+
+        let (events_tx, mut events_rx) = tokio::sync::mpsc::unbounded_channel();
+        let first_path = DiagnosticsStore::new(dir.path().to_path_buf(), u64::MAX)
+            .unwrap()
+            .save(&bundle(1))
+            .unwrap();
+        let size = std::fs::metadata(&first_path).unwrap().len();
+
+        // Only one bundle fits into the limit
+        let store = DiagnosticsStore::new(dir.path().to_path_buf(), size)
+            .unwrap()
+            .with_events(events_tx);
+        let second_path = store.save(&bundle(2)).unwrap();
+        assert!(!first_path.exists());
+        assert!(second_path.exists());
+        assert_eq!(events_rx.try_recv().unwrap().path, second_path);
+    }
+}
@@ -0,0 +1,36 @@
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+/// Writes the `content` into a temporary file next to the `path` first, and then renames it,
+/// so that a crash in the middle of writing doesn't leave a broken file behind
+pub fn write_atomically(path: &Path, content: &[u8]) -> io::Result<()> {
+    let temp_path = temp_path(path);
+    fs::write(&temp_path, content)?;
+    fs::rename(&temp_path, path)
+}
+
+/// `<file name>.tmp`, so that files, that differ only by their extension, don't clash
+fn temp_path(path: &Path) -> PathBuf {
+    let mut temp_name = path.file_name().unwrap_or_default().to_os_string();
+    temp_name.push(".tmp");
+    path.with_file_name(temp_name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::write_atomically;
+
+    #[test]
+    fn replaces_the_file_without_leaving_the_temporary_one() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("store.json");
+
+        write_atomically(&path, b"first").unwrap();
+        write_atomically(&path, b"second").unwrap();
+
+        assert_eq!(std::fs::read(&path).unwrap(), b"second");
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+}
@@ -1,8 +1,11 @@
-use std::time::Duration;
+use std::{future::Future, time::Duration};
 
 use reqwest::{
-    header::{self, ACCEPT, ACCEPT_LANGUAGE, DNT, REFERER, UPGRADE_INSECURE_REQUESTS, USER_AGENT},
-    Client, Response,
+    header::{
+        self, ACCEPT, ACCEPT_LANGUAGE, DNT, REFERER, RETRY_AFTER, UPGRADE_INSECURE_REQUESTS,
+        USER_AGENT,
+    },
+    Client, Response, StatusCode,
 };
 use thiserror::Error;
 
@@ -26,10 +29,211 @@ pub enum HttpClientLoadError {
     PostRequest(#[source] reqwest::Error),
     #[error("Couldn't load response body: {0}")]
     ResponseBody(#[source] reqwest::Error),
-    #[error("Request had an error: {0}")]
-    ClientError(String),
-    #[error("Server has encountered an error: {0}")]
-    ServerError(String),
+    #[error("Too many requests, retry after {retry_after:?}: {body}")]
+    RateLimited {
+        retry_after: Option<Duration>,
+        body: String,
+    },
+    #[error("Request was forbidden: {body}")]
+    Forbidden { body: String },
+    #[error("Request had an error {status}: {body}")]
+    ClientError { status: u16, body: String },
+    #[error("Server has encountered an error {status}: {body}")]
+    ServerError {
+        status: u16,
+        retry_after: Option<Duration>,
+        body: String,
+    },
+}
+
+/// What kind of a failure the request has encountered
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HttpErrorClass {
+    /// Connection errors, timeouts and broken response bodies
+    Network,
+    /// 429
+    RateLimited,
+    /// 403. YouTube usually responds with it, when it thinks that the requests are made by a bot
+    Forbidden,
+    /// Any other 4xx
+    ClientError,
+    /// 5xx
+    ServerError,
+}
+
+impl HttpClientLoadError {
+    pub fn class(&self) -> HttpErrorClass {
+        match self {
+            HttpClientLoadError::GetRequest(_)
+            | HttpClientLoadError::PostRequest(_)
+            | HttpClientLoadError::ResponseBody(_) => HttpErrorClass::Network,
+            HttpClientLoadError::RateLimited { .. } => HttpErrorClass::RateLimited,
+            HttpClientLoadError::Forbidden { .. } => HttpErrorClass::Forbidden,
+            HttpClientLoadError::ClientError { .. } => HttpErrorClass::ClientError,
+            HttpClientLoadError::ServerError { .. } => HttpErrorClass::ServerError,
+        }
+    }
+
+    pub fn status(&self) -> Option<u16> {
+        match self {
+            HttpClientLoadError::GetRequest(e)
+            | HttpClientLoadError::PostRequest(e)
+            | HttpClientLoadError::ResponseBody(e) => e.status().map(|status| status.as_u16()),
+            HttpClientLoadError::RateLimited { .. } => Some(StatusCode::TOO_MANY_REQUESTS.as_u16()),
+            HttpClientLoadError::Forbidden { .. } => Some(StatusCode::FORBIDDEN.as_u16()),
+            HttpClientLoadError::ClientError { status, .. }
+            | HttpClientLoadError::ServerError { status, .. } => Some(*status),
+        }
+    }
+
+    /// How long the server asked to wait before the next request
+    pub fn retry_after(&self) -> Option<Duration> {
+        match self {
+            HttpClientLoadError::RateLimited { retry_after, .. }
+            | HttpClientLoadError::ServerError { retry_after, .. } => *retry_after,
+            _ => None,
+        }
+    }
+
+    /// Repeating a forbidden or a malformed request won't help, and may even make things worse
+    pub fn is_retryable(&self) -> bool {
+        matches!(
+            self.class(),
+            HttpErrorClass::Network | HttpErrorClass::RateLimited | HttpErrorClass::ServerError
+        )
+    }
+}
+
+/// Exponential backoff with jitter for failed requests
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    /// How many times a failed request is repeated, before giving up
+    pub max_retries: u32,
+    pub initial_delay: Duration,
+    /// Upper bound of the delay, including the one asked by the server with `Retry-After`
+    pub max_delay: Duration,
+    /// Multipliers below 1 are treated as 1
+    pub multiplier: f64,
+    /// Part of the delay, that is randomized: 0.2 means ±20%. Clamped to `0.0..=1.0`.
+    pub jitter: f64,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_retries: 5,
+            initial_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(30),
+            multiplier: 2.0,
+            jitter: 0.2,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Delay before the `retry`-th retry, starting from 1,
+    /// or `None`, if the request shouldn't be retried anymore
+    pub fn delay(&self, retry: u32, error: &HttpClientLoadError) -> Option<Duration> {
+        if !error.is_retryable() {
+            return None;
+        }
+
+        let delay = self.backoff(retry)?;
+        match error.retry_after() {
+            // A broken or a hostile server shouldn't be able to stop the requests for days
+            Some(retry_after) => Some(delay.max(retry_after.min(self.max_delay))),
+            None => Some(delay),
+        }
+    }
+
+    /// Delay before the `retry`-th attempt of anything, that isn't a single request
+    pub fn backoff(&self, retry: u32) -> Option<Duration> {
+        if retry == 0 || retry > self.max_retries {
+            return None;
+        }
+
+        // Negative or NaN values would make the delay negative or NaN, which can't be a `Duration`
+        let multiplier = if self.multiplier.is_nan() {
+            1.0
+        } else {
+            self.multiplier.max(1.0)
+        };
+        let jitter = if self.jitter.is_nan() {
+            0.0
+        } else {
+            self.jitter.clamp(0.0, 1.0)
+        };
+
+        let backoff = self.initial_delay.as_secs_f64() * multiplier.powi(retry as i32 - 1);
+        let backoff = backoff.min(self.max_delay.as_secs_f64());
+        let factor = 1.0 - jitter + 2.0 * jitter * fastrand::f64();
+        Some(Duration::from_secs_f64(backoff * factor))
+    }
+
+    /// Starts counting the retries of a request, for the callers,
+    /// that have to handle something else, while waiting for the next attempt.
+    /// `context` is used to tell apart the requests in logs.
+    pub fn attempts(&self, context: &str) -> RetryAttempts {
+        RetryAttempts {
+            policy: self.clone(),
+            context: context.to_string(),
+            retry: 0,
+        }
+    }
+
+    /// Performs the request, retrying it according to the policy.
+    /// `context` is used to tell apart the requests in logs.
+    pub async fn retry<T, F, Fut>(
+        &self,
+        context: &str,
+        mut request: F,
+    ) -> Result<T, HttpClientLoadError>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, HttpClientLoadError>>,
+    {
+        let mut attempts = self.attempts(context);
+        loop {
+            let error = match request().await {
+                Ok(response) => return Ok(response),
+                Err(e) => e,
+            };
+
+            tokio::time::sleep(attempts.next_delay(error)?).await;
+        }
+    }
+}
+
+/// Retries of a single request
+pub struct RetryAttempts {
+    policy: RetryPolicy,
+    context: String,
+    retry: u32,
+}
+
+impl RetryAttempts {
+    /// Delay before the next attempt after the `error`,
+    /// or the `error` itself, if the request shouldn't be retried anymore
+    pub fn next_delay(
+        &mut self,
+        error: HttpClientLoadError,
+    ) -> Result<Duration, HttpClientLoadError> {
+        self.retry += 1;
+        let delay = match self.policy.delay(self.retry, &error) {
+            Some(delay) => delay,
+            None => return Err(error),
+        };
+
+        crate::tracing_warn!(
+            "{}: Request failed ({:?}). Retry #{} in {:?}. Error: {}",
+            &self.context,
+            error.class(),
+            self.retry,
+            delay,
+            &error
+        );
+        Ok(delay)
+    }
 }
 
 #[derive(Clone)]
@@ -37,6 +241,7 @@ pub struct RequestSettings {
     pub user_agent: String,
     pub browser_name: String,
     pub browser_version: String,
+    pub retry_policy: RetryPolicy,
 }
 
 pub struct HttpClient {
@@ -113,17 +318,121 @@ impl HttpClient {
 
     async fn extract_response(response: Response) -> Result<String, HttpClientLoadError> {
         let status_code = response.status();
+        let retry_after = response
+            .headers()
+            .get(RETRY_AFTER)
+            .and_then(|value| value.to_str().ok())
+            .and_then(HttpClient::parse_retry_after);
         let body = response
             .text()
             .await
             .map_err(HttpClientLoadError::ResponseBody)?;
 
-        if status_code.is_client_error() {
-            Err(HttpClientLoadError::ClientError(body))
+        if status_code == StatusCode::TOO_MANY_REQUESTS {
+            Err(HttpClientLoadError::RateLimited { retry_after, body })
+        } else if status_code == StatusCode::FORBIDDEN {
+            Err(HttpClientLoadError::Forbidden { body })
+        } else if status_code.is_client_error() {
+            Err(HttpClientLoadError::ClientError {
+                status: status_code.as_u16(),
+                body,
+            })
         } else if status_code.is_server_error() {
-            Err(HttpClientLoadError::ServerError(body))
+            Err(HttpClientLoadError::ServerError {
+                status: status_code.as_u16(),
+                retry_after,
+                body,
+            })
         } else {
             Ok(body)
         }
     }
+
+    /// `Retry-After` is either a number of seconds, or an HTTP date
+    fn parse_retry_after(value: &str) -> Option<Duration> {
+        let value = value.trim();
+        if let Ok(seconds) = value.parse::<u64>() {
+            return Some(Duration::from_secs(seconds));
+        }
+
+        let date = chrono::DateTime::parse_from_rfc2822(value).ok()?;
+        let delay = date
+            .signed_duration_since(chrono::Utc::now())
+            .to_std()
+            .unwrap_or_default();
+        Some(delay)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{HttpClient, HttpClientLoadError, RetryPolicy};
+
+    #[test]
+    fn retry_policy_backs_off_only_retryable_errors() {
+        let policy = RetryPolicy {
+            max_retries: 3,
+            initial_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(3),
+            multiplier: 2.0,
+            jitter: 0.5,
+        };
+        let server_error = HttpClientLoadError::ServerError {
+            status: 503,
+            retry_after: None,
+            body: String::new(),
+        };
+
+        let first = policy.delay(1, &server_error).unwrap();
+        assert!(first >= Duration::from_millis(500) && first <= Duration::from_millis(1500));
+        let third = policy.delay(3, &server_error).unwrap();
+        assert!(third >= Duration::from_millis(1500) && third <= Duration::from_millis(4500));
+        assert!(policy.delay(4, &server_error).is_none());
+
+        let rate_limited = HttpClientLoadError::RateLimited {
+            retry_after: Some(Duration::from_secs(2)),
+            body: String::new(),
+        };
+        assert_eq!(policy.delay(1, &rate_limited), Some(Duration::from_secs(2)));
+        let rate_limited_for_a_day = HttpClientLoadError::RateLimited {
+            retry_after: Some(Duration::from_secs(86_400)),
+            body: String::new(),
+        };
+        assert_eq!(
+            policy.delay(1, &rate_limited_for_a_day),
+            Some(Duration::from_secs(3))
+        );
+
+        let forbidden = HttpClientLoadError::Forbidden {
+            body: String::new(),
+        };
+        assert_eq!(forbidden.status(), Some(403));
+        assert!(policy.delay(1, &forbidden).is_none());
+
+        assert_eq!(
+            HttpClient::parse_retry_after("120"),
+            Some(Duration::from_secs(120))
+        );
+        assert_eq!(
+            HttpClient::parse_retry_after("Wed, 21 Oct 2015 07:28:00 GMT"),
+            Some(Duration::default())
+        );
+    }
+
+    #[test]
+    fn retry_policy_ignores_invalid_multiplier_and_jitter() {
+        for (multiplier, jitter) in [(-2.0, -1.0), (f64::NAN, f64::NAN), (0.5, 5.0)] {
+            let policy = RetryPolicy {
+                multiplier,
+                jitter,
+                ..RetryPolicy::default()
+            };
+            for retry in 1..=policy.max_retries {
+                let backoff = policy.backoff(retry).unwrap();
+                assert!(backoff <= policy.max_delay * 2);
+            }
+        }
+    }
 }
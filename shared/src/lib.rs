@@ -10,7 +10,10 @@ pub use tracing::error as tracing_error;
 pub use tracing::info as tracing_info;
 pub use tracing::warn as tracing_warn;
 
+pub mod currency;
 pub mod detector_params;
+pub mod diagnostics;
+pub mod fs;
 pub mod http_client;
 pub mod messages;
 pub mod types;
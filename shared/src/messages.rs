@@ -1,5 +1,7 @@
 pub mod stream_finder {
-    use std::collections::HashSet;
+    use std::{collections::HashSet, sync::Arc};
+
+    use crate::diagnostics::DiagnosticsStore;
 
     #[derive(Debug, Clone)]
     pub enum IncMessage {
@@ -9,7 +11,12 @@ pub mod stream_finder {
         UpdatePollInterval(u64),
         UpdateUserAgent(String),
         UpdateBrowserVersion(String),
-        UpdateBrowserNameAndVersion { name: String, version: String },
+        UpdateBrowserNameAndVersion {
+            name: String,
+            version: String,
+        },
+        /// Where to save the responses, that couldn't be parsed
+        SetDiagnostics(Option<Arc<DiagnosticsStore>>),
     }
 
     #[derive(Debug)]
@@ -20,7 +27,9 @@ pub mod stream_finder {
 }
 
 pub mod chat_poller {
-    use crate::types::Action;
+    use std::sync::Arc;
+
+    use crate::{diagnostics::DiagnosticsStore, types::Action};
 
     #[derive(Debug, Clone)]
     pub enum IncMessage {
@@ -28,7 +37,23 @@ pub mod chat_poller {
         Ping,
         UpdateUserAgent(String),
         UpdateBrowserVersion(String),
-        UpdateBrowserNameAndVersion { name: String, version: String },
+        UpdateBrowserNameAndVersion {
+            name: String,
+            version: String,
+        },
+        /// Where to save the responses, that couldn't be parsed
+        SetDiagnostics(Option<Arc<DiagnosticsStore>>),
+    }
+
+    /// What kind of poller has failed, and where its restarted poller should continue from
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    pub enum PollerKind {
+        Live,
+        /// Restarted replay poller skips everything up to the last offset, that was sent further,
+        /// as the replay has no checkpoints
+        Replay {
+            last_offset_ms: Option<u64>,
+        },
     }
 
     #[derive(Debug, Clone)]
@@ -42,15 +67,29 @@ pub mod chat_poller {
             video_id: String,
             actions: Vec<Action>,
         },
+        /// The stream has ended, or the chat was closed
         StreamEnded {
             channel: String,
             video_id: String,
         },
+        /// The poller has stopped because of an error, while the stream may still be going on
+        PollerFailed {
+            channel: String,
+            video_id: String,
+            error: String,
+            /// Id of the task of the poller, that tells it apart from the restarted ones
+            poller: tokio::task::Id,
+            kind: PollerKind,
+        },
     }
 }
 
 pub mod chat_manager {
-    use std::collections::HashSet;
+    use std::{collections::HashSet, path::PathBuf, sync::Arc};
+
+    use crate::diagnostics::DiagnosticsStore;
+
+    use super::chat_poller::PollerKind;
 
     #[derive(Debug, Clone)]
     pub enum IncMessage {
@@ -65,11 +104,37 @@ pub mod chat_manager {
             name: String,
             version: String,
         },
+        /// Directory for checkpoints of the chat pollers, or `None` to stop checkpointing
+        SetCheckpointDir(Option<PathBuf>),
+        /// Starts loading the chat replay of a finished stream or premiere
+        AddReplay {
+            channel: String,
+            video_id: String,
+        },
+        /// Where the chat pollers save the responses, that couldn't be parsed
+        SetDiagnostics(Option<Arc<DiagnosticsStore>>),
+    }
+
+    /// Poller, that has stopped because of an error, and should be restarted.
+    /// Failures have their own unbounded channel, so reporting one never waits for the manager.
+    #[derive(Debug, Clone)]
+    pub struct PollerFailure {
+        pub channel: String,
+        pub video_id: String,
+        /// Id of the task of the failed poller
+        pub poller: tokio::task::Id,
+        pub kind: PollerKind,
     }
 }
 
 pub mod detector {
-    use crate::detector_params::DetectorParams;
+    use std::{path::PathBuf, time::Duration};
+
+    use crate::{
+        detector_params::{CrossStreamParams, DetectorParams, ScriptParams},
+        diagnostics::SavedBundle,
+        types::{IdEntry, MessageContent, Timestamp},
+    };
 
     use super::chat_poller;
 
@@ -81,6 +146,25 @@ pub mod detector {
             channel: String,
             params: DetectorParams,
         },
+        UpdateCrossStreamParams(CrossStreamParams),
+        LoadReputation(PathBuf),
+        AddToWatchlist {
+            channel_id: String,
+            reason: String,
+        },
+        RemoveFromWatchlist(String),
+        GetDeletedMessages {
+            video_id: String,
+        },
+        GetMemoryUsage,
+        UpdateScript {
+            channel: String,
+            script: Option<ScriptParams>,
+        },
+        UpdateShadowParams {
+            channel: String,
+            params: Option<DetectorParams>,
+        },
     }
 
     #[derive(Debug)]
@@ -97,39 +181,137 @@ pub mod detector {
             video_id: String,
             processed_messages: usize,
             decisions: Vec<DetectorDecision>,
+            latency: ProcessingLatency,
+        },
+        /// A user from the watchlist has posted a message
+        WatchlistAlert {
+            channel: String,
+            video_id: String,
+            id: IdEntry,
+            message: Box<MessageContent>,
+            reason: String,
         },
+        DeletedMessages {
+            video_id: String,
+            messages: Vec<DeletedMessage>,
+        },
+        MemoryUsage {
+            video_id: String,
+            usage: MemoryUsage,
+        },
+        /// The stream has hit the hard cap of `MemoryParams` and started evicting its state
+        MemoryLimitReached {
+            video_id: String,
+            usage: MemoryUsage,
+        },
+        /// Decisions, made with the candidate params of the channel.
+        /// They are only for evaluating the params, and must never be acted upon.
+        ShadowResult {
+            video_id: String,
+            decisions: Vec<DetectorDecision>,
+            stats: ShadowStats,
+        },
+        /// Response, that couldn't be parsed, has been saved to the diagnostics directory
+        DiagnosticsSaved(SavedBundle),
+    }
+
+    /// Comparison of the users, flagged in the stream by the production and the candidate params.
+    /// A user is flagged, if the latest decision about them isn't `Clear` or `Blocked`.
+    #[derive(Debug, Clone, Default)]
+    pub struct ShadowStats {
+        pub flagged_by_both: usize,
+        pub flagged_only_by_production: usize,
+        pub flagged_only_by_shadow: usize,
+    }
+
+    impl ShadowStats {
+        /// Share of the flagged users, that both params agree on.
+        /// It's 1 if no one was flagged yet.
+        pub fn agreement(&self) -> f32 {
+            let flagged = self.flagged_by_both
+                + self.flagged_only_by_production
+                + self.flagged_only_by_shadow;
+            if flagged == 0 {
+                1.0
+            } else {
+                self.flagged_by_both as f32 / flagged as f32
+            }
+        }
+    }
+
+    /// How long it took the detector of a stream to produce a result
+    #[derive(Debug, Clone, Default)]
+    pub struct ProcessingLatency {
+        /// Time spent waiting in the queue of the stream
+        pub queued: Duration,
+        pub processing: Duration,
+    }
+
+    /// State, that a stream keeps in memory
+    #[derive(Debug, Clone, Default)]
+    pub struct MemoryUsage {
+        pub messages: usize,
+        pub deleted_messages: usize,
+        pub users: usize,
+        /// Rough estimate of the heap memory used by the state above
+        pub approximate_bytes: usize,
+    }
+
+    /// Original content of a message, that was deleted from the chat
+    #[derive(Debug, Clone)]
+    pub struct DeletedMessage {
+        pub id: String,
+        pub author: String,
+        pub text: String,
+        /// When the message was sent
+        pub timestamp: Timestamp,
+        /// When the deletion was received
+        pub deleted_at: Timestamp,
+        /// The latest decision about the author in this stream at the moment of the deletion,
+        /// including the one that was caused by this deletion
+        pub related_decision: Option<Decision>,
     }
 
     #[derive(Debug)]
     pub struct DetectorDecision {
         pub channel: String,
-        pub timestamp: i64,
+        /// Time of the action, that has led to the decision
+        pub timestamp: Timestamp,
         pub decision: Decision,
     }
 
     impl DetectorDecision {
-        pub fn new(channel: String, decision: Decision) -> Self {
+        pub fn new(channel: String, timestamp: Timestamp, decision: Decision) -> Self {
             Self {
                 channel,
-                timestamp: chrono::Utc::now().timestamp(),
+                timestamp,
                 decision,
             }
         }
     }
 
-    #[derive(Debug)]
+    #[derive(Debug, Clone)]
     pub enum Decision {
         TooFast(f32),
         TooLong(f32),
         TooManyDeleted,
         Similar,
+        EmojiFlood(f32),
+        RepeatedCharacters(usize),
+        TooManyCaps(f32),
+        Zalgo(f32),
+        CrossStreamSpam { streams: Vec<String> },
+        BadReputation { channels: Vec<String> },
+        Script { reason: String },
         Blocked,
         Clear,
     }
 }
 
 pub mod alkonost {
-    use crate::detector_params::DetectorParams;
+    use std::path::PathBuf;
+
+    use crate::detector_params::{CrossStreamParams, DetectorParams, ScriptParams};
 
     #[derive(Debug)]
     pub enum IncMessage {
@@ -147,5 +329,41 @@ pub mod alkonost {
             channel: String,
             new_params: DetectorParams,
         },
+        UpdateCrossStreamParams(CrossStreamParams),
+        LoadReputation(PathBuf),
+        /// Adds the user with the `channel_id` to the watchlist, or updates the reason
+        AddToWatchlist {
+            channel_id: String,
+            reason: String,
+        },
+        RemoveFromWatchlist(String),
+        /// Requests the original content of all messages, that were deleted from the stream
+        GetDeletedMessages {
+            video_id: String,
+        },
+        /// Requests the memory usage of every active stream
+        GetMemoryUsage,
+        /// Replaces the detection script of the channel, or removes it if `script` is `None`
+        UpdateScript {
+            channel: String,
+            script: Option<ScriptParams>,
+        },
+        /// Starts running candidate params in shadow mode next to the params of the channel,
+        /// or stops it if `params` is `None`
+        UpdateShadowParams {
+            channel: String,
+            params: Option<DetectorParams>,
+        },
+        /// Saves the state of every chat poller to the directory, so that polling can be resumed
+        /// after a restart without losing messages. `None` stops checkpointing.
+        SetChatCheckpointDir(Option<PathBuf>),
+        /// Loads the chat replay of a finished stream or premiere and runs it through the detector
+        AddReplay {
+            channel: String,
+            video_id: String,
+        },
+        /// Directory, where the responses, that couldn't be parsed, are saved along with the requests
+        /// and errors. `diagnostics` in the working directory is used by default, `None` stops saving them.
+        SetDiagnosticsDir(Option<PathBuf>),
     }
 }
@@ -1,6 +1,231 @@
+use unicode_normalization::UnicodeNormalization;
+use unicode_segmentation::UnicodeSegmentation;
 use vec1::Vec1;
 
-pub type RichText = String;
+use crate::currency::PurchaseAmount;
+
+#[derive(Debug, Clone)]
+pub struct Emoji {
+    pub emoji_id: String,
+    pub shortcuts: Vec<String>,
+    pub image_url: Option<String>,
+    pub is_custom: bool,
+}
+
+impl Emoji {
+    /// Token that represents the emoji in a plain text: its first shortcut, or its id,
+    /// if the emoji doesn't have any shortcuts
+    pub fn token(&self) -> &str {
+        self.shortcuts.first().unwrap_or(&self.emoji_id)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum RichTextSegment {
+    Text {
+        text: String,
+        bold: bool,
+        italics: bool,
+    },
+    Link {
+        text: String,
+        url: String,
+    },
+    Emoji(Emoji),
+}
+
+/// Text of a chat message, a user name or any other text from YouTube,
+/// that keeps formatting, links and emojis.
+#[derive(Debug, Clone, Default)]
+pub struct RichText {
+    pub segments: Vec<RichTextSegment>,
+}
+
+impl RichText {
+    pub fn to_html(&self) -> String {
+        self.segments
+            .iter()
+            .map(|segment| match segment {
+                RichTextSegment::Text {
+                    text,
+                    bold,
+                    italics,
+                } => {
+                    let mut result = html_escape::encode_text(text).into_owned();
+                    if *bold {
+                        result = format!("<strong>{}</strong>", result);
+                    }
+                    if *italics {
+                        result = format!("<em>{}</em>", result);
+                    }
+                    result
+                }
+                RichTextSegment::Link { text, url } => format!(
+                    r#"<a href="{}" target="_blank">{}</a>"#,
+                    html_escape::encode_double_quoted_attribute(url),
+                    html_escape::encode_text(text)
+                ),
+                RichTextSegment::Emoji(emoji) => match (&emoji.image_url, emoji.is_custom) {
+                    (Some(url), true) => format!(
+                        r#"<img src="{}" alt="{}">"#,
+                        html_escape::encode_double_quoted_attribute(url),
+                        html_escape::encode_double_quoted_attribute(emoji.token())
+                    ),
+                    _ => html_escape::encode_text(&emoji.emoji_id).into_owned(),
+                },
+            })
+            .collect::<Vec<String>>()
+            .join("")
+    }
+
+    pub fn to_plain_text(&self) -> PlainText {
+        let mut builder = PlainTextBuilder::default();
+        for segment in &self.segments {
+            match segment {
+                RichTextSegment::Text { text, .. } | RichTextSegment::Link { text, .. } => {
+                    builder.push_text(text)
+                }
+                RichTextSegment::Emoji(emoji) => builder.push_emoji(emoji.token()),
+            }
+        }
+        builder.build()
+    }
+}
+
+/// Normalized plain-text form of a message, that is used for spam detection instead of `RichText`.
+///
+/// Text is normalized using NFKC, so that stylized letters and full-width characters
+/// are treated the same as regular ones. Lengths are counted in grapheme clusters,
+/// and every emoji is represented by a single token, that counts as one grapheme.
+#[derive(Debug, Clone, Default)]
+pub struct PlainText {
+    text: String,
+    graphemes: usize,
+    emojis: usize,
+    letters: usize,
+    uppercase_letters: usize,
+    combining_marks: usize,
+    longest_repeated_run: usize,
+}
+
+impl PlainText {
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    pub fn graphemes(&self) -> usize {
+        self.graphemes
+    }
+
+    pub fn emojis(&self) -> usize {
+        self.emojis
+    }
+
+    pub fn letters(&self) -> usize {
+        self.letters
+    }
+
+    /// Share of emojis among all graphemes
+    pub fn emoji_ratio(&self) -> f32 {
+        PlainText::ratio(self.emojis, self.graphemes)
+    }
+
+    /// Share of uppercase letters among all letters
+    pub fn caps_ratio(&self) -> f32 {
+        PlainText::ratio(self.uppercase_letters, self.letters)
+    }
+
+    /// Average amount of combining marks per grapheme. Regular text rarely has more than 1,
+    /// while "zalgo" text stacks dozens of them on top of each letter.
+    pub fn combining_marks_density(&self) -> f32 {
+        PlainText::ratio(self.combining_marks, self.graphemes)
+    }
+
+    /// Length of the longest run of the same grapheme or emoji, repeated without any whitespaces between them
+    pub fn longest_repeated_run(&self) -> usize {
+        self.longest_repeated_run
+    }
+
+    fn ratio(count: usize, total: usize) -> f32 {
+        if total == 0 {
+            0.0
+        } else {
+            count as f32 / total as f32
+        }
+    }
+}
+
+impl From<&str> for PlainText {
+    fn from(text: &str) -> Self {
+        let mut builder = PlainTextBuilder::default();
+        builder.push_text(text);
+        builder.build()
+    }
+}
+
+/// Builds `PlainText` segment by segment, keeping track of the current run of repeated graphemes,
+/// that may continue from one segment to the next one
+#[derive(Default)]
+pub struct PlainTextBuilder {
+    plain_text: PlainText,
+    current_run: usize,
+    last_grapheme: String,
+}
+
+impl PlainTextBuilder {
+    pub fn push_text(&mut self, text: &str) {
+        // NFKC composes some of the combining marks with the preceding letters,
+        // so they are counted before the normalization
+        self.plain_text.combining_marks += text
+            .chars()
+            .filter(|c| unicode_normalization::char::is_combining_mark(*c))
+            .count();
+
+        let normalized = text.nfkc().collect::<String>();
+        for grapheme in normalized.graphemes(true) {
+            self.plain_text.graphemes += 1;
+            for c in grapheme.chars() {
+                if !c.is_alphabetic() || unicode_normalization::char::is_combining_mark(c) {
+                    continue;
+                }
+                self.plain_text.letters += 1;
+                if c.is_uppercase() {
+                    self.plain_text.uppercase_letters += 1;
+                }
+            }
+
+            if grapheme.trim().is_empty() {
+                self.current_run = 0;
+                self.last_grapheme.clear();
+            } else {
+                self.extend_run(grapheme);
+            }
+        }
+        self.plain_text.text.push_str(&normalized);
+    }
+
+    pub fn push_emoji(&mut self, token: &str) {
+        self.plain_text.graphemes += 1;
+        self.plain_text.emojis += 1;
+        self.extend_run(token);
+        self.plain_text.text.push_str(token);
+    }
+
+    pub fn build(self) -> PlainText {
+        self.plain_text
+    }
+
+    fn extend_run(&mut self, grapheme: &str) {
+        if self.current_run > 0 && self.last_grapheme == grapheme {
+            self.current_run += 1;
+        } else {
+            self.current_run = 1;
+            self.last_grapheme = grapheme.to_string();
+        }
+        self.plain_text.longest_repeated_run =
+            self.plain_text.longest_repeated_run.max(self.current_run);
+    }
+}
 
 #[derive(Debug, Clone)]
 pub enum UserBadges {
@@ -17,7 +242,9 @@ pub struct User {
     pub badges: Option<Vec1<UserBadges>>,
 }
 
-#[derive(Debug, Clone)]
+/// Modes are ordered by priority: if several active modes override the same threshold,
+/// the one, that comes later, wins
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub enum ChatMode {
     SubscribersOnly,
     SlowMode,
@@ -42,6 +269,7 @@ pub enum MessageContent {
     SimpleMessage {
         author: User,
         message: RichText,
+        plain_text: PlainText,
     },
     Membership {
         author: User,
@@ -51,17 +279,34 @@ pub enum MessageContent {
         author: User,
         message: Option<RichText>,
         amount: RichText,
+        /// `None`, if the format of the amount is unknown
+        parsed_amount: Option<PurchaseAmount>,
     },
     Sticker {
         author: User,
         sticker_name: String,
         purchase_amount: RichText,
+        /// `None`, if the format of the amount is unknown
+        parsed_amount: Option<PurchaseAmount>,
     },
     Fundraiser {
         author: Option<User>,
         text: RichText,
         subtext: RichText,
     },
+    /// Someone bought memberships for other viewers of the chat
+    GiftPurchase {
+        gifter: User,
+        count: u32,
+        /// Name of the membership, usually the name of the channel
+        tier: Option<String>,
+    },
+    /// One of the gifted memberships went to this viewer
+    GiftRedemption {
+        recipient: User,
+        /// Name of the gifter, as YouTube doesn't provide their channel id here
+        gifter: Option<String>,
+    },
     ChatMode {
         text: RichText,
         subtext: RichText,
@@ -72,10 +317,47 @@ pub enum MessageContent {
     },
 }
 
+impl MessageContent {
+    pub fn author(&self) -> Option<&User> {
+        match self {
+            MessageContent::SimpleMessage { author, .. }
+            | MessageContent::Membership { author, .. }
+            | MessageContent::Superchat { author, .. }
+            | MessageContent::Sticker { author, .. }
+            | MessageContent::GiftPurchase { gifter: author, .. }
+            | MessageContent::GiftRedemption {
+                recipient: author, ..
+            } => Some(author),
+            MessageContent::Fundraiser { author, .. } => author.as_ref(),
+            MessageContent::ChatMode { .. } | MessageContent::PollResult { .. } => None,
+        }
+    }
+}
+
+/// Time of a chat action in microseconds since the Unix epoch
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Timestamp(u64);
+
+impl Timestamp {
+    pub fn from_micros(micros: u64) -> Self {
+        Self(micros)
+    }
+
+    pub fn now() -> Self {
+        Self(chrono::Utc::now().timestamp_micros().max(0) as u64)
+    }
+
+    pub fn as_micros(self) -> u64 {
+        self.0
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct IdEntry {
     pub id: String,
-    pub timepstamp: u64,
+    pub timestamp: Timestamp,
+    /// Offset from the start of the video, for the messages of a chat replay
+    pub video_offset_ms: Option<u64>,
 }
 
 #[derive(Debug, Clone)]
@@ -97,6 +379,7 @@ pub enum Action {
     },
     DeleteMessage {
         target_id: String,
+        received_at: Timestamp,
     },
     ReplaceMessage {
         target_id: String,
@@ -105,19 +388,23 @@ pub enum Action {
     },
     BlockUser {
         channel_id: String,
+        received_at: Timestamp,
     },
     CloseBanner {
         banner_id: String,
+        received_at: Timestamp,
     },
     StartPoll {
         id: String,
         question: RichText,
-        choices: Vec<String>,
+        choices: Vec<RichText>,
         poll_type: PollType,
+        received_at: Timestamp,
     },
     FinishPoll {
         id: String,
         choices: Vec<PollResult>,
+        received_at: Timestamp,
     },
     ChannelNotice {
         id: IdEntry,
@@ -128,8 +415,135 @@ pub enum Action {
         raised: RichText,
         title: String,
         goal_label: String,
+        received_at: Timestamp,
     },
     ClosePanel {
         target_id: String,
+        received_at: Timestamp,
+    },
+    /// Action, that couldn't be parsed, with its raw JSON and the reason
+    Unknown {
+        raw: String,
+        reason: String,
+        received_at: Timestamp,
     },
 }
+
+impl Action {
+    /// Id and time of messages and notices
+    pub fn id_entry(&self) -> Option<&IdEntry> {
+        match self {
+            Action::NewMessage { id, .. }
+            | Action::ReplaceMessage { new_id: id, .. }
+            | Action::ChannelNotice { id, .. } => Some(id),
+            _ => None,
+        }
+    }
+
+    pub fn id_entry_mut(&mut self) -> Option<&mut IdEntry> {
+        match self {
+            Action::NewMessage { id, .. }
+            | Action::ReplaceMessage { new_id: id, .. }
+            | Action::ChannelNotice { id, .. } => Some(id),
+            _ => None,
+        }
+    }
+
+    /// Time of the message for messages and notices.
+    /// Other actions don't have their own time, so they're stamped with the time, when they were received.
+    pub fn timestamp(&self) -> Timestamp {
+        match self {
+            Action::NewMessage { id, .. }
+            | Action::ReplaceMessage { new_id: id, .. }
+            | Action::ChannelNotice { id, .. } => id.timestamp,
+            Action::DeleteMessage { received_at, .. }
+            | Action::BlockUser { received_at, .. }
+            | Action::CloseBanner { received_at, .. }
+            | Action::StartPoll { received_at, .. }
+            | Action::FinishPoll { received_at, .. }
+            | Action::FundraiserProgress { received_at, .. }
+            | Action::ClosePanel { received_at, .. }
+            | Action::Unknown { received_at, .. } => *received_at,
+        }
+    }
+
+    /// Time of receiving for actions, that don't have their own time
+    pub fn received_at_mut(&mut self) -> Option<&mut Timestamp> {
+        match self {
+            Action::NewMessage { .. }
+            | Action::ReplaceMessage { .. }
+            | Action::ChannelNotice { .. } => None,
+            Action::DeleteMessage { received_at, .. }
+            | Action::BlockUser { received_at, .. }
+            | Action::CloseBanner { received_at, .. }
+            | Action::StartPoll { received_at, .. }
+            | Action::FinishPoll { received_at, .. }
+            | Action::FundraiserProgress { received_at, .. }
+            | Action::ClosePanel { received_at, .. }
+            | Action::Unknown { received_at, .. } => Some(received_at),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Emoji, PlainTextBuilder, RichText, RichTextSegment};
+
+    #[test]
+    fn plain_text_counts_graphemes_and_emojis() {
+        let mut builder = PlainTextBuilder::default();
+        builder.push_text("ｈｅｌｌｏ ");
+        builder.push_text("né");
+        builder.push_emoji(":_custom:");
+        let plain_text = builder.build();
+
+        assert_eq!(plain_text.text(), "hello né:_custom:");
+        assert_eq!(plain_text.graphemes(), 9);
+        assert_eq!(plain_text.emojis(), 1);
+    }
+
+    #[test]
+    fn plain_text_flood_metrics() {
+        let mut builder = PlainTextBuilder::default();
+        builder.push_text("WOW!!!!! a\u{0301}\u{0302}");
+        builder.push_emoji(":_pog:");
+        builder.push_emoji(":_pog:");
+        let plain_text = builder.build();
+
+        assert_eq!(plain_text.longest_repeated_run(), 5);
+        assert_eq!(plain_text.letters(), 4);
+        assert_eq!(plain_text.caps_ratio(), 0.75);
+        assert_eq!(plain_text.emoji_ratio(), 2.0 / 12.0);
+        // Both marks are counted, even though NFKC composes the first one into "á"
+        assert_eq!(plain_text.combining_marks_density(), 2.0 / 12.0);
+    }
+
+    #[test]
+    fn rich_text_renders_html_and_plain_text() {
+        let rich_text = RichText {
+            segments: vec![
+                RichTextSegment::Text {
+                    text: "<hi>".to_string(),
+                    bold: true,
+                    italics: false,
+                },
+                RichTextSegment::Link {
+                    text: "link".to_string(),
+                    url: "https://youtu.be/id".to_string(),
+                },
+                RichTextSegment::Emoji(Emoji {
+                    emoji_id: "UC/emoji".to_string(),
+                    shortcuts: vec![":_pog:".to_string()],
+                    image_url: Some("https://yt3.ggpht.com/pog".to_string()),
+                    is_custom: true,
+                }),
+            ],
+        };
+
+        assert_eq!(
+            rich_text.to_html(),
+            r#"<strong>&lt;hi&gt;</strong><a href="https://youtu.be/id" target="_blank">link</a><img src="https://yt3.ggpht.com/pog" alt=":_pog:">"#
+        );
+        assert_eq!(rich_text.to_plain_text().text(), "<hi>link:_pog:");
+    }
+}
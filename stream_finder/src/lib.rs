@@ -1,10 +1,15 @@
 #![allow(proc_macro_derive_resolution_fallback, unused_attributes)]
 
-use std::{collections::HashMap, fs::File, io::Write, sync::Arc, time::Duration};
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
 
 use error::StreamFinderError;
 use futures::{stream::FuturesUnordered, StreamExt};
 use shared::{
+    diagnostics::{self, DiagnosticsStore, FailureBundle},
     http_client::{HttpClient, RequestSettings},
     messages::stream_finder::{IncMessage, OutMessage},
     youtube_regexes::YoutubeRegexes,
@@ -26,6 +31,7 @@ pub struct StreamFinder {
     poll_interval: Duration,
     channels: HashMap<String, String>,
     request_settings: RequestSettings,
+    diagnostics: Option<Arc<DiagnosticsStore>>,
     http_client: Arc<HttpClient>,
 }
 
@@ -35,6 +41,7 @@ impl StreamFinder {
         request_settings: RequestSettings,
         result_tx: Sender<OutMessage>,
         poll_interval: Duration,
+        diagnostics: Option<Arc<DiagnosticsStore>>,
     ) -> ActorWrapper<IncMessage> {
         let (tx, rx) = mpsc::channel(32);
 
@@ -45,6 +52,7 @@ impl StreamFinder {
             poll_interval,
             channels: HashMap::new(),
             request_settings,
+            diagnostics,
             http_client,
         };
 
@@ -104,6 +112,9 @@ impl StreamFinder {
                             self.request_settings.browser_name = name.clone();
                             self.request_settings.browser_version = version.clone();
                         }
+                        IncMessage::SetDiagnostics(diagnostics) => {
+                            self.diagnostics = diagnostics;
+                        }
                     },
                     None => {
                         // Incoming channel was closed. That should never happen,
@@ -145,9 +156,14 @@ impl StreamFinder {
         channel_url: String,
         result_tx: Sender<OutMessage>,
     ) {
+        let requested_at = SystemTime::now();
         let load_result = self
-            .http_client
-            .get_request(&channel_url, &self.request_settings.user_agent)
+            .request_settings
+            .retry_policy
+            .retry(&channel_id, || {
+                self.http_client
+                    .get_request(&channel_url, &self.request_settings.user_agent)
+            })
             .await;
 
         let channel_page = match load_result {
@@ -173,38 +189,21 @@ impl StreamFinder {
         let video_list = match serde_json::from_str::<VideoList>(video_list) {
             Ok(list) => list,
             Err(e) => {
-                // Dumping stream page to the logs for further investigation
-                let mut request_output = match File::create(format!("{}.channel", &channel_id)) {
-                    Ok(file) => file,
-                    Err(io_e) => {
-                        shared::tracing_error!(
-                            "Couldn't dump an error {} from the channel {}, reason: {}",
-                            e,
-                            channel_id,
-                            io_e
-                        );
-                        return;
-                    }
+                // Saving the channel page for further investigation
+                let bundle = FailureBundle {
+                    source: "stream_finder".to_string(),
+                    id: channel_id,
+                    url: channel_url,
+                    request_body: None,
+                    error: e.to_string(),
+                    error_path: diagnostics::error_path::<VideoList>(video_list),
+                    client_version: YoutubeRegexes::extract_client_version(&channel_page)
+                        .map(str::to_string),
+                    requested_at: diagnostics::unix_time_ms(requested_at),
+                    failed_at: diagnostics::unix_time_ms(SystemTime::now()),
+                    response: channel_page,
                 };
-
-                match write!(request_output, "{}", channel_page) {
-                    Ok(_r) => {}
-                    Err(io_e) => {
-                        shared::tracing_error!(
-                            "Couldn't dump an error {} from the channel {}, reason: {}",
-                            e,
-                            channel_id,
-                            io_e
-                        );
-                        return;
-                    }
-                }
-
-                shared::tracing_error!(
-                    "Couldn't extract video list from the channel {}, reason: {}",
-                    channel_id,
-                    e
-                );
+                diagnostics::save_failure(self.diagnostics.as_deref(), &bundle);
                 return;
             }
         };
@@ -1,6 +1,6 @@
 use std::{collections::HashSet, time::Duration};
 
-use alkonost::{Alkonost, AlkonostInMessage, AlkonostOutMessage, RequestSettings};
+use alkonost::{Alkonost, AlkonostInMessage, AlkonostOutMessage, RequestSettings, RetryPolicy};
 use tokio::time::sleep;
 use tracing::Level;
 
@@ -18,6 +18,7 @@ pub async fn main() {
         user_agent:
             r#"Mozilla/5.0 (Windows NT 10.0; Win64; x64; rv:90.0) Gecko/20100101 Firefox/90.0"#
                 .to_string(),
+        retry_policy: RetryPolicy::default(),
     };
     let poll_interval = Duration::from_secs(90);
 
@@ -45,8 +46,48 @@ pub async fn main() {
                     video_id,
                     decisions,
                     processed_messages: _,
+                    latency,
                 } => {
-                    tracing::info!("<{}>: {:?}", video_id, decisions);
+                    tracing::info!("<{}> ({:?}): {:?}", video_id, latency, decisions);
+                }
+                AlkonostOutMessage::WatchlistAlert {
+                    video_id,
+                    message,
+                    reason,
+                    ..
+                } => {
+                    tracing::warn!("<{}>: watched user ({}): {:?}", video_id, reason, message);
+                }
+                AlkonostOutMessage::DeletedMessages { video_id, messages } => {
+                    tracing::info!("<{}>: deleted messages: {:?}", video_id, messages);
+                }
+                AlkonostOutMessage::MemoryUsage { video_id, usage } => {
+                    tracing::info!("<{}>: memory usage: {:?}", video_id, usage);
+                }
+                AlkonostOutMessage::MemoryLimitReached { video_id, usage } => {
+                    tracing::warn!("<{}>: memory limit reached: {:?}", video_id, usage);
+                }
+                AlkonostOutMessage::ShadowResult {
+                    video_id,
+                    decisions,
+                    stats,
+                } => {
+                    tracing::debug!(
+                        "<{}>: shadow decisions: {:?}, agreement: {:.2} ({:?})",
+                        video_id,
+                        decisions,
+                        stats.agreement(),
+                        stats
+                    );
+                }
+                AlkonostOutMessage::DiagnosticsSaved(bundle) => {
+                    tracing::warn!(
+                        "<{}>: couldn't parse a response from {}, saved to {}: {}",
+                        bundle.id,
+                        bundle.source,
+                        bundle.path.display(),
+                        bundle.error
+                    );
                 }
             }
         }
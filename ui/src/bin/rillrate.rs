@@ -1,6 +1,8 @@
 use std::{collections::HashSet, time::Duration};
 
-use alkonost::{Alkonost, AlkonostInMessage, AlkonostOutMessage, DecisionAction, RequestSettings};
+use alkonost::{
+    Alkonost, AlkonostInMessage, AlkonostOutMessage, DecisionAction, RequestSettings, RetryPolicy,
+};
 use rillrate::prime::{
     table::{Col, Row},
     Click, ClickOpts, LiveTail, LiveTailOpts, Pulse, PulseOpts, Table, TableOpts,
@@ -28,6 +30,7 @@ pub async fn main() {
         user_agent:
             r#"Mozilla/5.0 (Windows NT 10.0; Win64; x64; rv:90.0) Gecko/20100101 Firefox/90.0"#
                 .to_string(),
+        retry_policy: RetryPolicy::default(),
     };
     let poll_interval = Duration::from_secs(90);
 
@@ -136,6 +139,7 @@ pub async fn main() {
                     video_id,
                     decisions,
                     processed_messages,
+                    latency: _,
                 } => {
                     let chat_stats = stats_data
                         .iter_mut()
@@ -169,6 +173,23 @@ pub async fn main() {
                         }
                     }
                 }
+                AlkonostOutMessage::WatchlistAlert {
+                    video_id,
+                    message,
+                    reason,
+                    ..
+                } => {
+                    let author = message
+                        .author()
+                        .map(|author| author.channel_id.as_str())
+                        .unwrap_or_default();
+                    decision_log_tail.log_now(&video_id, author, format!("Watchlist: {}", &reason));
+                }
+                AlkonostOutMessage::DeletedMessages { .. }
+                | AlkonostOutMessage::MemoryUsage { .. }
+                | AlkonostOutMessage::MemoryLimitReached { .. }
+                | AlkonostOutMessage::ShadowResult { .. }
+                | AlkonostOutMessage::DiagnosticsSaved(_) => {}
             }
             active_chat_pulse.push(stats_data.len() as f64);
             render_stats_table(&stats_table, &stats_data);